@@ -0,0 +1,299 @@
+//! Reading star packs, as written by `via-tool pack`.
+//!
+//! A pack starts with:
+//!
+//! | bytes | content                                            |
+//! |-------|----------------------------------------------------|
+//! | 8     | magic `VIAPACK\0`                                  |
+//! | 2     | format version                                     |
+//! | 4     | length of the header                               |
+//!
+//! followed by the [`PackHeader`] as JSON, the chunk table with
+//! [`ChunkEntry::SIZE`] bytes per chunk, and the chunk data. All numbers are
+//! big-endian. Chunks are ordered by the distance of their center from the
+//! sun, so a client can start with the nearest ones.
+//!
+//! The data of a chunk is compressed with zstd. Uncompressed, it contains the
+//! stars ordered by their Morton code, i.e. the quantized position within
+//! the chunk with the bits of the x, y and z coordinate interleaved:
+//!
+//! - the differences between consecutive Morton codes as LEB128 varints,
+//!   starting from 0,
+//! - one byte per star with the quantized absolute magnitude,
+//! - one byte per star with the quantized logarithm of the temperature.
+//!
+//! A quantized coordinate `q` is decoded to the center of its cell,
+//! `chunk * chunk_size + (q + 0.5) * chunk_size / 2^position_bits`, so the
+//! decoded position is at most `max_position_error`, or `sqrt(3) / 2` cell
+//! edges, away from the star. A quantized byte `q` of a
+//! [`QuantizedRange`] is decoded to `min + q * (max - min) / 255`.
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+pub const MAGIC: [u8; 8] = *b"VIAPACK\0";
+
+pub const VERSION: u16 = 1;
+
+/// Size of the magic, version and header length.
+pub const PRELUDE_SIZE: usize = MAGIC.len() + 6;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PackError {
+    #[error("not a star pack")]
+    InvalidMagic,
+    #[error("the pack has version {0}, but only versions up to {VERSION} are supported")]
+    UnsupportedVersion(u16),
+    #[error("the pack is truncated")]
+    Truncated,
+    #[error("invalid pack header")]
+    Header(#[from] serde_json::Error),
+    #[error("can't decompress chunk")]
+    Decompress(#[from] std::io::Error),
+    #[error("corrupt chunk")]
+    CorruptChunk,
+}
+
+/// A range of values quantized to a byte.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl QuantizedRange {
+    /// Values outside of the range are clamped.
+    pub fn quantize(&self, value: f64) -> u8 {
+        let q = (value - self.min) / (self.max - self.min) * 255.0;
+        q.round().clamp(0.0, 255.0) as u8
+    }
+
+    pub fn dequantize(&self, q: u8) -> f64 {
+        self.min + q as f64 * (self.max - self.min) / 255.0
+    }
+}
+
+/// How the stars of a pack are quantized.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackHeader {
+    /// Edge length of a chunk in parsec.
+    pub chunk_size: f64,
+    /// Bits per axis of the position within a chunk.
+    pub position_bits: u32,
+    /// Upper bound of the distance between a star and its decoded position
+    /// in parsec.
+    pub max_position_error: f64,
+    pub magnitude: QuantizedRange,
+    pub log_temperature: QuantizedRange,
+    pub num_stars: u64,
+    pub num_chunks: u64,
+}
+
+impl PackHeader {
+    /// Edge length of a quantization cell in parsec.
+    pub fn cell_size(&self) -> f64 {
+        self.chunk_size / (1u64 << self.position_bits) as f64
+    }
+
+    /// The decoded heliocentric position of a star in parsec.
+    pub fn position(&self, chunk: [i32; 3], morton: u64) -> [f64; 3] {
+        let cell_size = self.cell_size();
+        std::array::from_fn(|axis| {
+            let cell = compact_bits(morton >> (2 - axis)) as f64;
+            chunk[axis] as f64 * self.chunk_size + (cell + 0.5) * cell_size
+        })
+    }
+}
+
+/// Inverse of spreading the bits of a coordinate to every third bit.
+fn compact_bits(x: u64) -> u64 {
+    let mut x = x & 0x1249_2492_4924_9249;
+    x = (x | x >> 2) & 0x10c3_0c30_c30c_30c3;
+    x = (x | x >> 4) & 0x100f_00f0_0f00_f00f;
+    x = (x | x >> 8) & 0x001f_0000_ff00_00ff;
+    x = (x | x >> 16) & 0x001f_0000_0000_ffff;
+    x = (x | x >> 32) & 0x1f_ffff;
+    x
+}
+
+/// An entry of the chunk table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkEntry {
+    /// Chunk coordinates, i.e. the position of its corner divided by the
+    /// chunk size.
+    pub chunk: [i32; 3],
+    pub num_stars: u32,
+    /// Offset of the compressed data from the start of the file.
+    pub offset: u64,
+    pub length: u32,
+}
+
+impl ChunkEntry {
+    pub const SIZE: usize = 28;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        for (axis, coordinate) in self.chunk.iter().enumerate() {
+            bytes[4 * axis..4 * axis + 4].copy_from_slice(&coordinate.to_be_bytes());
+        }
+        bytes[12..16].copy_from_slice(&self.num_stars.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[24..28].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self {
+            chunk: [0, 4, 8].map(|i| u32_at(i) as i32),
+            num_stars: u32_at(12),
+            offset: u64::from_be_bytes(bytes[16..24].try_into().unwrap()),
+            length: u32_at(24),
+        }
+    }
+}
+
+/// A decoded star.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Star {
+    /// Heliocentric galactic coordinates in parsec.
+    pub position: [f64; 3],
+    pub absolute_magnitude: f32,
+    /// Effective temperature in K.
+    pub t_eff: f32,
+}
+
+/// A star pack in memory.
+pub struct Pack<'a> {
+    data: &'a [u8],
+    header: PackHeader,
+    chunks: Vec<ChunkEntry>,
+}
+
+impl<'a> Pack<'a> {
+    /// Parses the header and chunk table. The chunks are decoded on demand
+    /// with [`Pack::decode_chunk`].
+    pub fn parse(data: &'a [u8]) -> Result<Self, PackError> {
+        let prelude = data.get(..PRELUDE_SIZE).ok_or(PackError::Truncated)?;
+        if prelude[..MAGIC.len()] != MAGIC {
+            return Err(PackError::InvalidMagic);
+        }
+        let version = u16::from_be_bytes([prelude[8], prelude[9]]);
+        if version > VERSION {
+            return Err(PackError::UnsupportedVersion(version));
+        }
+        let header_length = u32::from_be_bytes(prelude[10..14].try_into().unwrap()) as usize;
+
+        let header_end = PRELUDE_SIZE + header_length;
+        let header_json = data
+            .get(PRELUDE_SIZE..header_end)
+            .ok_or(PackError::Truncated)?;
+        let header: PackHeader = serde_json::from_slice(header_json)?;
+
+        let table_length = usize::try_from(header.num_chunks)
+            .ok()
+            .and_then(|num_chunks| num_chunks.checked_mul(ChunkEntry::SIZE))
+            .ok_or(PackError::Truncated)?;
+        let table = data
+            .get(header_end..)
+            .and_then(|rest| rest.get(..table_length))
+            .ok_or(PackError::Truncated)?;
+        let chunks = table
+            .chunks_exact(ChunkEntry::SIZE)
+            .map(|bytes| ChunkEntry::from_bytes(bytes.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            data,
+            header,
+            chunks,
+        })
+    }
+
+    pub fn header(&self) -> &PackHeader {
+        &self.header
+    }
+
+    /// The chunks, nearest first.
+    pub fn chunks(&self) -> &[ChunkEntry] {
+        &self.chunks
+    }
+
+    pub fn decode_chunk(&self, entry: &ChunkEntry) -> Result<Vec<Star>, PackError> {
+        let compressed = usize::try_from(entry.offset)
+            .ok()
+            .and_then(|offset| self.data.get(offset..))
+            .and_then(|rest| rest.get(..entry.length as usize))
+            .ok_or(PackError::Truncated)?;
+        let num_stars = entry.num_stars as usize;
+        let data = zstd::stream::decode_all(compressed)?;
+
+        let mut rest = &data[..];
+        let mut mortons = Vec::with_capacity(num_stars.min(data.len()));
+        let mut morton = 0u64;
+        for _ in 0..num_stars {
+            morton = morton
+                .checked_add(read_varint(&mut rest)?)
+                .ok_or(PackError::CorruptChunk)?;
+            mortons.push(morton);
+        }
+        if rest.len() != 2 * num_stars {
+            return Err(PackError::CorruptChunk);
+        }
+        let (magnitudes, temperatures) = rest.split_at(num_stars);
+
+        let stars = mortons
+            .iter()
+            .zip(magnitudes.iter().zip(temperatures))
+            .map(|(&morton, (&magnitude, &temperature))| {
+                Star {
+                    position: self.header.position(entry.chunk, morton),
+                    absolute_magnitude: self.header.magnitude.dequantize(magnitude) as f32,
+                    t_eff: self.header.log_temperature.dequantize(temperature).exp() as f32,
+                }
+            })
+            .collect();
+
+        Ok(stars)
+    }
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, PackError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(PackError::CorruptChunk)?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(PackError::CorruptChunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_entries_round_trip() {
+        let entry = ChunkEntry {
+            chunk: [-3, 0, 7],
+            num_stars: 12345,
+            offset: 1 << 40,
+            length: 999,
+        };
+        assert_eq!(ChunkEntry::from_bytes(&entry.to_bytes()), entry);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(Pack::parse(b"VIAPACK"), Err(PackError::Truncated)));
+        assert!(matches!(
+            Pack::parse(b"VIAEXPRT\0\x01\0\0\0\0"),
+            Err(PackError::InvalidMagic)
+        ));
+    }
+}
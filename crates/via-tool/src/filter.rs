@@ -0,0 +1,636 @@
+//! Filter expressions for selecting records on the command line, like
+//! `parallax_over_error > 5 && ruwe < 1.4 && phot_g_mean_mag < 18`.
+//!
+//! Expressions consist of field names, numbers, strings in double quotes,
+//! `true`, `false` and `null`, the arithmetic operators `+ - * /`, the
+//! comparisons `< <= > >= == !=`, and `! && ||`, with the usual precedence
+//! and parentheses. Comparing a missing value is false, unless it's compared
+//! to `null`, like `radial_velocity != null`.
+//!
+//! A [`Filter`] is parsed with field names, and bound to the fields of the
+//! records it's applied to with [`Filter::bind`], so that unknown names are
+//! reported before any record is read.
+
+use std::{
+    cmp::Ordering,
+    fmt::{
+        self,
+        Display,
+    },
+    str::FromStr,
+};
+
+use color_eyre::eyre::eyre;
+
+use crate::Error;
+
+/// A parsed filter expression, with fields of type `F`.
+#[derive(Clone, Debug)]
+pub struct Filter<F = String> {
+    source: String,
+    expression: Expression<F>,
+}
+
+#[derive(Clone, Debug)]
+enum Expression<F> {
+    Literal(Literal),
+    Field(F),
+    Not(Box<Self>),
+    Negate(Box<Self>),
+    Binary(BinaryOperator, Box<Self>, Box<Self>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryOperator {
+    Or,
+    And,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+/// A value of a field, or the result of an expression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(&'a str),
+}
+
+impl From<f64> for Value<'static> {
+    /// NaN is a missing value.
+    fn from(value: f64) -> Self {
+        if value.is_nan() {
+            Self::Null
+        }
+        else {
+            Self::Number(value)
+        }
+    }
+}
+
+impl<'a> From<&'a serde_json::Value> for Value<'a> {
+    fn from(value: &'a serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Bool(value) => Self::Bool(*value),
+            serde_json::Value::Number(value) => value.as_f64().map_or(Self::Null, Self::Number),
+            serde_json::Value::String(value) => Self::String(value),
+            _ => Self::Null,
+        }
+    }
+}
+
+impl<F> Filter<F> {
+    /// Resolves the field names with `resolve`, which fails for names that
+    /// aren't fields of the records.
+    pub fn bind<G>(
+        &self,
+        mut resolve: impl FnMut(&str) -> Result<G, Error>,
+    ) -> Result<Filter<G>, Error>
+    where
+        F: AsRef<str>,
+    {
+        let expression = self
+            .expression
+            .bind(&mut resolve)
+            .map_err(|error| error.wrap_err(format!("invalid filter: {}", self.source)))?;
+
+        Ok(Filter {
+            source: self.source.clone(),
+            expression,
+        })
+    }
+
+    /// The fields used by the expression.
+    pub fn fields(&self) -> Vec<&F> {
+        let mut fields = vec![];
+        self.expression.fields(&mut fields);
+        fields
+    }
+
+    /// Evaluates the filter with the field values returned by `get`. Records
+    /// match if the expression is `true`.
+    pub fn matches<'a>(&'a self, get: impl Fn(&'a F) -> Value<'a>) -> bool {
+        self.expression.evaluate(&get) == Value::Bool(true)
+    }
+}
+
+impl<F> Expression<F> {
+    fn bind<G>(
+        &self,
+        resolve: &mut impl FnMut(&str) -> Result<G, Error>,
+    ) -> Result<Expression<G>, Error>
+    where
+        F: AsRef<str>,
+    {
+        let expression = match self {
+            Self::Literal(literal) => Expression::Literal(literal.clone()),
+            Self::Field(name) => Expression::Field(resolve(name.as_ref())?),
+            Self::Not(operand) => Expression::Not(Box::new(operand.bind(resolve)?)),
+            Self::Negate(operand) => Expression::Negate(Box::new(operand.bind(resolve)?)),
+            Self::Binary(operator, left, right) => {
+                Expression::Binary(
+                    *operator,
+                    Box::new(left.bind(resolve)?),
+                    Box::new(right.bind(resolve)?),
+                )
+            }
+        };
+        Ok(expression)
+    }
+
+    fn fields<'a>(&'a self, fields: &mut Vec<&'a F>) {
+        match self {
+            Self::Literal(_) => {}
+            Self::Field(field) => fields.push(field),
+            Self::Not(operand) | Self::Negate(operand) => operand.fields(fields),
+            Self::Binary(_, left, right) => {
+                left.fields(fields);
+                right.fields(fields);
+            }
+        }
+    }
+
+    fn evaluate<'a>(&'a self, get: &impl Fn(&'a F) -> Value<'a>) -> Value<'a> {
+        match self {
+            Self::Literal(Literal::Null) => Value::Null,
+            Self::Literal(Literal::Bool(value)) => Value::Bool(*value),
+            Self::Literal(Literal::Number(value)) => Value::Number(*value),
+            Self::Literal(Literal::String(value)) => Value::String(value),
+            Self::Field(field) => get(field),
+            Self::Not(operand) => Value::Bool(operand.evaluate(get) != Value::Bool(true)),
+            Self::Negate(operand) => {
+                match operand.evaluate(get) {
+                    Value::Number(value) => Value::Number(-value),
+                    _ => Value::Null,
+                }
+            }
+            Self::Binary(BinaryOperator::And, left, right) => {
+                Value::Bool(
+                    left.evaluate(get) == Value::Bool(true)
+                        && right.evaluate(get) == Value::Bool(true),
+                )
+            }
+            Self::Binary(BinaryOperator::Or, left, right) => {
+                Value::Bool(
+                    left.evaluate(get) == Value::Bool(true)
+                        || right.evaluate(get) == Value::Bool(true),
+                )
+            }
+            Self::Binary(operator, left, right) => {
+                operator.apply(left.evaluate(get), right.evaluate(get))
+            }
+        }
+    }
+}
+
+impl BinaryOperator {
+    fn apply<'a>(&self, left: Value<'a>, right: Value<'a>) -> Value<'a> {
+        match self {
+            Self::Equal => Value::Bool(left == right),
+            Self::NotEqual => Value::Bool(left != right),
+            Self::Less | Self::LessEqual | Self::Greater | Self::GreaterEqual => {
+                let ordering = match (left, right) {
+                    (Value::Number(left), Value::Number(right)) => left.partial_cmp(&right),
+                    (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+                    _ => None,
+                };
+                let Some(ordering) = ordering
+                else {
+                    return Value::Bool(false);
+                };
+                Value::Bool(match self {
+                    Self::Less => ordering == Ordering::Less,
+                    Self::LessEqual => ordering != Ordering::Greater,
+                    Self::Greater => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                })
+            }
+            Self::Add | Self::Subtract | Self::Multiply | Self::Divide => {
+                let (Value::Number(left), Value::Number(right)) = (left, right)
+                else {
+                    return Value::Null;
+                };
+                Value::from(match self {
+                    Self::Add => left + right,
+                    Self::Subtract => left - right,
+                    Self::Multiply => left * right,
+                    _ => left / right,
+                })
+            }
+            Self::And | Self::Or => unreachable!("logical operators are short-circuited"),
+        }
+    }
+}
+
+impl<F> Display for Filter<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s).map_err(|error| error.report(s))?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            end: s.len(),
+        };
+        let expression = parser.parse().map_err(|error| error.report(s))?;
+
+        Ok(Self {
+            source: s.to_owned(),
+            expression,
+        })
+    }
+}
+
+/// A parse error at a byte offset of the expression.
+struct ParseError {
+    offset: usize,
+    message: String,
+}
+
+impl ParseError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// Shows the message with the expression and a marker below the
+    /// position of the error.
+    fn report(self, source: &str) -> Error {
+        let column = source[..self.offset].chars().count();
+        eyre!(
+            "can't parse filter: {}\n  {source}\n  {}^",
+            self.message,
+            " ".repeat(column)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(f64),
+    String(String),
+    Operator(&'static str),
+    OpenParenthesis,
+    CloseParenthesis,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Identifier(name) => write!(f, "`{name}`"),
+            Self::Number(value) => write!(f, "`{value}`"),
+            Self::String(value) => write!(f, "{value:?}"),
+            Self::Operator(operator) => write!(f, "`{operator}`"),
+            Self::OpenParenthesis => write!(f, "`(`"),
+            Self::CloseParenthesis => write!(f, "`)`"),
+        }
+    }
+}
+
+/// Operators, with longer ones first, so that `<=` isn't read as `<`.
+const OPERATORS: [&str; 14] = [
+    "&&", "||", "<=", ">=", "==", "!=", "<", ">", "!", "+", "-", "*", "/", "=",
+];
+
+/// Splits the expression into tokens with their byte offsets.
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = vec![];
+    let mut rest = s;
+
+    loop {
+        rest = rest.trim_start();
+        let offset = s.len() - rest.len();
+        let Some(c) = rest.chars().next()
+        else {
+            return Ok(tokens);
+        };
+
+        let (token, length) = if c.is_ascii_alphabetic() || c == '_' {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            (Token::Identifier(rest[..length].to_owned()), length)
+        }
+        else if c.is_ascii_digit() || c == '.' {
+            let mut length = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.'))
+                .unwrap_or(rest.len());
+            // exponents can have a sign, like `1e-3`
+            if rest[..length].ends_with(['e', 'E']) && rest[length..].starts_with(['+', '-']) {
+                length += 1 + rest[length + 1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - length - 1);
+            }
+            let value = rest[..length]
+                .parse()
+                .map_err(|_| ParseError::new(offset, "invalid number"))?;
+            (Token::Number(value), length)
+        }
+        else if c == '"' {
+            let length = rest[1..]
+                .find('"')
+                .ok_or_else(|| ParseError::new(offset, "unterminated string"))?;
+            (Token::String(rest[1..1 + length].to_owned()), length + 2)
+        }
+        else if c == '(' {
+            (Token::OpenParenthesis, 1)
+        }
+        else if c == ')' {
+            (Token::CloseParenthesis, 1)
+        }
+        else {
+            let operator = OPERATORS
+                .into_iter()
+                .find(|operator| rest.starts_with(operator))
+                .ok_or_else(|| ParseError::new(offset, format!("unexpected character `{c}`")))?;
+            if operator == "=" {
+                return Err(ParseError::new(offset, "use `==` to compare values"));
+            }
+            (Token::Operator(operator), operator.len())
+        };
+
+        tokens.push((offset, token));
+        rest = &rest[length..];
+    }
+}
+
+/// Recursive descent parser, with one function per precedence level.
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    position: usize,
+    /// Offset of the end of the expression, for errors there.
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(&mut self) -> Result<Expression<String>, ParseError> {
+        let expression = self.parse_or()?;
+        match self.peek() {
+            Some((offset, token)) => {
+                Err(ParseError::new(
+                    *offset,
+                    format!("expected an operator, found {token}"),
+                ))
+            }
+            None => Ok(expression),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a (usize, Token)> {
+        self.tokens.get(self.position)
+    }
+
+    /// Consumes the next token if it's one of the operators.
+    fn operator(&mut self, operators: &[(&str, BinaryOperator)]) -> Option<BinaryOperator> {
+        let Some((_, Token::Operator(token))) = self.peek()
+        else {
+            return None;
+        };
+        let (_, operator) = operators.iter().find(|(operator, _)| operator == token)?;
+        self.position += 1;
+        Some(*operator)
+    }
+
+    /// Parses left-associative binary operators of one precedence level.
+    fn parse_binary(
+        &mut self,
+        operators: &[(&str, BinaryOperator)],
+        mut operand: impl FnMut(&mut Self) -> Result<Expression<String>, ParseError>,
+    ) -> Result<Expression<String>, ParseError> {
+        let mut left = operand(self)?;
+        while let Some(operator) = self.operator(operators) {
+            let right = operand(self)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_or(&mut self) -> Result<Expression<String>, ParseError> {
+        self.parse_binary(&[("||", BinaryOperator::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression<String>, ParseError> {
+        self.parse_binary(&[("&&", BinaryOperator::And)], Self::parse_not)
+    }
+
+    fn parse_not(&mut self) -> Result<Expression<String>, ParseError> {
+        if let Some((_, Token::Operator("!"))) = self.peek() {
+            self.position += 1;
+            Ok(Expression::Not(Box::new(self.parse_not()?)))
+        }
+        else {
+            self.parse_comparison()
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expression<String>, ParseError> {
+        const COMPARISONS: [(&str, BinaryOperator); 6] = [
+            ("<", BinaryOperator::Less),
+            ("<=", BinaryOperator::LessEqual),
+            (">", BinaryOperator::Greater),
+            (">=", BinaryOperator::GreaterEqual),
+            ("==", BinaryOperator::Equal),
+            ("!=", BinaryOperator::NotEqual),
+        ];
+
+        let left = self.parse_sum()?;
+        let Some(operator) = self.operator(&COMPARISONS)
+        else {
+            return Ok(left);
+        };
+        let right = self.parse_sum()?;
+
+        if let Some((offset, Token::Operator(token))) = self.peek() {
+            if COMPARISONS
+                .iter()
+                .any(|(comparison, _)| comparison == token)
+            {
+                return Err(ParseError::new(
+                    *offset,
+                    "comparisons can't be chained, combine them with `&&`",
+                ));
+            }
+        }
+
+        Ok(Expression::Binary(
+            operator,
+            Box::new(left),
+            Box::new(right),
+        ))
+    }
+
+    fn parse_sum(&mut self) -> Result<Expression<String>, ParseError> {
+        self.parse_binary(
+            &[("+", BinaryOperator::Add), ("-", BinaryOperator::Subtract)],
+            Self::parse_product,
+        )
+    }
+
+    fn parse_product(&mut self) -> Result<Expression<String>, ParseError> {
+        self.parse_binary(
+            &[
+                ("*", BinaryOperator::Multiply),
+                ("/", BinaryOperator::Divide),
+            ],
+            Self::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression<String>, ParseError> {
+        let Some((offset, token)) = self.peek()
+        else {
+            return Err(ParseError::new(self.end, "expected a value"));
+        };
+        self.position += 1;
+
+        let expression = match token {
+            Token::Operator("-") => Expression::Negate(Box::new(self.parse_unary()?)),
+            Token::Number(value) => Expression::Literal(Literal::Number(*value)),
+            Token::String(value) => Expression::Literal(Literal::String(value.clone())),
+            Token::Identifier(name) => {
+                match name.as_str() {
+                    "null" => Expression::Literal(Literal::Null),
+                    "true" => Expression::Literal(Literal::Bool(true)),
+                    "false" => Expression::Literal(Literal::Bool(false)),
+                    _ => Expression::Field(name.clone()),
+                }
+            }
+            Token::OpenParenthesis => {
+                let expression = self.parse_or()?;
+                match self.peek() {
+                    Some((_, Token::CloseParenthesis)) => self.position += 1,
+                    Some((offset, token)) => {
+                        return Err(ParseError::new(
+                            *offset,
+                            format!("expected `)`, found {token}"),
+                        ));
+                    }
+                    None => return Err(ParseError::new(self.end, "expected `)`")),
+                }
+                expression
+            }
+            _ => {
+                return Err(ParseError::new(
+                    *offset,
+                    format!("expected a value, found {token}"),
+                ));
+            }
+        };
+
+        Ok(expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(filter: &str, fields: &[(&str, Value<'static>)]) -> bool {
+        let filter: Filter = filter.parse().unwrap();
+        let filter = filter
+            .bind(|name| {
+                fields
+                    .iter()
+                    .position(|(field, _)| *field == name)
+                    .ok_or_else(|| eyre!("unknown field {name}"))
+            })
+            .unwrap();
+        filter.matches(|index| fields[*index].1)
+    }
+
+    #[test]
+    fn evaluates_with_precedence() {
+        let fields = [
+            ("parallax", Value::Number(2.0)),
+            ("parallax_error", Value::Number(0.25)),
+            ("ruwe", Value::Number(1.2)),
+            ("radial_velocity", Value::Null),
+            ("libname_gspphot", Value::String("MARCS")),
+        ];
+
+        assert!(evaluate(
+            "parallax / parallax_error > 5 && ruwe < 1.4",
+            &fields
+        ));
+        assert!(evaluate(
+            "ruwe > 2 || parallax >= 2 && ruwe <= 1.2",
+            &fields
+        ));
+        assert!(!evaluate(
+            "(ruwe > 2 || parallax >= 2) && ruwe < 1",
+            &fields
+        ));
+        assert!(evaluate("-parallax + 1 * 3 == 1", &fields));
+        assert!(evaluate("!(parallax < 1e-3)", &fields));
+        assert!(evaluate("libname_gspphot == \"MARCS\"", &fields));
+
+        // comparing missing values is false
+        assert!(!evaluate("radial_velocity < 0", &fields));
+        assert!(!evaluate("radial_velocity >= 0", &fields));
+        assert!(evaluate("radial_velocity == null", &fields));
+        assert!(!evaluate("radial_velocity + 1 > 0", &fields));
+    }
+
+    #[test]
+    fn reports_the_position_of_errors() {
+        let error = "parallax > && ruwe < 1.4".parse::<Filter>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "can't parse filter: expected a value, found `&&`\n  \
+             parallax > && ruwe < 1.4\n             ^"
+        );
+
+        for filter in [
+            "ruwe = 1",
+            "(ruwe < 1",
+            "1 < ruwe < 2",
+            "ruwe $ 1",
+            "ruwe <",
+            "\"abc",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn binds_field_names() {
+        let filter: Filter = "a > 1 && b.c < 2".parse().unwrap();
+        assert_eq!(filter.fields(), ["a", "b.c"]);
+        assert!(filter
+            .bind(|name| {
+                if name == "a" {
+                    Ok(0)
+                }
+                else {
+                    Err(eyre!("unknown field {name}"))
+                }
+            })
+            .is_err());
+    }
+}
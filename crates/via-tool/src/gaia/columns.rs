@@ -0,0 +1,542 @@
+//! Columns of the Gaia tables, for commands that select fields by name.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+};
+
+use color_eyre::eyre::{
+    bail,
+    eyre,
+};
+use lazy_static::lazy_static;
+use serde::{
+    de::{
+        self,
+        value::StrDeserializer,
+        DeserializeOwned,
+        DeserializeSeed,
+        MapAccess,
+        Visitor,
+    },
+    forward_to_deserialize_any,
+    Deserializer,
+};
+use serde_json::Value;
+
+use super::{
+    AstrophysicalParameters,
+    GaiaSource,
+    Record,
+};
+use crate::Error;
+
+/// The table of the Gaia archive a column comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Table {
+    GaiaSource,
+    AstrophysicalParameters,
+}
+
+impl Table {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GaiaSource => "gaia_source",
+            Self::AstrophysicalParameters => "astrophysical_parameters",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "gaia_source" => Ok(Self::GaiaSource),
+            "astrophysical_parameters" => Ok(Self::AstrophysicalParameters),
+            _ => bail!("unknown table: {name}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    U64,
+    F32,
+    F64,
+    String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub table: Table,
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    /// Whether the column is written with its qualified name, as another
+    /// column of the output has the same name.
+    pub qualified: bool,
+}
+
+impl Column {
+    /// Name of the column in outputs, like `ra` or
+    /// `astrophysical_parameters.source_id`.
+    pub fn output_name(&self) -> Cow<'static, str> {
+        if self.qualified {
+            Cow::Owned(format!("{}.{}", self.table.name(), self.name))
+        }
+        else {
+            Cow::Borrowed(self.name)
+        }
+    }
+
+    /// All columns of a table, in the order of the CSV files.
+    pub fn all(table: Table) -> Result<Vec<Self>, Error> {
+        match table {
+            Table::GaiaSource => columns::<GaiaSource>(table),
+            Table::AstrophysicalParameters => columns::<AstrophysicalParameters>(table),
+        }
+    }
+
+    /// Finds a column by its name, which can be qualified with the table,
+    /// like `astrophysical_parameters.source_id`. Unqualified names are looked
+    /// up in `gaia_source` first.
+    pub fn find(name: &str) -> Result<Self, Error> {
+        let (tables, field) = match name.split_once('.') {
+            Some((table, field)) => (vec![Table::from_name(table)?], field),
+            None => {
+                (
+                    vec![Table::GaiaSource, Table::AstrophysicalParameters],
+                    name,
+                )
+            }
+        };
+
+        for table in tables {
+            if let Some(column) = Self::all(table)?
+                .into_iter()
+                .find(|column| column.name == field)
+            {
+                return Ok(column);
+            }
+        }
+
+        bail!("unknown column: {name}")
+    }
+
+    /// Description and unit of the column, from the doc comments of the
+    /// model.
+    pub fn doc(&self) -> Option<&'static ColumnDoc> {
+        DOCS.get(&(self.table, self.name))
+    }
+
+    /// Unified Content Descriptor of the column, as used by the Gaia archive,
+    /// if it's known.
+    pub fn ucd(&self) -> Option<String> {
+        ucd(self.name)
+    }
+}
+
+/// Documentation of a column.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColumnDoc {
+    pub description: String,
+    /// Unit in the VOUnit syntax of the Gaia archive, like `mas.yr**-1`.
+    pub unit: Option<&'static str>,
+}
+
+lazy_static! {
+    static ref DOCS: HashMap<(Table, &'static str), ColumnDoc> = {
+        let mut docs = HashMap::new();
+        for (table, source) in [
+            (Table::GaiaSource, include_str!("model/source.rs")),
+            (
+                Table::AstrophysicalParameters,
+                include_str!("model/astro.rs"),
+            ),
+        ] {
+            docs.extend(
+                parse_docs(source)
+                    .into_iter()
+                    .map(|(name, doc)| ((table, name), doc)),
+            );
+        }
+        docs
+    };
+}
+
+/// Parses the doc comments of the fields of a model struct. The comments are
+/// the description from the Gaia data model, optionally followed by a line
+/// `Unit: <unit>`.
+fn parse_docs(source: &'static str) -> Vec<(&'static str, ColumnDoc)> {
+    let mut docs = vec![];
+    let mut comment = vec![];
+
+    for line in source.lines().map(str::trim) {
+        if let Some(line) = line.strip_prefix("///") {
+            comment.push(line.trim());
+        }
+        else if let Some((name, _)) = line
+            .strip_prefix("pub ")
+            .and_then(|line| line.split_once(':'))
+        {
+            let mut doc = ColumnDoc::default();
+            let mut description = vec![];
+            for line in comment.drain(..) {
+                match line.strip_prefix("Unit:") {
+                    Some(unit) => doc.unit = Some(unit.trim()),
+                    None if !line.is_empty() => description.push(line),
+                    None => {}
+                }
+            }
+            doc.description = description.join(" ");
+            docs.push((name, doc));
+        }
+        else if !line.starts_with("#[") {
+            comment.clear();
+        }
+    }
+
+    docs
+}
+
+/// UCDs of the columns that can't be derived from their names.
+const UCDS: &[(&str, &str)] = &[
+    ("solution_id", "meta.version"),
+    ("designation", "meta.id;meta.main"),
+    ("source_id", "meta.id"),
+    ("random_index", "meta.code"),
+    ("ref_epoch", "meta.ref;time.epoch"),
+    ("ra", "pos.eq.ra;meta.main"),
+    ("dec", "pos.eq.dec;meta.main"),
+    ("parallax", "pos.parallax.trig"),
+    ("pm", "pos.pm"),
+    ("pmra", "pos.pm;pos.eq.ra"),
+    ("pmdec", "pos.pm;pos.eq.dec"),
+    ("pseudocolour", "em.wavenumber"),
+    ("nu_eff_used_in_astrometry", "em.wavenumber"),
+    ("ruwe", "stat.error"),
+    ("phot_g_mean_flux", "phot.flux;em.opt"),
+    ("phot_bp_mean_flux", "phot.flux;em.opt.B"),
+    ("phot_rp_mean_flux", "phot.flux;em.opt.R"),
+    ("phot_g_mean_mag", "phot.mag;em.opt"),
+    ("phot_bp_mean_mag", "phot.mag;em.opt.B"),
+    ("phot_rp_mean_mag", "phot.mag;em.opt.R"),
+    ("bp_rp", "phot.color;em.opt.B;em.opt.R"),
+    ("bp_g", "phot.color;em.opt.B;em.opt"),
+    ("g_rp", "phot.color;em.opt;em.opt.R"),
+    ("grvs_mag", "phot.mag;em.opt.I"),
+    ("radial_velocity", "spect.dopplerVeloc.opt;em.opt.I"),
+    ("vbroad", "phys.veloc.rotat"),
+    ("l", "pos.galactic.lon"),
+    ("b", "pos.galactic.lat"),
+    ("ecl_lon", "pos.ecliptic.lon"),
+    ("ecl_lat", "pos.ecliptic.lat"),
+    ("non_single_star", "meta.code.multip"),
+];
+
+/// UCDs of the astrophysical parameters, by the first word of their names,
+/// like `teff` in `teff_gspphot`.
+const PARAMETER_UCDS: &[(&str, &str)] = &[
+    ("teff", "phys.temperature.effective"),
+    ("logg", "phys.gravity"),
+    ("mh", "phys.abund.Z"),
+    ("distance", "pos.distance"),
+    ("azero", "phys.absorption;em.opt"),
+    ("ag", "phys.absorption;em.opt"),
+    ("ebpminrp", "phot.color.excess"),
+    ("radius", "phys.size.radius"),
+    ("lum", "phys.luminosity"),
+    ("mass", "phys.mass"),
+    ("age", "time.age"),
+    ("vsini", "phys.veloc.rotat"),
+    ("classprob", "stat.probability"),
+];
+
+fn ucd(name: &str) -> Option<String> {
+    if let Some((_, ucd)) = UCDS.iter().find(|(column, _)| *column == name) {
+        return Some((*ucd).to_owned());
+    }
+
+    if name.ends_with("_over_error") {
+        return Some("stat.snr".to_owned());
+    }
+    if name.ends_with("_corr") {
+        return Some("stat.correlation".to_owned());
+    }
+    // errors and bounds of the main position aren't the main position
+    let base_ucd = |name| Some(ucd(name)?.replace(";meta.main", ""));
+    if let Some(name) = name
+        .strip_suffix("_error")
+        .or_else(|| name.strip_suffix("_uncertainty"))
+    {
+        return Some(format!("stat.error;{}", base_ucd(name)?));
+    }
+    if let Some(name) = name.strip_suffix("_lower") {
+        return Some(format!("{};stat.min", base_ucd(name)?));
+    }
+    if let Some(name) = name.strip_suffix("_upper") {
+        return Some(format!("{};stat.max", base_ucd(name)?));
+    }
+
+    let word = name.split('_').next()?;
+    PARAMETER_UCDS
+        .iter()
+        .find(|(prefix, _)| *prefix == word)
+        .map(|(_, ucd)| (*ucd).to_owned())
+}
+
+/// Lists the fields of a model struct with their types.
+///
+/// The struct is deserialized from a deserializer that doesn't contain any
+/// data, but records the field names and which type each field asks for.
+fn columns<T: DeserializeOwned>(table: Table) -> Result<Vec<Column>, Error> {
+    let mut fields = vec![];
+    T::deserialize(StructTracer {
+        fields: &mut fields,
+    })
+    .map_err(|error| eyre!("can't list the columns of {}: {error}", table.name()))?;
+
+    fields
+        .into_iter()
+        .map(|(name, column_type)| {
+            let column_type = column_type
+                .ok_or_else(|| eyre!("unsupported type of column {}.{name}", table.name()))?;
+            Ok(Column {
+                table,
+                name,
+                column_type,
+                qualified: false,
+            })
+        })
+        .collect()
+}
+
+type TracedFields = Vec<(&'static str, Option<ColumnType>)>;
+
+struct StructTracer<'a> {
+    fields: &'a mut TracedFields,
+}
+
+impl<'de, 'a> Deserializer<'de> for StructTracer<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("expected a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(FieldTracer {
+            names: fields.iter(),
+            fields: self.fields,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+struct FieldTracer<'a> {
+    names: std::slice::Iter<'static, &'static str>,
+    fields: &'a mut TracedFields,
+}
+
+impl<'de, 'a> MapAccess<'de> for FieldTracer<'a> {
+    type Error = de::value::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some(&name) = self.names.next()
+        else {
+            return Ok(None);
+        };
+
+        // the type is filled in once the value is deserialized
+        self.fields.push((name, None));
+        seed.deserialize(StrDeserializer::new(name)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (_, column_type) = self
+            .fields
+            .last_mut()
+            .ok_or_else(|| de::Error::custom("value without a field"))?;
+        seed.deserialize(ValueTracer { column_type })
+    }
+}
+
+struct ValueTracer<'a> {
+    column_type: &'a mut Option<ColumnType>,
+}
+
+macro_rules! trace_values {
+    ($($method:ident => $column_type:ident, $visit:ident($value:expr);)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                *self.column_type = Some(ColumnType::$column_type);
+                visitor.$visit($value)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for ValueTracer<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("unsupported type"))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    trace_values! {
+        deserialize_bool => Bool, visit_bool(false);
+        deserialize_i8 => I8, visit_i8(0);
+        deserialize_i16 => I16, visit_i16(0);
+        deserialize_i32 => I32, visit_i32(0);
+        deserialize_i64 => I64, visit_i64(0);
+        deserialize_u64 => U64, visit_u64(0);
+        deserialize_f32 => F32, visit_f32(0.0);
+        deserialize_f64 => F64, visit_f64(0.0);
+        deserialize_str => String, visit_str("");
+        deserialize_string => String, visit_str("");
+    }
+
+    forward_to_deserialize_any! {
+        i128 u8 u16 u32 u128 char bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// The fields of a record as serialized by serde_json, to look up columns
+/// by name. Missing values are `null`.
+pub struct Row {
+    gaia_source: Value,
+    astrophysical_parameters: Value,
+}
+
+impl Row {
+    /// Only the tables used by `columns` are serialized.
+    pub fn new<'a>(
+        record: &Record,
+        columns: impl IntoIterator<Item = &'a Column>,
+    ) -> Result<Self, Error> {
+        let mut row = Self {
+            gaia_source: Value::Null,
+            astrophysical_parameters: Value::Null,
+        };
+
+        for column in columns {
+            match column.table {
+                Table::GaiaSource if row.gaia_source.is_null() => {
+                    row.gaia_source = serde_json::to_value(&record.gaia_source)?;
+                }
+                Table::AstrophysicalParameters if row.astrophysical_parameters.is_null() => {
+                    if let Some(astrophysical_parameters) = &record.astrophysical_parameters {
+                        row.astrophysical_parameters =
+                            serde_json::to_value(astrophysical_parameters)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(row)
+    }
+
+    #[cfg(test)]
+    pub fn from_json(gaia_source: Value, astrophysical_parameters: Value) -> Self {
+        Self {
+            gaia_source,
+            astrophysical_parameters,
+        }
+    }
+
+    pub fn get(&self, column: &Column) -> &Value {
+        let table = match column.table {
+            Table::GaiaSource => &self.gaia_source,
+            Table::AstrophysicalParameters => &self.astrophysical_parameters,
+        };
+        table.get(column.name).unwrap_or(&Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_have_the_types_of_the_fields() {
+        let columns = Column::all(Table::GaiaSource).unwrap();
+        let column_type = |name| {
+            columns
+                .iter()
+                .find(|column| column.name == name)
+                .map(|column| column.column_type)
+        };
+
+        assert_eq!(columns[0].name, "solution_id");
+        assert_eq!(column_type("source_id"), Some(ColumnType::U64));
+        assert_eq!(column_type("designation"), Some(ColumnType::String));
+        assert_eq!(column_type("random_index"), Some(ColumnType::I64));
+        assert_eq!(column_type("ra"), Some(ColumnType::F64));
+        assert_eq!(column_type("ra_error"), Some(ColumnType::F32));
+    }
+
+    #[test]
+    fn finds_qualified_names() {
+        let column = Column::find("source_id").unwrap();
+        assert_eq!(column.table, Table::GaiaSource);
+
+        let column = Column::find("astrophysical_parameters.source_id").unwrap();
+        assert_eq!(column.table, Table::AstrophysicalParameters);
+
+        let column = Column::find("teff_esphs").unwrap();
+        assert_eq!(column.table, Table::AstrophysicalParameters);
+
+        assert!(Column::find("gaia_source.teff_esphs").is_err());
+        assert!(Column::find("unknown").is_err());
+    }
+
+    #[test]
+    fn documents_columns() {
+        let ra = Column::find("ra").unwrap();
+        let doc = ra.doc().unwrap();
+        assert_eq!(doc.description, "Right ascension");
+        assert_eq!(doc.unit, Some("deg"));
+        assert_eq!(ra.ucd().as_deref(), Some("pos.eq.ra;meta.main"));
+
+        let column = Column::find("pmra_error").unwrap();
+        assert_eq!(column.doc().unwrap().unit, Some("mas.yr**-1"));
+        assert_eq!(column.ucd().as_deref(), Some("stat.error;pos.pm;pos.eq.ra"));
+
+        let column = Column::find("astrophysical_parameters.teff_gspphot_upper").unwrap();
+        assert_eq!(column.doc().unwrap().unit, Some("K"));
+        assert_eq!(
+            column.ucd().as_deref(),
+            Some("phys.temperature.effective;stat.max")
+        );
+
+        let columns = Column::all(Table::AstrophysicalParameters).unwrap();
+        assert!(columns.iter().all(|column| column.doc().is_some()));
+        assert_eq!(
+            Column::find("astrometric_params_solved").unwrap().ucd(),
+            None
+        );
+    }
+}
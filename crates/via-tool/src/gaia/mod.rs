@@ -1,3 +1,4 @@
+mod columns;
 mod model;
 
 use std::{
@@ -20,15 +21,26 @@ use csv_async::{
 use futures::TryStreamExt;
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use tokio::{
     fs::File,
     io::BufReader,
 };
 
-pub use self::model::{
-    astro::AstrophysicalParameters,
-    source::GaiaSource,
+pub use self::{
+    columns::{
+        Column,
+        ColumnType,
+        Row,
+        Table,
+    },
+    model::{
+        astro::AstrophysicalParameters,
+        source::GaiaSource,
+    },
 };
 use crate::Error;
 
@@ -89,7 +101,7 @@ impl Data {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
 pub struct HealPixRange {
     pub start: u32,
     pub end: u32,
@@ -7,22 +7,19 @@ use std::path::{
     PathBuf,
 };
 
-use color_eyre::eyre::ensure;
+use color_eyre::eyre::{
+    bail,
+    ensure,
+};
+use futures::{
+    stream::BoxStream,
+    StreamExt,
+    TryStreamExt,
+};
 use serde::{
     Deserialize,
     Serialize,
 };
-use tokio::{
-    fs::{
-        File,
-        ReadDir,
-    },
-    io::{
-        AsyncRead,
-        AsyncReadExt,
-        BufReader,
-    },
-};
 
 use crate::Error;
 
@@ -76,7 +73,7 @@ impl DataSet {
         &self.manifest
     }
 
-    pub async fn particles(&self) -> Result<ParticleDirReader, Error> {
+    pub async fn particles(&self, parallelism: usize) -> Result<ParticleDirReader, Error> {
         let file = self
             .manifest
             .data
@@ -90,67 +87,137 @@ impl DataSet {
 
         tracing::debug!(path = %path.display(), "opening particle directory");
 
-        Ok(ParticleDirReader::new(path).await?)
+        Ok(ParticleDirReader::new(path, parallelism).await?)
     }
 }
 
+/// Reads all particle files in a directory.
+///
+/// The files are read in the order of their file names, so repeated runs yield
+/// the particles in the same order. Up to `parallelism` files are read and
+/// decoded concurrently.
 pub struct ParticleDirReader {
-    read_dir: ReadDir,
-    file_reader: Option<ParticleFileReader<BufReader<File>>>,
+    files: BoxStream<'static, Result<Vec<Particle>, Error>>,
+    particles: std::vec::IntoIter<Particle>,
 }
 
 impl ParticleDirReader {
-    pub async fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let read_dir = tokio::fs::read_dir(path).await?;
+    pub async fn new(path: impl AsRef<Path>, parallelism: usize) -> Result<Self, Error> {
+        let mut read_dir = tokio::fs::read_dir(path).await?;
+
+        let mut paths = vec![];
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            if dir_entry.file_type().await?.is_file() {
+                paths.push(dir_entry.path());
+            }
+        }
+        paths.sort();
+
+        let files = futures::stream::iter(paths)
+            .map(|path| async move { tokio::spawn(read_particle_file(path)).await? })
+            .buffered(parallelism.max(1))
+            .boxed();
 
         Ok(Self {
-            read_dir,
-            file_reader: None,
+            files,
+            particles: Vec::new().into_iter(),
         })
     }
 
     pub async fn read_particle(&mut self) -> Result<Option<Particle>, Error> {
         loop {
-            if self.file_reader.is_none() {
-                let Some(dir_entry) = self.read_dir.next_entry().await?
-                else {
-                    return Ok(None);
-                };
-
-                let path = dir_entry.path();
-                tracing::debug!(path = %path.display(), "opening particle file");
-                self.file_reader =
-                    Some(ParticleFileReader::new(BufReader::new(File::open(path).await?)).await?);
-            }
-
-            let file_reader = self.file_reader.as_mut().unwrap();
-
-            if let Some(particle) = file_reader.read_particle().await? {
+            if let Some(particle) = self.particles.next() {
                 return Ok(Some(particle));
             }
+
+            let Some(particles) = self.files.try_next().await?
             else {
-                self.file_reader = None;
-                //return Ok(None); // debug
-            }
+                return Ok(None);
+            };
+            self.particles = particles.into_iter();
         }
     }
 }
 
-pub struct ParticleFileReader<R> {
-    reader: R,
+async fn read_particle_file(path: PathBuf) -> Result<Vec<Particle>, Error> {
+    tracing::debug!(path = %path.display(), "reading particle file");
+    let data = tokio::fs::read(&path).await?;
+
+    tokio::task::spawn_blocking(move || {
+        let mut file_reader = ParticleFileReader::new(&data)?;
+        let mut particles = Vec::with_capacity(file_reader.capacity());
+        while let Some(particle) = file_reader.read_particle()? {
+            particles.push(particle);
+        }
+        Ok(particles)
+    })
+    .await?
+}
+
+/// Big-endian reader over an in-memory buffer.
+struct ByteReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let Some((bytes, rest)) = self.data.split_first_chunk::<N>()
+        else {
+            bail!("unexpected end of particle file");
+        };
+        self.data = rest;
+        Ok(*bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.read_bytes()?))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(self.read_bytes()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.read_bytes()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.read_bytes()?))
+    }
+
+    fn read_f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_be_bytes(self.read_bytes()?))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_be_bytes(self.read_bytes()?))
+    }
+}
+
+/// Size of a particle without names.
+const MIN_PARTICLE_SIZE: usize = 80;
+
+pub struct ParticleFileReader<'a> {
+    reader: ByteReader<'a>,
     num_read: u32,
     num_particles: u32,
 }
 
-impl<R: AsyncRead + Unpin> ParticleFileReader<R> {
-    pub async fn new(mut reader: R) -> Result<Self, Error> {
-        let tag = reader.read_i32().await?;
+impl<'a> ParticleFileReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = ByteReader { data };
+
+        let tag = reader.read_i32()?;
         ensure!(tag == -1, "invalid file tag");
 
-        let version = reader.read_i32().await?;
+        let version = reader.read_i32()?;
         ensure!(version == 2, "unsupported version");
 
-        let num_particles = reader.read_u32().await?;
+        let num_particles = reader.read_u32()?;
 
         Ok(Self {
             reader,
@@ -159,32 +226,40 @@ impl<R: AsyncRead + Unpin> ParticleFileReader<R> {
         })
     }
 
-    pub async fn read_particle(&mut self) -> Result<Option<Particle>, Error> {
+    /// Number of particles that can still be read, as far as the header and
+    /// the size of the file agree. A corrupt header won't make us allocate
+    /// more than the file could hold.
+    pub fn capacity(&self) -> usize {
+        let num_remaining = self.num_particles.saturating_sub(self.num_read) as usize;
+        num_remaining.min(self.reader.remaining() / MIN_PARTICLE_SIZE)
+    }
+
+    pub fn read_particle(&mut self) -> Result<Option<Particle>, Error> {
         if self.num_read >= self.num_particles {
             return Ok(None);
         }
 
         let position = Vector3 {
-            x: self.reader.read_f64().await?,
-            y: self.reader.read_f64().await?,
-            z: self.reader.read_f64().await?,
+            x: self.reader.read_f64()?,
+            y: self.reader.read_f64()?,
+            z: self.reader.read_f64()?,
         };
 
         let proper_motion = Vector3 {
-            x: self.reader.read_f32().await?,
-            y: self.reader.read_f32().await?,
-            z: self.reader.read_f32().await?,
+            x: self.reader.read_f32()?,
+            y: self.reader.read_f32()?,
+            z: self.reader.read_f32()?,
         };
 
-        let mu_alpha = self.reader.read_f32().await?;
-        let mu_delta = self.reader.read_f32().await?;
-        let radial_velocity = self.reader.read_f32().await?;
-        let apparent_magnitude = self.reader.read_f32().await?;
-        let absolute_magnitude = self.reader.read_f32().await?;
-        let color = self.reader.read_f32().await?;
-        let size = self.reader.read_f32().await?;
-        let hip = self.reader.read_u32().await?;
-        let source_id = self.reader.read_u64().await?;
+        let mu_alpha = self.reader.read_f32()?;
+        let mu_delta = self.reader.read_f32()?;
+        let radial_velocity = self.reader.read_f32()?;
+        let apparent_magnitude = self.reader.read_f32()?;
+        let absolute_magnitude = self.reader.read_f32()?;
+        let color = self.reader.read_f32()?;
+        let size = self.reader.read_f32()?;
+        let hip = self.reader.read_u32()?;
+        let source_id = self.reader.read_u64()?;
 
         let color = {
             // public static int floatToIntColor (float value) {
@@ -212,14 +287,14 @@ impl<R: AsyncRead + Unpin> ParticleFileReader<R> {
         const SCALE: f64 = 1e9; // m per gaia-sky unit
         const STAR_SCALE: f32 = 1.31526e-6;
 
-        let names_length = self.reader.read_u32().await?;
+        let names_length = self.reader.read_u32()?;
         let mut names = vec![];
         if names_length > 0 {
             let n = names_length as usize;
             let mut buf = String::new();
 
             for _ in 0..n {
-                let c = self.reader.read_u16().await?;
+                let c = self.reader.read_u16()?;
                 if c == b'|' as u16 {
                     if !buf.is_empty() {
                         names.push(std::mem::replace(&mut buf, String::new()));
@@ -284,9 +359,9 @@ pub struct Particle {
     pub names: Vec<String>,
 }
 
-pub async fn load_gaia_sky(path: impl AsRef<Path>) -> Result<(), Error> {
+pub async fn load_gaia_sky(path: impl AsRef<Path>, parallelism: usize) -> Result<(), Error> {
     let dataset = DataSet::open(path).await?;
-    let mut particles = dataset.particles().await?;
+    let mut particles = dataset.particles(parallelism).await?;
 
     while let Some(particle) = particles.read_particle().await? {
         //if particle.names.len() >= 2 {
@@ -308,3 +383,60 @@ pub async fn load_gaia_sky(path: impl AsRef<Path>) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particle_file(source_ids: &[u64]) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&(-1i32).to_be_bytes());
+        data.extend_from_slice(&2i32.to_be_bytes());
+        data.extend_from_slice(&(source_ids.len() as u32).to_be_bytes());
+        for source_id in source_ids {
+            // position, proper motion and the 7 other floats
+            data.extend_from_slice(&[0; 3 * 8 + 3 * 4 + 7 * 4]);
+            // hip
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend_from_slice(&source_id.to_be_bytes());
+            // names
+            data.extend_from_slice(&0u32.to_be_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn caps_capacity_by_file_size() {
+        let mut data = particle_file(&[1, 2]);
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let mut reader = ParticleFileReader::new(&data).unwrap();
+        assert_eq!(reader.capacity(), 2);
+        reader.read_particle().unwrap().unwrap();
+        assert_eq!(reader.capacity(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_files_in_order_of_their_names() {
+        let dir = tempfile::tempdir().unwrap();
+        // created out of order, and small files finish decoding first
+        let files = [
+            ("c.bin", 1000..1003),
+            ("a.bin", 0..500),
+            ("b.bin", 500..1000),
+        ];
+        for (name, source_ids) in files {
+            let source_ids = source_ids.collect::<Vec<_>>();
+            std::fs::write(dir.path().join(name), particle_file(&source_ids)).unwrap();
+        }
+
+        for _ in 0..3 {
+            let mut reader = ParticleDirReader::new(dir.path(), 3).await.unwrap();
+            let mut source_ids = vec![];
+            while let Some(particle) = reader.read_particle().await.unwrap() {
+                source_ids.push(particle.source_id);
+            }
+            assert_eq!(source_ids, (0..1003).collect::<Vec<_>>());
+        }
+    }
+}
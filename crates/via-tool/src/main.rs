@@ -1,5 +1,4 @@
-#![feature(btree_cursors)]
-
+mod filter;
 mod gaia;
 mod gaiasky;
 mod render;
@@ -8,7 +7,6 @@ mod utils;
 use std::path::PathBuf;
 
 use color_eyre::eyre::Error;
-use gaia::Data;
 use structopt::StructOpt;
 
 use crate::gaiasky::load_gaia_sky;
@@ -26,25 +24,102 @@ pub struct Args {
 enum Command {
     LoadGaiaSky {
         path: PathBuf,
+        /// Number of particle files to read in parallel. Defaults to the
+        /// number of CPUs.
+        #[structopt(short, long)]
+        jobs: Option<usize>,
     },
     Render {
         #[structopt(short, long)]
         output: PathBuf,
         path: PathBuf,
-        #[structopt(short, long, default_value = "top-down")]
-        view: render::View,
-        #[structopt(short, long, default_value = "1024")]
-        width: u32,
+        #[structopt(flatten)]
+        options: render::RenderOptions,
     },
+    /// Renders the sky as seen from an observer into a cubemap or
+    /// equirectangular panorama.
+    Skybox {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::SkyboxOptions,
+    },
+    /// Renders the sky into a HiPS directory.
+    Hips {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::HipsOptions,
+    },
+    /// Renders the top-down view into an XYZ tile pyramid.
+    Tiles {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::TilesOptions,
+    },
+    /// Renders a frame sequence along a camera path or over time.
+    Animate {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::AnimateOptions,
+    },
+    /// Renders a Hertzsprung-Russell or colour-magnitude diagram. Quality cuts
+    /// are given with `--filter`, like `parallax_over_error > 10 && distance <
+    /// 200`.
+    Diagram {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::DiagramOptions,
+    },
+    /// Exports Gaia records as binary records for rendering, or as Arrow
+    /// IPC, Parquet, FITS or VOTable with selected columns.
     Export {
         #[structopt(short, long)]
         output: PathBuf,
         path: PathBuf,
-        #[structopt(short, long, default_value = "1024")]
-        limit_per_file: u64,
+        #[structopt(flatten)]
+        options: render::ExportOptions,
+    },
+    /// Sorts an export spatially and adds an octree index, so that regions
+    /// can be read without scanning the whole export.
+    Sort {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::SortOptions,
+    },
+    /// Packs an export into a compact, compressed format with quantized
+    /// positions for distribution with the client.
+    Pack {
+        #[structopt(short, long)]
+        output: PathBuf,
+        path: PathBuf,
+        #[structopt(flatten)]
+        options: render::PackOptions,
     },
-    Test {
+    /// Writes the source id index of an export. Exports and sorted exports
+    /// are also indexed when they are written with `--index`.
+    Index { path: PathBuf },
+    /// Looks up records of an export by their source ids, using its index,
+    /// and prints them as JSON.
+    Lookup { path: PathBuf, source_ids: Vec<u64> },
+    /// Writes selected columns of Gaia records as CSV or JSON Lines, to the
+    /// standard output unless an output is given.
+    Dump {
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
         path: PathBuf,
+        #[structopt(flatten)]
+        options: render::DumpOptions,
     },
 }
 
@@ -53,38 +128,91 @@ impl Args {
         //let mut db = PgPool::connect(&self.database_url).await?;
 
         match self.command {
-            Command::LoadGaiaSky { path } => {
-                load_gaia_sky(path).await?;
+            Command::LoadGaiaSky { path, jobs } => {
+                load_gaia_sky(path, jobs.unwrap_or_else(num_cpus::get)).await?;
             }
             Command::Render {
                 output,
                 path,
-                view,
-                width,
+                options,
             } => {
-                render::render(output, path, view, width).await?;
+                render::render(output, path, options).await?;
+            }
+            Command::Skybox {
+                output,
+                path,
+                options,
+            } => {
+                render::skybox(output, path, options).await?;
+            }
+            Command::Hips {
+                output,
+                path,
+                options,
+            } => {
+                render::hips(output, path, options).await?;
+            }
+            Command::Tiles {
+                output,
+                path,
+                options,
+            } => {
+                render::tiles(output, path, options).await?;
+            }
+            Command::Animate {
+                output,
+                path,
+                options,
+            } => {
+                render::animate(output, path, options).await?;
+            }
+            Command::Diagram {
+                output,
+                path,
+                options,
+            } => {
+                render::diagram(output, path, options).await?;
             }
             Command::Export {
                 output,
                 path,
-                limit_per_file,
+                options,
             } => {
-                render::export(output, path, limit_per_file).await?;
+                render::export(output, path, options).await?;
             }
-            Command::Test { path } => {
-                let data = Data::open(path).await?;
-                let mut records = data.records();
-
-                while let Some(record) = records.read_record().await? {
-                    println!(
-                        "{} - {}: {}, {:?}",
-                        record.healpix_range.start,
-                        record.healpix_range.end,
-                        record.gaia_source.source_id,
-                        record.astrophysical_parameters.map(|x| x.source_id),
-                    )
+            Command::Sort {
+                output,
+                path,
+                options,
+            } => {
+                render::sort(output, path, options).await?;
+            }
+            Command::Pack {
+                output,
+                path,
+                options,
+            } => {
+                render::pack(output, path, options).await?;
+            }
+            Command::Index { path } => {
+                render::write_source_index(path).await?;
+            }
+            Command::Lookup { path, source_ids } => {
+                let records = render::lookup(path, &source_ids).await?;
+                for (source_id, record) in source_ids.iter().zip(records) {
+                    match record {
+                        Some(record) => println!("{}", serde_json::to_string(&record)?),
+                        None => tracing::warn!(source_id, "not found"),
+                    }
                 }
             }
+            Command::Dump {
+                output,
+                path,
+                options,
+            } => {
+                render::dump(output, path, options).await?;
+            }
         }
 
         Ok(())
@@ -0,0 +1,340 @@
+//! Frame sequences, either along a camera path or advancing time by moving the
+//! stars with their velocity.
+
+use std::{
+    fs::File,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use color_eyre::eyre::ensure;
+use image::{
+    codecs::gif::{
+        GifEncoder,
+        Repeat,
+    },
+    Delay,
+    DynamicImage,
+    Frame,
+    RgbImage,
+};
+use nalgebra::{
+    Point3,
+    Unit,
+    Vector3,
+};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use super::{
+    camera::Observer,
+    canvas::Canvas,
+    RenderOptions,
+    Viewport,
+};
+use crate::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct AnimateOptions {
+    /// Number of frames. Defaults to one past the last keyframe of the camera
+    /// path, or 100.
+    #[structopt(long)]
+    pub frames: Option<u32>,
+
+    /// Years the stars move per frame, with their proper motion and radial
+    /// velocity.
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub years_per_frame: f64,
+
+    /// JSON file with a list of camera keyframes, e.g.
+    /// `[{"frame": 0, "observer": [0, 0, 0], "direction": [1, 0, 0]}, ...]`.
+    /// Keyframes can also set `up` and `fov`; anything left out is taken from
+    /// the options. Between keyframes the observer and field of view are
+    /// interpolated linearly, and the direction and up vector are slerped.
+    /// Consecutive keyframes with opposite directions or up vectors are an
+    /// error, as the rotation between them is ambiguous.
+    #[structopt(long)]
+    pub camera_path: Option<PathBuf>,
+
+    /// Frame rate of animated GIFs.
+    #[structopt(long, default_value = "25")]
+    pub fps: u32,
+
+    /// Approximate memory in MiB used for frames. The export is read once for
+    /// every batch of frames that fits.
+    #[structopt(long, default_value = "4096")]
+    pub memory_limit: u64,
+
+    #[structopt(flatten)]
+    pub render: RenderOptions,
+}
+
+/// A keyframe as given in the camera path file.
+#[derive(Debug, Deserialize)]
+struct Keyframe {
+    frame: u32,
+    /// Heliocentric galactic coordinates in parsec.
+    observer: Option<[f64; 3]>,
+    direction: Option<[f64; 3]>,
+    up: Option<[f64; 3]>,
+    /// Field of view in degrees.
+    fov: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+struct CameraState {
+    observer: Point3<f64>,
+    direction: Vector3<f64>,
+    up: Vector3<f64>,
+    fov: f64,
+}
+
+/// Rotates `a` towards `b` along the great circle through both. They must not
+/// point in opposite directions.
+fn slerp(a: &Vector3<f64>, b: &Vector3<f64>, t: f64) -> Vector3<f64> {
+    Unit::new_normalize(*a)
+        .slerp(&Unit::new_normalize(*b), t)
+        .into_inner()
+}
+
+/// Whether [`slerp`] can interpolate between the vectors.
+fn can_slerp(a: &Vector3<f64>, b: &Vector3<f64>) -> bool {
+    match (a.try_normalize(f64::EPSILON), b.try_normalize(f64::EPSILON)) {
+        (Some(a), Some(b)) => a.dot(&b) > -1.0 + 1e-9,
+        _ => false,
+    }
+}
+
+impl CameraState {
+    fn lerp(&self, other: &Self, t: f64) -> Self {
+        Self {
+            observer: self.observer + t * (other.observer - self.observer),
+            direction: slerp(&self.direction, &other.direction, t),
+            up: slerp(&self.up, &other.up, t),
+            fov: self.fov + t * (other.fov - self.fov),
+        }
+    }
+}
+
+/// Keyframes sorted by frame, with everything they leave out filled in.
+struct CameraPath {
+    keyframes: Vec<(u32, CameraState)>,
+}
+
+impl CameraPath {
+    async fn load(path: &Path, defaults: &CameraState) -> Result<Self, Error> {
+        let keyframes: Vec<Keyframe> = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        ensure!(!keyframes.is_empty(), "the camera path has no keyframes");
+
+        let mut keyframes = keyframes
+            .into_iter()
+            .map(|keyframe| {
+                let state = CameraState {
+                    observer: keyframe.observer.map_or(defaults.observer, Point3::from),
+                    direction: keyframe.direction.map_or(defaults.direction, Vector3::from),
+                    up: keyframe.up.map_or(defaults.up, Vector3::from),
+                    fov: keyframe.fov.unwrap_or(defaults.fov),
+                };
+                (keyframe.frame, state)
+            })
+            .collect::<Vec<_>>();
+        keyframes.sort_by_key(|(frame, _)| *frame);
+
+        for ((start_frame, start), (end_frame, end)) in keyframes.iter().zip(&keyframes[1..]) {
+            ensure!(
+                can_slerp(&start.direction, &end.direction) && can_slerp(&start.up, &end.up),
+                "the camera can't turn around between frames {start_frame} and {end_frame}, as \
+                 the directions or up vectors are opposite or zero. Add a keyframe in between."
+            );
+        }
+
+        Ok(Self { keyframes })
+    }
+
+    fn num_frames(&self) -> u32 {
+        self.keyframes.last().unwrap().0 + 1
+    }
+
+    /// Camera at the frame. Before the first and after the last keyframe, the
+    /// camera stays in place.
+    fn at(&self, frame: u32) -> CameraState {
+        let next = self
+            .keyframes
+            .partition_point(|(keyframe, _)| *keyframe <= frame);
+        if next == 0 {
+            return self.keyframes[0].1.clone();
+        }
+        let (start_frame, start) = &self.keyframes[next - 1];
+        let Some((end_frame, end)) = self.keyframes.get(next)
+        else {
+            return start.clone();
+        };
+
+        let t = (frame - start_frame) as f64 / (end_frame - start_frame) as f64;
+        start.lerp(end, t)
+    }
+}
+
+/// Writes frames as numbered PNGs into a directory, or into an animated GIF.
+enum FrameWriter {
+    Directory(PathBuf),
+    Gif {
+        encoder: GifEncoder<File>,
+        delay: Delay,
+    },
+}
+
+impl FrameWriter {
+    fn new(output: &Path, fps: u32) -> Result<Self, Error> {
+        tracing::info!("writing frames: {}", output.display());
+
+        if output
+            .extension()
+            .is_some_and(|extension| extension == "gif")
+        {
+            let mut encoder = GifEncoder::new(File::create(output)?);
+            encoder.set_repeat(Repeat::Infinite)?;
+            Ok(Self::Gif {
+                encoder,
+                delay: Delay::from_numer_denom_ms(1000, fps.max(1)),
+            })
+        }
+        else {
+            std::fs::create_dir_all(output)?;
+            Ok(Self::Directory(output.to_owned()))
+        }
+    }
+
+    fn write(&mut self, index: u32, image: RgbImage) -> Result<(), Error> {
+        match self {
+            Self::Directory(directory) => image.save(directory.join(format!("{index:05}.png")))?,
+            Self::Gif { encoder, delay } => {
+                let image = DynamicImage::ImageRgb8(image).into_rgba8();
+                encoder.encode_frame(Frame::from_parts(image, 0, 0, *delay))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders a frame sequence. A `.gif` output is written as an animated GIF,
+/// any other output is a directory the frames are written to as
+/// `00000.png`, `00001.png`, etc.
+pub async fn animate(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: AnimateOptions,
+) -> Result<(), Error> {
+    let render = &options.render;
+    ensure!(
+        render.stats.statistic.is_none(),
+        "statistic maps can't be animated"
+    );
+    ensure!(
+        render.bit_depth == 8,
+        "frames are only written with 8 bits per channel"
+    );
+
+    let path = path.as_ref();
+    let observer = render.camera.observer.resolve(path).await?;
+
+    let defaults = CameraState {
+        observer: observer.position,
+        direction: render.camera.direction,
+        up: render.camera.up,
+        fov: render.fov,
+    };
+    let camera_path = match &options.camera_path {
+        Some(camera_path) => Some(CameraPath::load(camera_path, &defaults).await?),
+        None => None,
+    };
+    let num_frames = options
+        .frames
+        .or_else(|| camera_path.as_ref().map(CameraPath::num_frames))
+        .unwrap_or(100);
+
+    let viewport = |frame: u32| {
+        let camera = camera_path
+            .as_ref()
+            .map_or_else(|| defaults.clone(), |camera_path| camera_path.at(frame));
+        let observer = Observer {
+            position: camera.observer,
+            source_id: observer.source_id,
+        };
+        Viewport::with_camera(render, observer, camera.direction, camera.up, camera.fov)
+    };
+
+    let [width, height] = viewport(0)?.image_size();
+    let jobs = render.select.num_jobs();
+    let frame_bytes = 3 * 4 * width as u64 * height as u64;
+    let batch_size = (options.memory_limit * 1024 * 1024 / (frame_bytes * jobs as u64)).max(1);
+
+    let mut writer = FrameWriter::new(output.as_ref(), options.fps)?;
+
+    for batch_start in (0..num_frames).step_by(batch_size as usize) {
+        let batch_end = (batch_start + batch_size as u32).min(num_frames);
+        tracing::info!(batch_start, batch_end, num_frames, "rendering frames");
+
+        let frames = (batch_start..batch_end)
+            .map(|frame| Ok((viewport(frame)?, frame as f64 * options.years_per_frame)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut threads = render
+            .select
+            .draw_parallel(
+                path,
+                || {
+                    frames
+                        .iter()
+                        .map(|_| Canvas::new(width, height))
+                        .collect::<Vec<_>>()
+                },
+                |canvases, record| {
+                    for ((viewport, years), canvas) in frames.iter().zip(canvases) {
+                        let moved;
+                        let record = if *years == 0.0 {
+                            record
+                        }
+                        else {
+                            moved = record.at_epoch(*years);
+                            &moved
+                        };
+                        viewport.draw_particle(canvas, &render.psf, record);
+                    }
+                },
+            )
+            .await?
+            .into_iter();
+
+        let mut canvases = threads.next().unwrap();
+        for other in threads {
+            for (canvas, other) in canvases.iter_mut().zip(&other) {
+                canvas.merge(other);
+            }
+        }
+
+        for (index, ((viewport, _), canvas)) in (batch_start..).zip(frames.iter().zip(canvases)) {
+            let mut image = canvas.tone_map(render.tone_map, render.exposure);
+            viewport.draw_overlay(&mut image);
+            writer.write(index, image)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slerps_directions() {
+        let direction = slerp(&Vector3::x(), &Vector3::y(), 0.5);
+        assert!((direction - Vector3::new(1.0, 1.0, 0.0).normalize()).norm() < 1e-12);
+        assert!(can_slerp(&Vector3::x(), &Vector3::x()));
+        assert!(!can_slerp(&Vector3::x(), &-Vector3::x()));
+        assert!(!can_slerp(&Vector3::x(), &Vector3::zeros()));
+    }
+}
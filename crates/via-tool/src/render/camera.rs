@@ -0,0 +1,278 @@
+use std::{
+    path::Path,
+    str::FromStr,
+};
+
+use color_eyre::eyre::{
+    bail,
+    ensure,
+    eyre,
+};
+use nalgebra::{
+    Point3,
+    Vector3,
+};
+use structopt::StructOpt;
+
+use super::{
+    lookup::{
+        index_path,
+        lookup,
+    },
+    Record,
+    RecordReader,
+};
+use crate::{
+    utils::parse_vector3,
+    Error,
+};
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct CameraOptions {
+    /// Position of the observer. Either heliocentric galactic coordinates
+    /// `x,y,z` in parsec, or `star:<source_id>`. This applies to the sky and
+    /// perspective views.
+    #[structopt(long, default_value = "0,0,0", allow_hyphen_values = true)]
+    pub observer: ObserverSpec,
+
+    /// Viewing direction `x,y,z` of the perspective and orthographic views in
+    /// galactic coordinates.
+    #[structopt(
+        long,
+        default_value = "1,0,0",
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_vector3)
+    )]
+    pub direction: Vector3<f64>,
+
+    /// Up vector `x,y,z` of the perspective and orthographic views in galactic
+    /// coordinates.
+    #[structopt(
+        long,
+        default_value = "0,0,1",
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_vector3)
+    )]
+    pub up: Vector3<f64>,
+
+    /// Height of the perspective and orthographic views. Defaults to the
+    /// width.
+    #[structopt(long)]
+    pub height: Option<u32>,
+}
+
+/// Observer position as given on the command line.
+#[derive(Clone, Debug)]
+pub enum ObserverSpec {
+    /// Heliocentric galactic coordinates in parsec.
+    Position(Point3<f64>),
+    /// At the star with this source ID.
+    Star(u64),
+}
+
+impl ObserverSpec {
+    /// Resolves the observer position. For [`ObserverSpec::Star`] the star is
+    /// looked up in the source id index of the export, or the export is
+    /// scanned for it if there is no index.
+    pub async fn resolve(&self, path: impl AsRef<Path>) -> Result<Observer, Error> {
+        match self {
+            Self::Position(position) => {
+                Ok(Observer {
+                    position: *position,
+                    source_id: None,
+                })
+            }
+            Self::Star(source_id) => {
+                let path = path.as_ref();
+                let record = if index_path(path).exists() {
+                    lookup(path, &[*source_id]).await?.pop().flatten()
+                }
+                else {
+                    find_record(path, *source_id).await?
+                };
+                let Some(record) = record
+                else {
+                    bail!("star {source_id} not found in {}", path.display());
+                };
+
+                let position = record.heliocentric_position();
+                tracing::info!(source_id, ?position, "observer at star");
+                Ok(Observer {
+                    position,
+                    source_id: Some(*source_id),
+                })
+            }
+        }
+    }
+}
+
+/// Scans the export at `path` for the record with the source id.
+async fn find_record(path: &Path, source_id: u64) -> Result<Option<Record>, Error> {
+    tracing::info!("scanning for star {source_id}, index the export to find it faster");
+    let mut records = RecordReader::open(path).await?;
+    while let Some(record) = records.read_record().await? {
+        if record.source_id == source_id {
+            return Ok(Some(record));
+        }
+    }
+    Ok(None)
+}
+
+impl FromStr for ObserverSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(source_id) = s.strip_prefix("star:") {
+            Ok(Self::Star(source_id.parse()?))
+        }
+        else {
+            Ok(Self::Position(parse_vector3(s)?.into()))
+        }
+    }
+}
+
+/// A star as seen by an [`Observer`].
+pub struct Observation {
+    /// Unit vector from the observer to the star in galactic coordinates.
+    pub direction: Vector3<f64>,
+    /// Distance from the observer in parsec.
+    pub distance: f64,
+    pub apparent_magnitude: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Observer {
+    /// Heliocentric galactic coordinates in parsec.
+    pub position: Point3<f64>,
+    /// The star the observer is at. It is not visible to the observer.
+    pub source_id: Option<u64>,
+}
+
+impl Observer {
+    pub fn is_heliocentric(&self) -> bool {
+        self.position == Point3::origin()
+    }
+
+    /// Direction and apparent magnitude of the star from the observer's
+    /// position.
+    pub fn observe(&self, record: &Record) -> Option<Observation> {
+        if self.source_id == Some(record.source_id) {
+            return None;
+        }
+
+        if self.is_heliocentric() {
+            // use the catalogue values, which don't depend on the parallax.
+            return Some(Observation {
+                direction: record.direction(),
+                distance: 1000.0 * record.distance(),
+                apparent_magnitude: record.apparent_magnitude,
+            });
+        }
+
+        if record.parallax <= 0.0 {
+            return None;
+        }
+
+        let offset = record.heliocentric_position() - self.position;
+        let distance = offset.norm();
+        if distance <= 0.0 {
+            return None;
+        }
+
+        Some(Observation {
+            direction: offset / distance,
+            distance,
+            apparent_magnitude: record.absolute_magnitude() + 5.0 * (distance.log10() as f32 - 1.0),
+        })
+    }
+}
+
+/// Pinhole camera for the perspective view.
+pub struct Camera {
+    right: Vector3<f64>,
+    up: Vector3<f64>,
+    forward: Vector3<f64>,
+    /// `tan` of half the horizontal and vertical field of view.
+    scale: [f64; 2],
+    width: u32,
+    height: u32,
+}
+
+impl Camera {
+    /// `direction` and `up` are given in galactic coordinates, `fov` is the
+    /// horizontal field of view in degrees. Fails if the direction is zero or
+    /// parallel to the up vector, or the field of view isn't between 0 and
+    /// 180 degrees.
+    pub fn new(
+        direction: Vector3<f64>,
+        up: Vector3<f64>,
+        fov: f64,
+        [width, height]: [u32; 2],
+    ) -> Result<Self, Error> {
+        let forward = direction
+            .try_normalize(f64::EPSILON)
+            .ok_or_else(|| eyre!("camera direction must not be zero"))?;
+        let right = forward.cross(&up);
+        ensure!(
+            right.norm() > 1e-9,
+            "camera direction {direction:?} is parallel to the up vector {up:?}"
+        );
+        ensure!(
+            fov > 0.0 && fov < 180.0,
+            "the field of view must be between 0 and 180 degrees, but is {fov}"
+        );
+        let right = right.normalize();
+        let up = right.cross(&forward);
+
+        let scale_x = (0.5 * fov.to_radians()).tan();
+        let scale_y = scale_x * height as f64 / width as f64;
+
+        Ok(Self {
+            right,
+            up,
+            forward,
+            scale: [scale_x, scale_y],
+            width,
+            height,
+        })
+    }
+
+    pub fn image_size(&self) -> [u32; 2] {
+        [self.width, self.height]
+    }
+
+    /// Projects a direction in galactic coordinates to pixel coordinates.
+    pub fn project(&self, direction: &Vector3<f64>) -> Option<[f64; 2]> {
+        let z = direction.dot(&self.forward);
+        if z <= 0.0 {
+            return None;
+        }
+
+        let x = direction.dot(&self.right) / z / self.scale[0];
+        let y = direction.dot(&self.up) / z / self.scale[1];
+
+        Some([
+            0.5 * (x + 1.0) * self.width as f64,
+            0.5 * (1.0 - y) * self.height as f64,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_degenerate_cameras() {
+        let camera = |direction: Vector3<f64>, fov: f64| {
+            Camera::new(direction, Vector3::z(), fov, [64, 64])
+        };
+
+        assert!(camera(Vector3::x(), 60.0).is_ok());
+        assert!(camera(Vector3::zeros(), 60.0).is_err());
+        assert!(camera(Vector3::z(), 60.0).is_err());
+        for fov in [0.0, -10.0, 180.0, 270.0, f64::NAN] {
+            assert!(camera(Vector3::x(), fov).is_err(), "{fov}");
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::Path,
+};
+
+use color_eyre::eyre::bail;
+use image::{
+    codecs::hdr::HdrEncoder,
+    ImageBuffer,
+    Rgb,
+    Rgb32FImage,
+    RgbImage,
+};
+use palette::{
+    LinSrgb,
+    Srgb,
+};
+
+use super::tone_map::ToneMap;
+use crate::Error;
+
+/// Floating-point accumulation buffer.
+///
+/// Stars are added to the pixels they fall on, so overlapping stars sum up
+/// instead of overwriting each other. The buffer holds linear flux and must be
+/// tone mapped before it can be saved as a regular image.
+pub struct Canvas {
+    pub image: Rgb32FImage,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        let image = Rgb32FImage::from_pixel(width, height, Rgb([0.0; 3]));
+        Self { image }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+
+    /// Adds `color` scaled by `flux` to the pixel at `(x, y)`. Coordinates
+    /// outside of the canvas are ignored.
+    pub fn add(&mut self, x: i64, y: i64, color: LinSrgb, flux: f32) {
+        if x < 0 || y < 0 || x >= self.width() as i64 || y >= self.height() as i64 {
+            return;
+        }
+
+        let pixel = self.image.get_pixel_mut(x as u32, y as u32);
+        pixel.0[0] += color.red * flux;
+        pixel.0[1] += color.green * flux;
+        pixel.0[2] += color.blue * flux;
+    }
+
+    /// Adds the flux of another canvas of the same size.
+    pub fn merge(&mut self, other: &Canvas) {
+        for (channel, other) in self.image.iter_mut().zip(other.image.iter()) {
+            *channel += other;
+        }
+    }
+
+    /// Tone maps the accumulated flux of a pixel into sRGB.
+    fn tone_map_pixel(&self, x: u32, y: u32, tone_map: ToneMap, scale: f32) -> Srgb {
+        let [red, green, blue] = self.image.get_pixel(x, y).0;
+        Srgb::from_linear(tone_map.apply(LinSrgb::new(red, green, blue) * scale))
+    }
+
+    /// Tone maps the accumulated flux into an 8-bit sRGB image.
+    ///
+    /// `exposure` is given in stops, i.e. the flux is multiplied by
+    /// `2^exposure` before the tone mapping operator is applied.
+    pub fn tone_map(&self, tone_map: ToneMap, exposure: f32) -> RgbImage {
+        let scale = exposure.exp2();
+
+        RgbImage::from_fn(self.width(), self.height(), |x, y| {
+            let color: Srgb<u8> = self.tone_map_pixel(x, y, tone_map, scale).into_format();
+            Rgb([color.red, color.green, color.blue])
+        })
+    }
+
+    /// Tone maps the accumulated flux into a 16-bit sRGB image.
+    pub fn tone_map_16(&self, tone_map: ToneMap, exposure: f32) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+        let scale = exposure.exp2();
+
+        ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
+            let color: Srgb<u16> = self.tone_map_pixel(x, y, tone_map, scale).into_format();
+            Rgb([color.red, color.green, color.blue])
+        })
+    }
+
+    /// The linear flux multiplied by `2^exposure`, without tone mapping.
+    pub fn linear(&self, exposure: f32) -> Rgb32FImage {
+        let scale = exposure.exp2();
+
+        let mut image = self.image.clone();
+        for pixel in image.pixels_mut() {
+            for channel in &mut pixel.0 {
+                *channel *= scale;
+            }
+        }
+        image
+    }
+}
+
+/// How a canvas is written to a file, chosen by its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Tone mapped with 8 bits per channel.
+    Rgb8,
+    /// Tone mapped with 16 bits per channel.
+    Rgb16,
+    /// OpenEXR with the linear flux.
+    Exr,
+    /// Radiance HDR with the linear flux.
+    Hdr,
+}
+
+impl OutputFormat {
+    /// OpenEXR (`.exr`) and Radiance HDR (`.hdr`) files contain the linear
+    /// flux, anything else is tone mapped with `bit_depth` bits per channel.
+    /// 16 bits are only supported by PNG.
+    pub fn from_path(path: &Path, bit_depth: u8) -> Result<Self, Error> {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        let format = match (extension.as_deref(), bit_depth) {
+            (Some("exr"), _) => Self::Exr,
+            (Some("hdr"), _) => Self::Hdr,
+            (_, 8) => Self::Rgb8,
+            (Some("png"), 16) => Self::Rgb16,
+            (_, 16) => bail!("16 bits per channel are only supported for PNG"),
+            _ => bail!("unsupported bit depth: {bit_depth}"),
+        };
+
+        Ok(format)
+    }
+}
+
+/// Pixels an overlay, like the graticule, can be drawn onto.
+pub trait OverlayPixel {
+    /// Blends the pixel half-way towards the sRGB `color`.
+    fn overlay(&mut self, color: Rgb<u8>);
+}
+
+impl OverlayPixel for Rgb<u8> {
+    fn overlay(&mut self, color: Rgb<u8>) {
+        for (channel, color) in self.0.iter_mut().zip(color.0) {
+            *channel = ((*channel as u16 + color as u16) / 2) as u8;
+        }
+    }
+}
+
+impl OverlayPixel for Rgb<u16> {
+    fn overlay(&mut self, color: Rgb<u8>) {
+        for (channel, color) in self.0.iter_mut().zip(color.0) {
+            *channel = ((*channel as u32 + color as u32 * 257) / 2) as u16;
+        }
+    }
+}
+
+impl OverlayPixel for Rgb<f32> {
+    fn overlay(&mut self, color: Rgb<u8>) {
+        let [red, green, blue] = color.0;
+        let color: LinSrgb = Srgb::new(red, green, blue)
+            .into_format::<f32>()
+            .into_linear();
+        for (channel, color) in self.0.iter_mut().zip([color.red, color.green, color.blue]) {
+            *channel = 0.5 * (*channel + color);
+        }
+    }
+}
+
+/// Writes a floating-point image as Radiance HDR, which `image` can't do
+/// through [`ImageBuffer::save`].
+pub fn save_hdr(image: &Rgb32FImage, path: &Path) -> Result<(), Error> {
+    let writer = BufWriter::new(File::create(path)?);
+    let pixels = image.pixels().copied().collect::<Vec<_>>();
+    HdrEncoder::new(writer).encode(&pixels, image.width() as usize, image.height() as usize)?;
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use image::{
+    Rgb,
+    RgbImage,
+};
+
+use super::font::{
+    draw_text,
+    format_number,
+    text_width,
+    GLYPH_HEIGHT,
+};
+
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Colormap {
+    Viridis,
+    Magma,
+    Inferno,
+    /// Diverging colormap by Moreland, for quantities centered on a value.
+    Coolwarm,
+}
+
+impl Colormap {
+    /// sRGB colours at equally spaced points from 0 to 1.
+    fn control_points(&self) -> &'static [[u8; 3]] {
+        match self {
+            Self::Viridis => {
+                &[
+                    [68, 1, 84],
+                    [71, 44, 122],
+                    [59, 81, 139],
+                    [44, 113, 142],
+                    [33, 144, 141],
+                    [39, 173, 129],
+                    [92, 200, 99],
+                    [170, 220, 50],
+                    [253, 231, 37],
+                ]
+            }
+            Self::Magma => {
+                &[
+                    [0, 0, 4],
+                    [28, 16, 68],
+                    [79, 18, 123],
+                    [129, 37, 129],
+                    [181, 54, 122],
+                    [229, 80, 100],
+                    [251, 135, 97],
+                    [254, 194, 135],
+                    [252, 253, 191],
+                ]
+            }
+            Self::Inferno => {
+                &[
+                    [0, 0, 4],
+                    [31, 12, 72],
+                    [85, 15, 109],
+                    [136, 34, 106],
+                    [186, 54, 85],
+                    [227, 89, 51],
+                    [249, 142, 9],
+                    [249, 203, 53],
+                    [252, 255, 164],
+                ]
+            }
+            Self::Coolwarm => {
+                &[
+                    [59, 76, 192],
+                    [98, 130, 234],
+                    [141, 176, 254],
+                    [184, 208, 249],
+                    [221, 221, 221],
+                    [245, 196, 173],
+                    [244, 154, 123],
+                    [222, 96, 77],
+                    [180, 4, 38],
+                ]
+            }
+        }
+    }
+
+    /// Colour at `t`, which is clamped to `[0, 1]`.
+    pub fn sample(&self, t: f64) -> Rgb<u8> {
+        let points = self.control_points();
+        let position = t.clamp(0.0, 1.0) * (points.len() - 1) as f64;
+        let index = (position.floor() as usize).min(points.len() - 2);
+        let fraction = position - index as f64;
+
+        let [a, b] = [points[index], points[index + 1]];
+        Rgb(std::array::from_fn(|i| {
+            (a[i] as f64 + fraction * (b[i] as f64 - a[i] as f64)).round() as u8
+        }))
+    }
+}
+
+const LABEL_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Appends a horizontal colorbar below `image`, labeled with the values at
+/// its left end, its center and its right end.
+pub fn with_colorbar(image: &RgbImage, colormap: Colormap, labels: [f64; 3]) -> RgbImage {
+    let scale = (image.width() / 512).max(1);
+    let margin = 4 * scale;
+    let bar_height = 6 * scale;
+    let strip_height = bar_height + (GLYPH_HEIGHT + 3) * scale + 2 * margin;
+
+    let mut output = RgbImage::new(image.width(), image.height() + strip_height);
+    image::imageops::replace(&mut output, image, 0, 0);
+
+    let bar_top = image.height() + margin;
+    // images too narrow for the margins get no bar.
+    let bar_width = image.width().saturating_sub(2 * margin);
+    for x in 0..bar_width {
+        let color = colormap.sample(x as f64 / (bar_width - 1).max(1) as f64);
+        for y in bar_top..bar_top + bar_height {
+            output.put_pixel(margin + x, y, color);
+        }
+    }
+
+    let label_top = bar_top + bar_height + 2 * scale;
+    let [left, center, right] = labels.map(format_number);
+    draw_text(&mut output, &left, [margin, label_top], scale, LABEL_COLOR);
+    let center_x = image.width().saturating_sub(text_width(&center, scale)) / 2;
+    draw_text(
+        &mut output,
+        &center,
+        [center_x, label_top],
+        scale,
+        LABEL_COLOR,
+    );
+    let right_x = (margin + bar_width).saturating_sub(text_width(&right, scale));
+    draw_text(
+        &mut output,
+        &right,
+        [right_x, label_top],
+        scale,
+        LABEL_COLOR,
+    );
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_colorbar_into_narrow_images() {
+        for width in 0..=10 {
+            let image = RgbImage::new(width, 3);
+            let output = with_colorbar(&image, Colormap::Magma, [1.0, 10.0, 100.0]);
+            assert_eq!(output.width(), width);
+            assert!(output.height() > 3);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Hertzsprung-Russell and colour-magnitude diagrams.
+//!
+//! Stars are selected with `--filter` and `--region`, so quality cuts and
+//! parts of the sky are given as filter expressions, like
+//! `parallax_over_error > 10 && distance < 200 && (longitude > 350 ||
+//! longitude < 10)`.
+
+use std::path::Path;
+
+use color_eyre::eyre::ensure;
+use image::{
+    Rgb,
+    RgbImage,
+};
+use structopt::StructOpt;
+
+use super::{
+    colormap::{
+        with_colorbar,
+        Colormap,
+    },
+    font::{
+        draw_text,
+        format_number,
+        text_width,
+        GLYPH_HEIGHT,
+    },
+    Record,
+    SelectOptions,
+};
+use crate::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct DiagramOptions {
+    /// Kind of diagram: hr (Teff against luminosity) or cmd (BP-RP against
+    /// absolute G magnitude).
+    #[structopt(short, long, default_value = "cmd")]
+    pub kind: DiagramKind,
+
+    #[structopt(short, long, default_value = "1024")]
+    pub width: u32,
+
+    /// Defaults to the width.
+    #[structopt(long)]
+    pub height: Option<u32>,
+
+    /// Colormap for the star density: viridis, magma, inferno or coolwarm.
+    #[structopt(long, default_value = "magma")]
+    pub colormap: Colormap,
+
+    #[structopt(flatten)]
+    pub select: SelectOptions,
+}
+
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DiagramKind {
+    /// Hertzsprung-Russell diagram.
+    Hr,
+    /// Colour-magnitude diagram.
+    Cmd,
+}
+
+impl DiagramKind {
+    /// Horizontal and vertical axis.
+    fn axes(&self) -> [Axis; 2] {
+        match self {
+            Self::Hr => {
+                [
+                    Axis {
+                        title: "TEFF (K)",
+                        range: [40000.0, 2400.0],
+                        log: true,
+                        ticks: &[3000.0, 4000.0, 6000.0, 10000.0, 20000.0],
+                    },
+                    Axis {
+                        title: "LOG L/LSUN",
+                        range: [-4.0, 6.0],
+                        log: false,
+                        ticks: &[-4.0, -2.0, 0.0, 2.0, 4.0, 6.0],
+                    },
+                ]
+            }
+            Self::Cmd => {
+                [
+                    Axis {
+                        title: "BP-RP",
+                        range: [-0.5, 4.5],
+                        log: false,
+                        ticks: &[0.0, 1.0, 2.0, 3.0, 4.0],
+                    },
+                    Axis {
+                        title: "MG",
+                        range: [17.0, -5.0],
+                        log: false,
+                        ticks: &[-5.0, 0.0, 5.0, 10.0, 15.0],
+                    },
+                ]
+            }
+        }
+    }
+
+    /// Coordinates of the star in the diagram, or `None` if the needed
+    /// quantities aren't known.
+    fn coordinates(&self, record: &Record) -> Option<[f64; 2]> {
+        if record.parallax <= 0.0 {
+            return None;
+        }
+        let coordinates = match self {
+            Self::Hr => {
+                let t_eff = record.t_eff as f64;
+                let extinction = if record.extinction.is_finite() {
+                    record.extinction
+                }
+                else {
+                    0.0
+                };
+                let bolometric_magnitude = (record.absolute_magnitude() - extinction) as f64
+                    + bolometric_correction(t_eff);
+                [
+                    t_eff,
+                    (SUN_BOLOMETRIC_MAGNITUDE - bolometric_magnitude) / 2.5,
+                ]
+            }
+            Self::Cmd => [record.bp_rp as f64, record.absolute_magnitude() as f64],
+        };
+        coordinates
+            .iter()
+            .all(|value| value.is_finite())
+            .then_some(coordinates)
+    }
+}
+
+const SUN_BOLOMETRIC_MAGNITUDE: f64 = 4.74;
+
+/// Bolometric correction for the G band by [Andrae et al. (2018)][1]. The
+/// polynomial is only defined for 3300 K to 8000 K, so temperatures outside of
+/// this are clamped.
+///
+/// [1]: https://doi.org/10.1051/0004-6361/201732516
+fn bolometric_correction(t_eff: f64) -> f64 {
+    let coefficients = if t_eff < 4000.0 {
+        [1.749, 1.977e-3, 3.737e-7, -8.966e-11, -4.183e-14]
+    }
+    else {
+        [6.000e-2, 6.731e-5, -6.647e-8, 2.859e-11, -7.197e-15]
+    };
+    let dt = t_eff.clamp(3300.0, 8000.0) - 5772.0;
+    coefficients.iter().rev().fold(0.0, |sum, c| sum * dt + c)
+}
+
+struct Axis {
+    title: &'static str,
+    /// Values at the left and right, or bottom and top end of the axis.
+    range: [f64; 2],
+    log: bool,
+    ticks: &'static [f64],
+}
+
+impl Axis {
+    /// Position of the value along the axis, from 0 to 1.
+    fn position(&self, value: f64) -> f64 {
+        let [start, end] = self.range;
+        if self.log {
+            (value.ln() - start.ln()) / (end.ln() - start.ln())
+        }
+        else {
+            (value - start) / (end - start)
+        }
+    }
+}
+
+const AXIS_COLOR: Rgb<u8> = Rgb([160, 160, 160]);
+const LABEL_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Area of the image inside the axes.
+struct PlotArea {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+impl PlotArea {
+    /// Pixel within the area, or `None` if the coordinates are outside of the
+    /// axes.
+    fn pixel(&self, axes: &[Axis; 2], [x, y]: [f64; 2]) -> Option<usize> {
+        let x = axes[0].position(x) * self.width as f64;
+        let y = (1.0 - axes[1].position(y)) * self.height as f64;
+        if !(x >= 0.0 && y >= 0.0 && x < self.width as f64 && y < self.height as f64) {
+            return None;
+        }
+        Some(y as usize * self.width as usize + x as usize)
+    }
+}
+
+fn draw_axes(image: &mut RgbImage, area: &PlotArea, axes: &[Axis; 2], scale: u32) {
+    let right = area.left + area.width;
+    let bottom = area.top + area.height;
+    let tick_length = 3 * scale;
+
+    for x in area.left - 1..=right {
+        image.put_pixel(x, area.top - 1, AXIS_COLOR);
+        image.put_pixel(x, bottom, AXIS_COLOR);
+    }
+    for y in area.top - 1..=bottom {
+        image.put_pixel(area.left - 1, y, AXIS_COLOR);
+        image.put_pixel(right, y, AXIS_COLOR);
+    }
+
+    let [x_axis, y_axis] = axes;
+
+    for &tick in x_axis.ticks {
+        let x = area.left + (x_axis.position(tick) * area.width as f64) as u32;
+        for y in bottom..bottom + tick_length {
+            image.put_pixel(x, y, AXIS_COLOR);
+        }
+        let label = format_number(tick);
+        let label_x = x.saturating_sub(text_width(&label, scale) / 2);
+        draw_text(
+            image,
+            &label,
+            [label_x, bottom + tick_length + scale],
+            scale,
+            LABEL_COLOR,
+        );
+    }
+    let title_x = area.left + area.width.saturating_sub(text_width(x_axis.title, scale)) / 2;
+    let title_y = bottom + tick_length + (GLYPH_HEIGHT + 4) * scale;
+    draw_text(image, x_axis.title, [title_x, title_y], scale, LABEL_COLOR);
+
+    for &tick in y_axis.ticks {
+        let y = area.top + ((1.0 - y_axis.position(tick)) * area.height as f64) as u32;
+        let y = y.min(bottom);
+        for x in area.left - 1 - tick_length..area.left - 1 {
+            image.put_pixel(x, y, AXIS_COLOR);
+        }
+        let label = format_number(tick);
+        let label_x =
+            (area.left - tick_length - 2 * scale).saturating_sub(text_width(&label, scale));
+        let label_y = y.saturating_sub(GLYPH_HEIGHT * scale / 2);
+        draw_text(image, &label, [label_x, label_y], scale, LABEL_COLOR);
+    }
+    // the vertical axis' title goes above it, so that it needn't be rotated.
+    let title_y = area.top.saturating_sub((GLYPH_HEIGHT + 4) * scale);
+    draw_text(image, y_axis.title, [scale, title_y], scale, LABEL_COLOR);
+}
+
+/// Renders a diagram with the density of stars shaded logarithmically.
+pub async fn diagram(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: DiagramOptions,
+) -> Result<(), Error> {
+    let width = options.width;
+    let height = options.height.unwrap_or(width);
+
+    let scale = (width / 512).max(1);
+    let area = PlotArea {
+        left: 32 * scale,
+        top: 12 * scale,
+        width: width.saturating_sub(44 * scale),
+        height: height.saturating_sub(36 * scale),
+    };
+    ensure!(
+        area.width > 0 && area.height > 0,
+        "the image is too small for the diagram"
+    );
+
+    let axes = options.kind.axes();
+
+    let mut histograms = options
+        .select
+        .draw_parallel(
+            path.as_ref(),
+            || vec![0u32; area.width as usize * area.height as usize],
+            |histogram, record| {
+                let Some(coordinates) = options.kind.coordinates(record)
+                else {
+                    return;
+                };
+                if let Some(pixel) = area.pixel(&axes, coordinates) {
+                    histogram[pixel] += 1;
+                }
+            },
+        )
+        .await?
+        .into_iter();
+
+    let mut histogram = histograms.next().unwrap();
+    for other in histograms {
+        for (count, other) in histogram.iter_mut().zip(other) {
+            *count += other;
+        }
+    }
+
+    let max_count = (histogram.iter().copied().max().unwrap_or_default() as f64).max(2.0);
+
+    let mut image = RgbImage::new(width, height);
+    for (index, &count) in histogram.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let x = area.left + (index % area.width as usize) as u32;
+        let y = area.top + (index / area.width as usize) as u32;
+        let t = (count as f64).ln() / max_count.ln();
+        image.put_pixel(x, y, options.colormap.sample(t));
+    }
+    draw_axes(&mut image, &area, &axes, scale);
+
+    let image = with_colorbar(&image, options.colormap, [1.0, max_count.sqrt(), max_count]);
+
+    let output = output.as_ref();
+    tracing::info!("writing image: {}", output.display());
+    image.save(output)?;
+
+    Ok(())
+}
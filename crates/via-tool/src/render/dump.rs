@@ -0,0 +1,329 @@
+//! Dumps of Gaia records as CSV or JSON Lines, for a quick look at the data.
+
+use std::{
+    fmt::{
+        self,
+        Display,
+    },
+    path::Path,
+};
+
+use csv_async::AsyncWriter;
+use serde::{
+    ser::SerializeMap,
+    Serialize,
+    Serializer,
+};
+use serde_json::Value;
+use structopt::StructOpt;
+use tokio::{
+    fs::File,
+    io::{
+        AsyncWrite,
+        AsyncWriteExt,
+        BufWriter,
+    },
+};
+
+use super::{
+    progress_bar,
+    sky::galactic_direction,
+    spatial::Region,
+    tabular::select_columns,
+};
+use crate::{
+    filter::Filter,
+    gaia::{
+        self,
+        Column,
+        ColumnType,
+        Data,
+        Row,
+    },
+    Error,
+};
+
+/// The format of a dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DumpFormat {
+    /// CSV with a header of the column names. Missing values are empty.
+    Csv,
+    /// One JSON object per line. Missing values are `null`.
+    Jsonl,
+}
+
+impl DumpFormat {
+    /// `.jsonl` and `.ndjson` files are written as JSON Lines, anything else
+    /// as CSV.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("jsonl" | "ndjson") => Self::Jsonl,
+            _ => Self::Csv,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DumpOptions {
+    /// Output format: csv or jsonl. Defaults to the format matching the
+    /// extension of the output, or CSV for the standard output.
+    #[structopt(short, long)]
+    pub format: Option<DumpFormat>,
+
+    /// Columns to dump, separated by commas, like for `export`. Defaults to
+    /// all columns of `gaia_source`.
+    #[structopt(long, use_delimiter = true)]
+    pub columns: Vec<String>,
+
+    /// Only dump records matching a filter expression, like
+    /// `parallax_over_error > 5 && ruwe < 1.4`, over the columns of
+    /// `gaia_source` and `astrophysical_parameters`.
+    #[structopt(long)]
+    pub filter: Option<Filter>,
+
+    /// Only dump stars within a region, given as `box:x,y,z,x,y,z` with the
+    /// minimum and maximum corner, or as `sphere:x,y,z,radius`, in
+    /// heliocentric galactic coordinates in parsec. Stars without `l`, `b`
+    /// or a positive parallax are never within a region.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub region: Option<Region>,
+
+    /// Stop after this many records.
+    #[structopt(long)]
+    pub limit: Option<u64>,
+}
+
+/// Whether the region contains the star of a Gaia record.
+fn in_region(region: &Region, record: &gaia::Record) -> bool {
+    let source = &record.gaia_source;
+    let (Some(longitude), Some(latitude), Some(parallax)) = (source.l, source.b, source.parallax)
+    else {
+        return false;
+    };
+
+    parallax > 0.0
+        && region.contains(&(1000.0 / parallax * galactic_direction(longitude, latitude)).into())
+}
+
+/// A value of a row, written with the precision of its column.
+struct Cell<'a> {
+    column_type: ColumnType,
+    value: &'a Value,
+}
+
+impl<'a> Cell<'a> {
+    fn new(column: &Column, row: &'a Row) -> Self {
+        Self {
+            column_type: column.column_type,
+            value: row.get(column),
+        }
+    }
+
+    /// serde_json keeps `f32` values as `f64`, so they are converted back to
+    /// be written with their shortest representation.
+    fn as_f32(&self) -> Option<f32> {
+        match (self.column_type, self.value) {
+            (ColumnType::F32, Value::Number(number)) => number.as_f64().map(|value| value as f32),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Cell<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(value) = self.as_f32() {
+            return write!(f, "{value}");
+        }
+
+        match self.value {
+            Value::Null => Ok(()),
+            Value::String(value) => f.write_str(value),
+            value => write!(f, "{value}"),
+        }
+    }
+}
+
+impl Serialize for Cell<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.as_f32() {
+            Some(value) => serializer.serialize_f32(value),
+            None => self.value.serialize(serializer),
+        }
+    }
+}
+
+/// The selected columns of a row as a JSON object.
+struct JsonRow<'a> {
+    columns: &'a [Column],
+    row: &'a Row,
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for column in self.columns {
+            map.serialize_entry(&column.output_name(), &Cell::new(column, self.row))?;
+        }
+        map.end()
+    }
+}
+
+enum DumpWriter<W: AsyncWrite + Unpin> {
+    Csv(Box<AsyncWriter<W>>),
+    Jsonl(W),
+}
+
+impl<W: AsyncWrite + Unpin> DumpWriter<W> {
+    async fn new(writer: W, format: DumpFormat, columns: &[Column]) -> Result<Self, Error> {
+        let writer = match format {
+            DumpFormat::Csv => {
+                let mut writer = AsyncWriter::from_writer(writer);
+                writer
+                    .write_record(
+                        columns
+                            .iter()
+                            .map(|column| column.output_name().into_owned()),
+                    )
+                    .await?;
+                Self::Csv(Box::new(writer))
+            }
+            DumpFormat::Jsonl => Self::Jsonl(writer),
+        };
+
+        Ok(writer)
+    }
+
+    async fn write_row(&mut self, columns: &[Column], row: &Row) -> Result<(), Error> {
+        match self {
+            Self::Csv(writer) => {
+                writer
+                    .write_record(
+                        columns
+                            .iter()
+                            .map(|column| Cell::new(column, row).to_string()),
+                    )
+                    .await?;
+            }
+            Self::Jsonl(writer) => {
+                let mut line = serde_json::to_vec(&JsonRow { columns, row })?;
+                line.push(b'\n');
+                writer.write_all(&line).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Self::Csv(writer) => writer.flush().await?,
+            Self::Jsonl(writer) => writer.flush().await?,
+        }
+        Ok(())
+    }
+}
+
+/// Dumps the selected columns of the Gaia records at `path` to `output`, or
+/// to the standard output.
+pub async fn dump(
+    output: Option<impl AsRef<Path>>,
+    path: impl AsRef<Path>,
+    options: DumpOptions,
+) -> Result<(), Error> {
+    let output: Option<&Path> = output.as_ref().map(AsRef::as_ref);
+    let format = options
+        .format
+        .unwrap_or_else(|| output.map(DumpFormat::from_path).unwrap_or(DumpFormat::Csv));
+
+    let columns = select_columns(&options.columns)?;
+    let filter = options
+        .filter
+        .as_ref()
+        .map(|filter| filter.bind(Column::find))
+        .transpose()?;
+    let filter_columns = filter.as_ref().map(Filter::fields).unwrap_or_default();
+
+    let writer: Box<dyn AsyncWrite + Unpin + Send> = match output {
+        Some(output) => Box::new(File::create(output).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let mut writer = DumpWriter::new(BufWriter::new(writer), format, &columns).await?;
+
+    let data = Data::open(path).await?;
+    let mut records = data.records();
+    let (_, num_partitions) = records.progress();
+    let progress_bar = progress_bar(num_partitions as _);
+    let mut num_dumped = 0;
+
+    while options.limit.is_none_or(|limit| num_dumped < limit) {
+        let Some(record) = records.read_record().await?
+        else {
+            break;
+        };
+
+        if options
+            .region
+            .is_some_and(|region| !in_region(&region, &record))
+        {
+            continue;
+        }
+
+        let row = Row::new(
+            &record,
+            columns.iter().chain(filter_columns.iter().copied()),
+        )?;
+        if filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(|column| row.get(column).into()))
+        {
+            writer.write_row(&columns, &row).await?;
+            num_dumped += 1;
+        }
+
+        let (progress, _) = records.progress();
+        progress_bar.set_position(progress as _);
+    }
+
+    writer.flush().await?;
+    progress_bar.finish_and_clear();
+    tracing::info!(num_dumped, "dumped records");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn writes_cells_with_the_precision_of_their_column() {
+        let columns = ["source_id", "designation", "ra", "ra_error", "dec"]
+            .map(|name| Column::find(name).unwrap());
+        let row = Row::from_json(
+            json!({ "source_id": 7, "designation": "Gaia DR3 7", "ra": 0.1, "ra_error": 0.1f32 }),
+            Value::Null,
+        );
+
+        let cells = columns
+            .iter()
+            .map(|column| Cell::new(column, &row).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(cells, ["7", "Gaia DR3 7", "0.1", "0.1", ""]);
+
+        let json = serde_json::to_string(&JsonRow {
+            columns: &columns,
+            row: &row,
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"source_id":7,"designation":"Gaia DR3 7","ra":0.1,"ra_error":0.1,"dec":null}"#
+        );
+    }
+}
@@ -0,0 +1,415 @@
+//! FITS binary tables, as read by TOPCAT, Aladin or astropy.
+//!
+//! The file has an empty primary HDU with the provenance in `HISTORY` cards,
+//! followed by a `BINTABLE` extension with the selected columns. Units come
+//! from the doc comments of the model, UCDs and descriptions are written in
+//! the `TUCDn` and `TCOMMn` keywords understood by TOPCAT.
+//!
+//! Missing floats are NaN, missing integers have the value of `TNULLn`.
+//! Unsigned integers are stored with an offset of 2^63, so a missing value
+//! reads as 0, which isn't a valid source id.
+//!
+//! Strings are variable-length arrays, since their lengths aren't known until
+//! all rows are written. They are collected in a temporary heap, which is
+//! copied after the rows, and the header of the table is written again once
+//! the number of rows and the size of the heap are known.
+
+use std::{
+    io::SeekFrom,
+    path::Path,
+};
+
+use tokio::{
+    fs::File,
+    io::{
+        AsyncSeekExt,
+        AsyncWriteExt,
+        BufWriter,
+    },
+};
+
+use super::{
+    format::Provenance,
+    tabular::integer,
+};
+use crate::{
+    gaia::{
+        Column,
+        ColumnType,
+        Row,
+    },
+    Error,
+};
+
+const BLOCK_SIZE: usize = 2880;
+
+const CARD_SIZE: usize = 80;
+
+/// Maximum length of a string value in a card, with its quotes.
+const MAX_STRING_SIZE: usize = 70;
+
+/// Header cards of an HDU.
+#[derive(Default)]
+struct Header {
+    bytes: Vec<u8>,
+}
+
+impl Header {
+    fn card(&mut self, card: &str) {
+        let mut card = card
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c as u8
+                }
+                else {
+                    b'?'
+                }
+            })
+            .take(CARD_SIZE)
+            .collect::<Vec<_>>();
+        card.resize(CARD_SIZE, b' ');
+        self.bytes.extend(card);
+    }
+
+    fn value(&mut self, key: &str, value: impl ToString) {
+        self.card(&format!("{key:<8}= {:>20}", value.to_string()));
+    }
+
+    fn logical(&mut self, key: &str, value: bool) {
+        self.value(key, if value { "T" } else { "F" });
+    }
+
+    /// Long strings are truncated.
+    fn string(&mut self, key: &str, value: &str) {
+        let mut quoted = "'".to_owned();
+        for c in value.chars() {
+            let escaped = if c == '\'' {
+                "''".to_owned()
+            }
+            else {
+                c.to_string()
+            };
+            if quoted.len() + escaped.len() + 1 > MAX_STRING_SIZE {
+                break;
+            }
+            quoted.push_str(&escaped);
+        }
+        // fixed-format strings are at least 8 characters long
+        while quoted.len() < 9 {
+            quoted.push(' ');
+        }
+        quoted.push('\'');
+        self.card(&format!("{key:<8}= {quoted}"));
+    }
+
+    /// Commentary cards, split over as many cards as needed.
+    fn text(&mut self, key: &str, text: &str) {
+        let chars = text.chars().collect::<Vec<_>>();
+        for line in chars.chunks(CARD_SIZE - 8) {
+            self.card(&format!("{key:<8}{}", line.iter().collect::<String>()));
+        }
+    }
+
+    /// Adds the `END` card and pads the header to full blocks.
+    fn finish(mut self) -> Vec<u8> {
+        self.card("END");
+        self.bytes
+            .resize(self.bytes.len().next_multiple_of(BLOCK_SIZE), b' ');
+        self.bytes
+    }
+}
+
+fn primary_header(provenance: &Provenance) -> Vec<u8> {
+    let mut header = Header::default();
+    header.logical("SIMPLE", true);
+    header.value("BITPIX", 8);
+    header.value("NAXIS", 0);
+    header.logical("EXTEND", true);
+    header.string("ORIGIN", &format!("via-tool {}", provenance.tool_version));
+    header.text("HISTORY", &format!("Dataset: {}", provenance.dataset));
+    header.text("HISTORY", &format!("Path: {}", provenance.path));
+    for filter in &provenance.filters {
+        header.text("HISTORY", &format!("Filter: {filter}"));
+    }
+    header.text("HISTORY", &format!("Created: {}", provenance.created));
+    header.finish()
+}
+
+/// Size of a column in a row, in bytes.
+fn column_size(column_type: ColumnType) -> u64 {
+    match column_type {
+        ColumnType::Bool => 1,
+        ColumnType::I8 | ColumnType::I16 => 2,
+        ColumnType::I32 | ColumnType::F32 => 4,
+        ColumnType::I64 | ColumnType::U64 | ColumnType::F64 => 8,
+        // descriptor with the length and the offset in the heap
+        ColumnType::String => 16,
+    }
+}
+
+fn padding(size: u64) -> usize {
+    (size.next_multiple_of(BLOCK_SIZE as u64) - size) as usize
+}
+
+pub struct FitsWriter {
+    writer: BufWriter<File>,
+    heap: BufWriter<File>,
+    columns: Vec<Column>,
+    /// Offset of the header of the table.
+    header_offset: u64,
+    num_rows: u64,
+    heap_size: u64,
+    /// Length of the longest string of every column.
+    max_lengths: Vec<u64>,
+    row: Vec<u8>,
+}
+
+impl FitsWriter {
+    pub async fn create(
+        path: &Path,
+        columns: Vec<Column>,
+        provenance: &Provenance,
+    ) -> Result<Self, Error> {
+        let mut writer = BufWriter::new(File::create(path).await?);
+        let primary_header = primary_header(provenance);
+        writer.write_all(&primary_header).await?;
+
+        let mut fits = Self {
+            writer,
+            heap: BufWriter::new(File::from_std(tempfile::tempfile()?)),
+            max_lengths: vec![0; columns.len()],
+            columns,
+            header_offset: primary_header.len() as u64,
+            num_rows: 0,
+            heap_size: 0,
+            row: vec![],
+        };
+        // written again by `finish`
+        let table_header = fits.table_header();
+        fits.writer.write_all(&table_header).await?;
+
+        Ok(fits)
+    }
+
+    fn row_size(&self) -> u64 {
+        self.columns
+            .iter()
+            .map(|column| column_size(column.column_type))
+            .sum()
+    }
+
+    fn table_header(&self) -> Vec<u8> {
+        let mut header = Header::default();
+        header.string("XTENSION", "BINTABLE");
+        header.value("BITPIX", 8);
+        header.value("NAXIS", 2);
+        header.value("NAXIS1", self.row_size());
+        header.value("NAXIS2", self.num_rows);
+        header.value("PCOUNT", self.heap_size);
+        header.value("GCOUNT", 1);
+        header.value("TFIELDS", self.columns.len());
+
+        for (i, (column, max_length)) in self.columns.iter().zip(&self.max_lengths).enumerate() {
+            let n = i + 1;
+            header.string(&format!("TTYPE{n}"), &column.output_name());
+
+            let (form, null) = match column.column_type {
+                ColumnType::Bool => ("L".to_owned(), None),
+                ColumnType::I8 | ColumnType::I16 => ("I".to_owned(), Some(i16::MIN as i64)),
+                ColumnType::I32 => ("J".to_owned(), Some(i32::MIN as i64)),
+                ColumnType::I64 | ColumnType::U64 => ("K".to_owned(), Some(i64::MIN)),
+                ColumnType::F32 => ("E".to_owned(), None),
+                ColumnType::F64 => ("D".to_owned(), None),
+                ColumnType::String => (format!("1QA({max_length})"), None),
+            };
+            header.string(&format!("TFORM{n}"), &form);
+            if let Some(null) = null {
+                header.value(&format!("TNULL{n}"), null);
+            }
+            if column.column_type == ColumnType::U64 {
+                header.value(&format!("TZERO{n}"), 1u64 << 63);
+            }
+
+            let doc = column.doc();
+            if let Some(unit) = doc.and_then(|doc| doc.unit) {
+                header.string(&format!("TUNIT{n}"), unit);
+            }
+            if let Some(ucd) = column.ucd() {
+                header.string(&format!("TUCD{n}"), &ucd);
+            }
+            if let Some(doc) = doc.filter(|doc| !doc.description.is_empty()) {
+                header.string(&format!("TCOMM{n}"), &doc.description);
+            }
+        }
+
+        header.finish()
+    }
+
+    pub async fn write_row(&mut self, row: &Row) -> Result<(), Error> {
+        self.row.clear();
+
+        for (column, max_length) in self.columns.iter().zip(&mut self.max_lengths) {
+            let value = row.get(column);
+            match column.column_type {
+                ColumnType::Bool => {
+                    self.row.push(match value.as_bool() {
+                        Some(true) => b'T',
+                        Some(false) => b'F',
+                        None => 0,
+                    });
+                }
+                ColumnType::I8 | ColumnType::I16 => {
+                    let value: i16 = integer(value).unwrap_or(i16::MIN);
+                    self.row.extend(value.to_be_bytes());
+                }
+                ColumnType::I32 => {
+                    let value: i32 = integer(value).unwrap_or(i32::MIN);
+                    self.row.extend(value.to_be_bytes());
+                }
+                ColumnType::I64 => {
+                    let value = value.as_i64().unwrap_or(i64::MIN);
+                    self.row.extend(value.to_be_bytes());
+                }
+                ColumnType::U64 => {
+                    let value = value
+                        .as_u64()
+                        .map_or(i64::MIN, |value| (value ^ (1 << 63)) as i64);
+                    self.row.extend(value.to_be_bytes());
+                }
+                ColumnType::F32 => {
+                    let value = value.as_f64().map_or(f32::NAN, |value| value as f32);
+                    self.row.extend(value.to_be_bytes());
+                }
+                ColumnType::F64 => {
+                    let value = value.as_f64().unwrap_or(f64::NAN);
+                    self.row.extend(value.to_be_bytes());
+                }
+                ColumnType::String => {
+                    let value = value.as_str().map(str::as_bytes).unwrap_or_default();
+                    let length = value.len() as u64;
+                    self.row.extend(length.to_be_bytes());
+                    self.row.extend(self.heap_size.to_be_bytes());
+                    self.heap.write_all(value).await?;
+                    self.heap_size += length;
+                    *max_length = (*max_length).max(length);
+                }
+            }
+        }
+
+        self.writer.write_all(&self.row).await?;
+        self.num_rows += 1;
+
+        Ok(())
+    }
+
+    /// Copies the heap after the rows and completes the header.
+    pub async fn finish(mut self) -> Result<(), Error> {
+        let table_header = self.table_header();
+        let data_size = self.num_rows * self.row_size() + self.heap_size;
+
+        self.heap.flush().await?;
+        let mut heap = self.heap.into_inner();
+        heap.rewind().await?;
+        tokio::io::copy(&mut heap, &mut self.writer).await?;
+        self.writer.write_all(&vec![0; padding(data_size)]).await?;
+
+        self.writer
+            .seek(SeekFrom::Start(self.header_offset))
+            .await?;
+        self.writer.write_all(&table_header).await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{
+        json,
+        Value,
+    };
+
+    use super::*;
+
+    fn card(header: &[u8], key: &str) -> Option<String> {
+        header
+            .chunks(CARD_SIZE)
+            .map(|card| String::from_utf8_lossy(card).into_owned())
+            .find(|card| card[..8].trim_end() == key)
+    }
+
+    #[test]
+    fn formats_cards() {
+        let mut header = Header::default();
+        header.value("NAXIS2", 42);
+        header.string("TCOMM1", "Gaia's designation");
+        header.string("TFORM1", "D");
+        header.text("HISTORY", &"x".repeat(100));
+        let header = header.finish();
+
+        assert_eq!(header.len(), BLOCK_SIZE);
+        assert_eq!(
+            card(&header, "NAXIS2").unwrap().trim_end(),
+            "NAXIS2  =                   42"
+        );
+        assert_eq!(
+            card(&header, "TCOMM1").unwrap().trim_end(),
+            "TCOMM1  = 'Gaia''s designation'"
+        );
+        assert_eq!(
+            card(&header, "TFORM1").unwrap().trim_end(),
+            "TFORM1  = 'D       '"
+        );
+        assert_eq!(
+            header
+                .chunks(CARD_SIZE)
+                .filter(|card| card.starts_with(b"HISTORY"))
+                .count(),
+            2
+        );
+        assert!(card(&header, "END").is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writes_rows_and_heap() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let columns = ["source_id", "designation", "ra", "teff_gspphot"]
+            .map(|name| Column::find(name).unwrap())
+            .to_vec();
+
+        let mut writer = FitsWriter::create(&path, columns.clone(), &Provenance::default())
+            .await
+            .unwrap();
+        for (source_id, designation) in [(1, "Gaia DR3 1"), (22, "Gaia DR3 22")] {
+            let row = Row::from_json(
+                json!({ "source_id": source_id, "designation": designation, "ra": 1.5 }),
+                Value::Null,
+            );
+            writer.write_row(&row).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let fits = std::fs::read(&path).unwrap();
+        assert_eq!(fits.len() % BLOCK_SIZE, 0);
+        let table = &fits[BLOCK_SIZE..];
+        assert_eq!(card(table, "NAXIS1").unwrap()[10..30].trim(), "36");
+        assert_eq!(card(table, "NAXIS2").unwrap()[10..30].trim(), "2");
+        assert_eq!(card(table, "PCOUNT").unwrap()[10..30].trim(), "21");
+        assert!(card(table, "TFORM2").unwrap().contains("'1QA(11) '"));
+        assert!(card(table, "TUNIT3").unwrap().contains("'deg     '"));
+        assert!(card(table, "TUCD3")
+            .unwrap()
+            .contains("'pos.eq.ra;meta.main'"));
+
+        let data = &table[BLOCK_SIZE..];
+        assert_eq!(&data[..8], &((1u64 ^ (1 << 63)) as i64).to_be_bytes());
+        assert_eq!(&data[8..16], &10u64.to_be_bytes());
+        assert_eq!(&data[16..24], &0u64.to_be_bytes());
+        assert_eq!(&data[24..32], &1.5f64.to_be_bytes());
+        assert!(f32::from_be_bytes(data[32..36].try_into().unwrap()).is_nan());
+        assert_eq!(&data[36 + 16..36 + 24], &10u64.to_be_bytes());
+        assert_eq!(&data[72..93], b"Gaia DR3 1Gaia DR3 22");
+    }
+}
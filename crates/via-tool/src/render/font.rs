@@ -0,0 +1,118 @@
+//! A tiny bitmap font for labels, so that no font files are needed.
+
+use image::{
+    Rgb,
+    RgbImage,
+};
+
+/// Width of a glyph in glyph pixels.
+const GLYPH_WIDTH: u32 = 3;
+
+/// Height of a glyph in glyph pixels.
+pub const GLYPH_HEIGHT: u32 = 5;
+
+/// Rows of the 3x5 glyph for `c`, with the leftmost pixel in bit 2.
+/// Lowercase letters are drawn as uppercase, except for `e`, which is used in
+/// exponents.
+fn glyph(c: char) -> Option<[u8; 5]> {
+    let rows = match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        'e' => [0b000, 0b111, 0b111, 0b100, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        c if c.is_ascii_lowercase() => return glyph(c.to_ascii_uppercase()),
+        _ => return None,
+    };
+    Some(rows)
+}
+
+/// Width of `text` in pixels, for glyph pixels of size `scale`.
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    ((GLYPH_WIDTH + 1) * text.chars().count() as u32).saturating_sub(1) * scale
+}
+
+/// Draws `text` with its top-left corner at `(x, y)`, with every glyph pixel
+/// drawn as a square of size `scale`. Characters without a glyph are left
+/// out.
+pub fn draw_text(image: &mut RgbImage, text: &str, [x, y]: [u32; 2], scale: u32, color: Rgb<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let Some(rows) = glyph(c)
+        else {
+            continue;
+        };
+        let glyph_x = x + (GLYPH_WIDTH + 1) * i as u32 * scale;
+
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = glyph_x + column * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+                        if px < image.width() && py < image.height() {
+                            image.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Formats a value with few digits, for use as a label.
+pub fn format_number(value: f64) -> String {
+    if value == 0.0 {
+        "0".to_owned()
+    }
+    else if value.abs() >= 1e5 || value.abs() < 1e-2 {
+        format!("{value:.1e}")
+    }
+    else {
+        let text = format!("{value:.2}");
+        text.trim_end_matches('0').trim_end_matches('.').to_owned()
+    }
+}
@@ -0,0 +1,1094 @@
+//! The file format of exported records.
+//!
+//! A file starts with a fixed prelude:
+//!
+//! | bytes | content                                            |
+//! |-------|----------------------------------------------------|
+//! | 8     | magic `VIASTARS`                                   |
+//! | 2     | format version                                     |
+//! | 2     | byte order mark `0xFEFF`                           |
+//! | 8     | number of records                                  |
+//! | 4     | length of the header                               |
+//!
+//! followed by the [`Header`] as JSON and the records. All numbers are
+//! big-endian. The header lists the fields of a record in the order they are
+//! stored, so files with other fields than the current [`Record`] can still
+//! be read, as long as they have the required ones.
+
+use std::{
+    collections::VecDeque,
+    io::SeekFrom,
+    ops::Range,
+    path::Path,
+};
+
+use color_eyre::eyre::{
+    bail,
+    ensure,
+    eyre,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    fs::File,
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncSeekExt,
+        AsyncWrite,
+        AsyncWriteExt,
+        BufReader,
+        BufWriter,
+    },
+};
+
+use super::{
+    spatial::{
+        Node,
+        Region,
+        SpatialIndex,
+    },
+    Record,
+};
+use crate::{
+    filter,
+    gaia::HealPixRange,
+    Error,
+};
+
+const MAGIC: [u8; 8] = *b"VIASTARS";
+
+/// Version of the container layout. The fields of a record are described by
+/// the header and don't need a new version.
+const VERSION: u16 = 1;
+
+/// Reads as `0xFFFE` if the byte order is swapped.
+const BYTE_ORDER_MARK: u16 = 0xFEFF;
+
+/// Offset of the number of records, which is written last.
+const NUM_RECORDS_OFFSET: u64 = 12;
+
+/// Size of everything before the header.
+const PRELUDE_SIZE: u64 = 24;
+
+/// Largest header that is accepted, to not allocate arbitrary amounts of
+/// memory for a corrupted file.
+const MAX_HEADER_LENGTH: u32 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    U8,
+    U32,
+    U64,
+    F32,
+    F64,
+}
+
+impl FieldType {
+    /// Size in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U32 | Self::F32 => 4,
+            Self::U64 | Self::F64 => 8,
+        }
+    }
+
+    async fn read(&self, mut reader: impl AsyncRead + Unpin) -> Result<Value, Error> {
+        let value = match self {
+            Self::U8 => Value::Unsigned(reader.read_u8().await?.into()),
+            Self::U32 => Value::Unsigned(reader.read_u32().await?.into()),
+            Self::U64 => Value::Unsigned(reader.read_u64().await?),
+            Self::F32 => Value::Float(reader.read_f32().await?.into()),
+            Self::F64 => Value::Float(reader.read_f64().await?),
+        };
+        Ok(value)
+    }
+}
+
+/// A field value of any type.
+#[derive(Clone, Copy, Debug)]
+enum Value {
+    Unsigned(u64),
+    Float(f64),
+}
+
+impl Value {
+    fn as_f64(self) -> f64 {
+        match self {
+            Self::Unsigned(value) => value as f64,
+            Self::Float(value) => value,
+        }
+    }
+
+    fn as_u64(self) -> u64 {
+        match self {
+            Self::Unsigned(value) => value,
+            Self::Float(value) => value as u64,
+        }
+    }
+}
+
+/// Description of a field of the stored records.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// Unit of the value, or `None` if it has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A field of [`Record`].
+struct RecordField {
+    name: &'static str,
+    field_type: FieldType,
+    unit: Option<&'static str>,
+    /// Whether files without this field are rejected.
+    required: bool,
+    description: &'static str,
+}
+
+/// Fields of [`Record`] in the order they are written by [`Record::write`].
+const RECORD_FIELDS: [RecordField; 16] = [
+    RecordField {
+        name: "source_id",
+        field_type: FieldType::U64,
+        unit: None,
+        required: true,
+        description: "Gaia DR3 source identifier",
+    },
+    RecordField {
+        name: "healpix_start",
+        field_type: FieldType::U32,
+        unit: None,
+        required: true,
+        description: "first HEALPix level 8 cell of the partition",
+    },
+    RecordField {
+        name: "healpix_end",
+        field_type: FieldType::U32,
+        unit: None,
+        required: true,
+        description: "last HEALPix level 8 cell of the partition",
+    },
+    RecordField {
+        name: "parallax",
+        field_type: FieldType::F64,
+        unit: Some("mas"),
+        required: true,
+        description: "parallax",
+    },
+    RecordField {
+        name: "parallax_error",
+        field_type: FieldType::F32,
+        unit: Some("mas"),
+        required: false,
+        description: "standard error of the parallax",
+    },
+    RecordField {
+        name: "longitude",
+        field_type: FieldType::F64,
+        unit: Some("deg"),
+        required: true,
+        description: "galactic longitude",
+    },
+    RecordField {
+        name: "latitude",
+        field_type: FieldType::F64,
+        unit: Some("deg"),
+        required: true,
+        description: "galactic latitude",
+    },
+    RecordField {
+        name: "t_eff",
+        field_type: FieldType::F32,
+        unit: Some("K"),
+        required: true,
+        description: "effective temperature",
+    },
+    RecordField {
+        name: "apparent_magnitude",
+        field_type: FieldType::F32,
+        unit: Some("mag"),
+        required: true,
+        description: "mean G band magnitude",
+    },
+    RecordField {
+        name: "pm_longitude",
+        field_type: FieldType::F32,
+        unit: Some("mas/yr"),
+        required: false,
+        description: "proper motion in galactic longitude times cos(latitude)",
+    },
+    RecordField {
+        name: "pm_latitude",
+        field_type: FieldType::F32,
+        unit: Some("mas/yr"),
+        required: false,
+        description: "proper motion in galactic latitude",
+    },
+    RecordField {
+        name: "bp_rp",
+        field_type: FieldType::F32,
+        unit: Some("mag"),
+        required: false,
+        description: "BP - RP colour",
+    },
+    RecordField {
+        name: "metallicity",
+        field_type: FieldType::F32,
+        unit: Some("dex"),
+        required: false,
+        description: "[M/H]",
+    },
+    RecordField {
+        name: "extinction",
+        field_type: FieldType::F32,
+        unit: Some("mag"),
+        required: false,
+        description: "extinction in the G band",
+    },
+    RecordField {
+        name: "radial_velocity",
+        field_type: FieldType::F32,
+        unit: Some("km/s"),
+        required: false,
+        description: "radial velocity",
+    },
+    RecordField {
+        name: "non_single_star",
+        field_type: FieldType::U8,
+        unit: None,
+        required: false,
+        description: "non-single star flag, 0 for single stars",
+    },
+];
+
+/// Fields that aren't stored, but computed from the others for filters:
+/// `parallax_over_error`, and `distance` in parsec.
+const COMPUTED_FIELDS: [&str; 2] = ["parallax_over_error", "distance"];
+
+/// Where the records come from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Name of the dataset.
+    pub dataset: String,
+    /// Path the dataset was read from.
+    pub path: String,
+    /// Human readable description of every filter applied to the dataset.
+    pub filters: Vec<String>,
+    /// Time of the export, in RFC 3339.
+    pub created: String,
+    /// Version of via-tool that wrote the file.
+    pub tool_version: String,
+}
+
+/// Self-description of a file, stored as JSON after the prelude.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub fields: Vec<Field>,
+    pub provenance: Provenance,
+    /// Set if the records are sorted spatially, in which case the octree
+    /// index follows the records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spatial_index: Option<SpatialIndex>,
+}
+
+impl Header {
+    /// Header for the fields of the current [`Record`].
+    pub fn new(provenance: Provenance) -> Self {
+        let fields = RECORD_FIELDS
+            .iter()
+            .map(|field| {
+                Field {
+                    name: field.name.to_owned(),
+                    field_type: field.field_type,
+                    unit: field.unit.map(ToOwned::to_owned),
+                    description: field.description.to_owned(),
+                }
+            })
+            .collect();
+
+        Self {
+            fields,
+            provenance,
+            spatial_index: None,
+        }
+    }
+
+    /// Size of a record in bytes.
+    pub fn record_size(&self) -> usize {
+        self.fields
+            .iter()
+            .map(|field| field.field_type.size())
+            .sum()
+    }
+
+    /// Whether the records are stored exactly as written by
+    /// [`Record::write`].
+    pub fn is_native(&self) -> bool {
+        self.fields.len() == RECORD_FIELDS.len()
+            && self
+                .fields
+                .iter()
+                .zip(&RECORD_FIELDS)
+                .all(|(field, expected)| {
+                    field.name == expected.name
+                        && field.field_type == expected.field_type
+                        && field.unit.as_deref() == expected.unit
+                })
+    }
+
+    /// Checks that the records can be read into a [`Record`]: all required
+    /// fields are present, and known fields have the expected units.
+    pub fn check_compatible(&self) -> Result<(), Error> {
+        for expected in &RECORD_FIELDS {
+            let name = expected.name;
+            match self.fields.iter().find(|field| field.name == name) {
+                Some(field) => {
+                    ensure!(
+                        field.unit.as_deref() == expected.unit,
+                        "the field {name} has the unit {:?} instead of {:?}",
+                        field.unit,
+                        expected.unit
+                    );
+                }
+                None => ensure!(!expected.required, "the required field {name} is missing"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the prelude and header.
+async fn write_header(
+    mut writer: impl AsyncWrite + Unpin,
+    header: &Header,
+    num_records: u64,
+) -> Result<(), Error> {
+    let json = serde_json::to_vec(header)?;
+
+    writer.write_all(&MAGIC).await?;
+    writer.write_u16(VERSION).await?;
+    writer.write_u16(BYTE_ORDER_MARK).await?;
+    writer.write_u64(num_records).await?;
+    writer.write_u32(json.len().try_into()?).await?;
+    writer.write_all(&json).await?;
+
+    Ok(())
+}
+
+/// Parses the prelude, and returns the number of records and the length of
+/// the header.
+fn parse_prelude(prelude: &[u8; PRELUDE_SIZE as usize]) -> Result<(u64, u32), Error> {
+    let bytes = |range: std::ops::Range<usize>| &prelude[range];
+
+    ensure!(
+        bytes(0..8) == MAGIC,
+        "not a star export, or written by an older version; export the data again"
+    );
+
+    let version = u16::from_be_bytes(bytes(8..10).try_into()?);
+    ensure!(
+        version <= VERSION,
+        "the file has version {version}, but only versions up to {VERSION} are supported"
+    );
+
+    match u16::from_be_bytes(bytes(10..12).try_into()?) {
+        BYTE_ORDER_MARK => {}
+        0xFFFE => bail!("the file is little-endian, which isn't supported"),
+        mark => bail!("invalid byte order mark: {mark:#06x}"),
+    }
+
+    let num_records = u64::from_be_bytes(bytes(12..20).try_into()?);
+
+    let header_length = u32::from_be_bytes(bytes(20..24).try_into()?);
+    ensure!(
+        header_length <= MAX_HEADER_LENGTH,
+        "the header is too large: {header_length} bytes"
+    );
+
+    Ok((num_records, header_length))
+}
+
+/// Reads the prelude and header, and returns the header with the number of
+/// records and the offset of the first record.
+async fn read_header(mut reader: impl AsyncRead + Unpin) -> Result<(Header, u64, u64), Error> {
+    let mut prelude = [0; PRELUDE_SIZE as usize];
+    reader.read_exact(&mut prelude).await?;
+    let (num_records, header_length) = parse_prelude(&prelude)?;
+
+    let mut json = vec![0; header_length as usize];
+    reader.read_exact(&mut json).await?;
+    let header = serde_json::from_slice(&json)?;
+
+    Ok((header, num_records, PRELUDE_SIZE + header_length as u64))
+}
+
+/// Like [`read_header`], but parses the start of a file that is already in
+/// memory.
+pub fn parse_header(data: &[u8]) -> Result<(Header, u64, u64), Error> {
+    let prelude = data
+        .get(..PRELUDE_SIZE as usize)
+        .ok_or_else(|| eyre!("the file is too short"))?;
+    let (num_records, header_length) = parse_prelude(prelude.try_into()?)?;
+
+    let data_offset = PRELUDE_SIZE + header_length as u64;
+    let json = data
+        .get(PRELUDE_SIZE as usize..data_offset as usize)
+        .ok_or_else(|| eyre!("the file is too short"))?;
+    let header = serde_json::from_slice(json)?;
+
+    Ok((header, num_records, data_offset))
+}
+
+/// Offset of the end of the records, or `None` if the number of records in a
+/// corrupt header is too large to be addressed.
+pub fn records_end(data_offset: u64, num_records: u64, header: &Header) -> Option<u64> {
+    num_records
+        .checked_mul(header.record_size() as u64)?
+        .checked_add(data_offset)
+}
+
+impl Record {
+    pub async fn write(&self, mut writer: impl AsyncWrite + Unpin) -> Result<(), Error> {
+        writer.write_u64(self.source_id).await?;
+        writer.write_u32(self.healpix_range.start).await?;
+        writer.write_u32(self.healpix_range.end).await?;
+        writer.write_f64(self.parallax).await?;
+        writer.write_f32(self.parallax_error).await?;
+        writer.write_f64(self.longitude).await?;
+        writer.write_f64(self.latitude).await?;
+        writer.write_f32(self.t_eff).await?;
+        writer.write_f32(self.apparent_magnitude).await?;
+        writer.write_f32(self.pm_longitude).await?;
+        writer.write_f32(self.pm_latitude).await?;
+        writer.write_f32(self.bp_rp).await?;
+        writer.write_f32(self.metallicity).await?;
+        writer.write_f32(self.extinction).await?;
+        writer.write_f32(self.radial_velocity).await?;
+        writer.write_u8(self.non_single_star).await?;
+        Ok(())
+    }
+
+    pub async fn read(mut reader: impl AsyncRead + Unpin) -> Result<Self, Error> {
+        let source_id = reader.read_u64().await?;
+        let healpix_start = reader.read_u32().await?;
+        let healpix_end = reader.read_u32().await?;
+        let parallax = reader.read_f64().await?;
+        let parallax_error = reader.read_f32().await?;
+        let longitude = reader.read_f64().await?;
+        let latitude = reader.read_f64().await?;
+        let t_eff = reader.read_f32().await?;
+        let apparent_magnitude = reader.read_f32().await?;
+        let pm_longitude = reader.read_f32().await?;
+        let pm_latitude = reader.read_f32().await?;
+        let bp_rp = reader.read_f32().await?;
+        let metallicity = reader.read_f32().await?;
+        let extinction = reader.read_f32().await?;
+        let radial_velocity = reader.read_f32().await?;
+        let non_single_star = reader.read_u8().await?;
+        Ok(Self {
+            source_id,
+            healpix_range: HealPixRange {
+                start: healpix_start,
+                end: healpix_end,
+            },
+            parallax,
+            parallax_error,
+            longitude,
+            latitude,
+            t_eff,
+            apparent_magnitude,
+            pm_longitude,
+            pm_latitude,
+            bp_rp,
+            metallicity,
+            extinction,
+            radial_velocity,
+            non_single_star,
+        })
+    }
+
+    /// Reads a record stored with other fields than [`Record::write`] writes.
+    /// Unknown fields are skipped, and missing ones set to NaN or 0.
+    async fn read_fields(
+        mut reader: impl AsyncRead + Unpin,
+        fields: &[Field],
+    ) -> Result<Self, Error> {
+        let mut record = Self {
+            source_id: 0,
+            healpix_range: HealPixRange { start: 0, end: 0 },
+            parallax: f64::NAN,
+            parallax_error: f32::NAN,
+            longitude: f64::NAN,
+            latitude: f64::NAN,
+            t_eff: f32::NAN,
+            apparent_magnitude: f32::NAN,
+            pm_longitude: f32::NAN,
+            pm_latitude: f32::NAN,
+            bp_rp: f32::NAN,
+            metallicity: f32::NAN,
+            extinction: f32::NAN,
+            radial_velocity: f32::NAN,
+            non_single_star: 0,
+        };
+
+        for field in fields {
+            let value = field.field_type.read(&mut reader).await?;
+            match field.name.as_str() {
+                "source_id" => record.source_id = value.as_u64(),
+                "healpix_start" => record.healpix_range.start = value.as_u64() as u32,
+                "healpix_end" => record.healpix_range.end = value.as_u64() as u32,
+                "parallax" => record.parallax = value.as_f64(),
+                "parallax_error" => record.parallax_error = value.as_f64() as f32,
+                "longitude" => record.longitude = value.as_f64(),
+                "latitude" => record.latitude = value.as_f64(),
+                "t_eff" => record.t_eff = value.as_f64() as f32,
+                "apparent_magnitude" => record.apparent_magnitude = value.as_f64() as f32,
+                "pm_longitude" => record.pm_longitude = value.as_f64() as f32,
+                "pm_latitude" => record.pm_latitude = value.as_f64() as f32,
+                "bp_rp" => record.bp_rp = value.as_f64() as f32,
+                "metallicity" => record.metallicity = value.as_f64() as f32,
+                "extinction" => record.extinction = value.as_f64() as f32,
+                "radial_velocity" => record.radial_velocity = value.as_f64() as f32,
+                "non_single_star" => record.non_single_star = value.as_u64() as u8,
+                _ => {}
+            }
+        }
+
+        Ok(record)
+    }
+
+    /// Resolves the name of a field for [`Record::field`], like filters do.
+    /// Besides the stored fields, the fields computed by [`Record::field`]
+    /// can be used.
+    pub fn field_name(name: &str) -> Result<&'static str, Error> {
+        RECORD_FIELDS
+            .iter()
+            .map(|field| field.name)
+            .chain(COMPUTED_FIELDS)
+            .find(|field| *field == name)
+            .ok_or_else(|| {
+                let names = RECORD_FIELDS
+                    .iter()
+                    .map(|field| field.name)
+                    .chain(COMPUTED_FIELDS)
+                    .collect::<Vec<_>>();
+                eyre!(
+                    "unknown field {name}, exports only have the fields {}",
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// The value of a field by its name. NaN is a missing value.
+    pub fn field(&self, name: &str) -> filter::Value<'static> {
+        let value = match name {
+            "source_id" => self.source_id as f64,
+            "healpix_start" => self.healpix_range.start as f64,
+            "healpix_end" => self.healpix_range.end as f64,
+            "parallax" => self.parallax,
+            "parallax_error" => self.parallax_error as f64,
+            "longitude" => self.longitude,
+            "latitude" => self.latitude,
+            "t_eff" => self.t_eff as f64,
+            "apparent_magnitude" => self.apparent_magnitude as f64,
+            "pm_longitude" => self.pm_longitude as f64,
+            "pm_latitude" => self.pm_latitude as f64,
+            "bp_rp" => self.bp_rp as f64,
+            "metallicity" => self.metallicity as f64,
+            "extinction" => self.extinction as f64,
+            "radial_velocity" => self.radial_velocity as f64,
+            "non_single_star" => self.non_single_star as f64,
+            "parallax_over_error" => self.parallax / self.parallax_error as f64,
+            "distance" => 1000.0 * self.distance(),
+            _ => f64::NAN,
+        };
+        value.into()
+    }
+}
+
+pub struct RecordReader {
+    reader: BufReader<File>,
+    header: Header,
+    native: bool,
+    data_offset: u64,
+    /// Ranges of records that are still to be read.
+    ranges: VecDeque<Range<u64>>,
+    /// Index of the record the reader is at.
+    position: u64,
+    /// Only records within the region are returned.
+    region: Option<Region>,
+    num_records: u64,
+    num_read: u64,
+}
+
+impl RecordReader {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = File::open(path).await?;
+        let file_size = file.metadata().await?.len();
+        let mut reader = BufReader::new(file);
+
+        let (header, num_records, data_offset) = read_header(&mut reader)
+            .await
+            .map_err(|error| error.wrap_err(format!("can't read {}", path.display())))?;
+        header.check_compatible()?;
+        let Some(end) = records_end(data_offset, num_records, &header)
+        else {
+            bail!(
+                "{} is corrupt: it claims to have {num_records} records",
+                path.display()
+            );
+        };
+        ensure!(file_size >= end, "{} is truncated", path.display());
+        let native = header.is_native();
+        if !native {
+            tracing::info!("the file has different fields, which are converted while reading");
+        }
+
+        Ok(Self {
+            reader,
+            header,
+            native,
+            data_offset,
+            ranges: std::iter::once(0..num_records).collect(),
+            position: 0,
+            region: None,
+            num_records,
+            num_read: 0,
+        })
+    }
+
+    /// Opens the file to only read the records within the region. If the
+    /// file is spatially sorted, only the parts of it near the region are
+    /// read, otherwise all records are read and filtered.
+    pub async fn open_region(path: impl AsRef<Path>, region: Region) -> Result<Self, Error> {
+        let mut records = Self::open(path).await?;
+        records.region = Some(region);
+
+        let Some(index) = records.header.spatial_index
+        else {
+            tracing::warn!("the file has no spatial index, so all records are read");
+            return Ok(records);
+        };
+
+        let index_offset =
+            records.data_offset + records.num_records * records.header.record_size() as u64;
+        records.reader.seek(SeekFrom::Start(index_offset)).await?;
+        let num_nodes = records.reader.read_u64().await?;
+        let mut nodes = Vec::with_capacity(num_nodes.min(1 << 20) as usize);
+        for _ in 0..num_nodes {
+            nodes.push(Node::read(&mut records.reader).await?);
+        }
+
+        let ranges = index.ranges(&nodes, records.num_records, &region);
+        records.num_records = ranges.iter().map(|range| range.end - range.start).sum();
+        tracing::info!(
+            num_records = records.num_records,
+            num_ranges = ranges.len(),
+            "reading records near the region"
+        );
+        records.ranges = ranges.into();
+        // forces a seek to the first range.
+        records.position = u64::MAX;
+
+        Ok(records)
+    }
+
+    pub async fn read_record(&mut self) -> Result<Option<Record>, Error> {
+        loop {
+            let Some(range) = self.ranges.front_mut()
+            else {
+                return Ok(None);
+            };
+            if range.is_empty() {
+                self.ranges.pop_front();
+                continue;
+            }
+            let index = range.start;
+            range.start += 1;
+
+            if index != self.position {
+                let offset = self.data_offset + index * self.header.record_size() as u64;
+                self.reader.seek(SeekFrom::Start(offset)).await?;
+            }
+
+            let record = if self.native {
+                Record::read(&mut self.reader).await?
+            }
+            else {
+                Record::read_fields(&mut self.reader, &self.header.fields).await?
+            };
+            self.position = index + 1;
+            self.num_read += 1;
+
+            if self
+                .region
+                .as_ref()
+                .is_none_or(|region| region.contains_record(&record))
+            {
+                return Ok(Some(record));
+            }
+        }
+    }
+
+    /// Reads the record at an index, regardless of the region.
+    pub async fn read_record_at(&mut self, index: u64) -> Result<Record, Error> {
+        ensure!(index < self.num_records, "record {index} is out of bounds");
+
+        let offset = self.data_offset + index * self.header.record_size() as u64;
+        self.reader.seek(SeekFrom::Start(offset)).await?;
+        let record = if self.native {
+            Record::read(&mut self.reader).await?
+        }
+        else {
+            Record::read_fields(&mut self.reader, &self.header.fields).await?
+        };
+        self.position = index + 1;
+
+        Ok(record)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Number of records that are read, including those outside of the
+    /// region.
+    pub fn num_records(&self) -> u64 {
+        self.num_records
+    }
+
+    pub fn num_read(&self) -> u64 {
+        self.num_read
+    }
+}
+
+pub struct RecordWriter {
+    writer: BufWriter<File>,
+    num_records: u64,
+}
+
+impl RecordWriter {
+    pub async fn create(path: impl AsRef<Path>, header: &Header) -> Result<Self, Error> {
+        let file = File::create(path).await?;
+        let mut writer = BufWriter::new(file);
+        write_header(&mut writer, header, 0).await?;
+
+        Ok(Self {
+            writer,
+            num_records: 0,
+        })
+    }
+
+    pub async fn write_record(&mut self, record: &Record) -> Result<(), Error> {
+        record.write(&mut self.writer).await?;
+        self.num_records += 1;
+        Ok(())
+    }
+
+    /// Writes the octree index after the records. No records may be written
+    /// after this.
+    pub async fn write_index(&mut self, nodes: &[Node]) -> Result<(), Error> {
+        self.writer.write_u64(nodes.len() as u64).await?;
+        for node in nodes {
+            node.write(&mut self.writer).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the number of records into the prelude and flushes the file.
+    pub async fn finish(mut self) -> Result<(), Error> {
+        self.writer
+            .seek(SeekFrom::Start(NUM_RECORDS_OFFSET))
+            .await?;
+        self.writer.write_u64(self.num_records).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+
+    fn provenance() -> Provenance {
+        Provenance {
+            dataset: "test".to_owned(),
+            path: "/data".to_owned(),
+            filters: vec!["limit_per_file=1".to_owned()],
+            created: "2024-01-01T00:00:00Z".to_owned(),
+            tool_version: "0.1.0".to_owned(),
+        }
+    }
+
+    fn record(source_id: u64) -> Record {
+        Record {
+            source_id,
+            healpix_range: HealPixRange { start: 3, end: 7 },
+            parallax: 1.25,
+            parallax_error: 0.125,
+            longitude: 123.5,
+            latitude: -45.25,
+            t_eff: 5772.0,
+            apparent_magnitude: 12.5,
+            pm_longitude: -3.5,
+            pm_latitude: f32::NAN,
+            bp_rp: 0.8,
+            metallicity: -0.25,
+            extinction: f32::NAN,
+            radial_velocity: 12.0,
+            non_single_star: 2,
+        }
+    }
+
+    fn assert_same(a: &Record, b: &Record) {
+        // compare the bits, so that NaN equals NaN.
+        let bits = |record: &Record| {
+            [
+                record.source_id,
+                record.healpix_range.start.into(),
+                record.healpix_range.end.into(),
+                record.parallax.to_bits(),
+                record.parallax_error.to_bits().into(),
+                record.longitude.to_bits(),
+                record.latitude.to_bits(),
+                record.t_eff.to_bits().into(),
+                record.apparent_magnitude.to_bits().into(),
+                record.pm_longitude.to_bits().into(),
+                record.pm_latitude.to_bits().into(),
+                record.bp_rp.to_bits().into(),
+                record.metallicity.to_bits().into(),
+                record.extinction.to_bits().into(),
+                record.radial_velocity.to_bits().into(),
+                record.non_single_star.into(),
+            ]
+        };
+        assert_eq!(bits(a), bits(b));
+    }
+
+    /// Writes a file with the given header and raw record bytes.
+    async fn write_raw(header: &Header, num_records: u64, records: &[u8]) -> tempfile::TempPath {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut data = vec![];
+        write_header(&mut data, header, num_records).await.unwrap();
+        data.extend_from_slice(records);
+        tokio::fs::write(&path, data).await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn schema_matches_record_layout() {
+        let mut data = vec![];
+        record(1).write(&mut data).await.unwrap();
+        assert_eq!(data.len(), Header::new(provenance()).record_size());
+    }
+
+    #[tokio::test]
+    async fn header_round_trip() {
+        let header = Header::new(provenance());
+        let mut data = vec![];
+        write_header(&mut data, &header, 42).await.unwrap();
+
+        let (read, num_records, data_offset) = read_header(&data[..]).await.unwrap();
+        assert_eq!(read, header);
+        assert_eq!(num_records, 42);
+        assert_eq!(data_offset, data.len() as u64);
+
+        let (parsed, num_records, data_offset) = parse_header(&data).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(num_records, 42);
+        assert_eq!(data_offset, data.len() as u64);
+        assert!(read.is_native());
+    }
+
+    #[tokio::test]
+    async fn records_round_trip() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let records = (0..100).map(record).collect::<Vec<_>>();
+
+        let mut writer = RecordWriter::create(&path, &Header::new(provenance()))
+            .await
+            .unwrap();
+        for record in &records {
+            writer.write_record(record).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let mut reader = RecordReader::open(&path).await.unwrap();
+        assert_eq!(reader.num_records(), 100);
+        assert_eq!(reader.header().provenance, provenance());
+        for record in &records {
+            assert_same(&reader.read_record().await.unwrap().unwrap(), record);
+        }
+        assert!(reader.read_record().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn filters_by_computed_fields() {
+        let record = record(1);
+        let matches = |filter: &str| {
+            let filter = filter.parse::<Filter>().unwrap();
+            let filter = filter.bind(Record::field_name).unwrap();
+            filter.matches(|name| record.field(name))
+        };
+
+        assert!(matches(
+            "parallax_over_error > 5 && distance < 1000 && apparent_magnitude < 18"
+        ));
+        assert!(!matches("distance < 500"));
+
+        let ruwe = "ruwe < 1.4".parse::<Filter>().unwrap();
+        assert!(ruwe.bind(Record::field_name).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_files_without_magic() {
+        // the old format: a bare count followed by records.
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut data = vec![];
+        data.write_u64(1).await.unwrap();
+        record(1).write(&mut data).await.unwrap();
+        tokio::fs::write(&path, data).await.unwrap();
+
+        assert!(RecordReader::open(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_impossible_record_counts() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut data = vec![];
+        write_header(&mut data, &Header::new(provenance()), u64::MAX / 2)
+            .await
+            .unwrap();
+        tokio::fs::write(&path, data).await.unwrap();
+
+        let error = RecordReader::open(&path).await.err().unwrap();
+        assert!(error.to_string().contains("corrupt"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn rejects_newer_versions() {
+        let mut data = vec![];
+        write_header(&mut data, &Header::new(provenance()), 0)
+            .await
+            .unwrap();
+        data[8..10].copy_from_slice(&(VERSION + 1).to_be_bytes());
+
+        assert!(read_header(&data[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_swapped_byte_order() {
+        let mut data = vec![];
+        write_header(&mut data, &Header::new(provenance()), 0)
+            .await
+            .unwrap();
+        data[10..12].copy_from_slice(&BYTE_ORDER_MARK.to_le_bytes());
+
+        assert!(read_header(&data[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn adapts_to_other_fields() {
+        // drop the radial velocity, store the parallax as f32 and add an
+        // unknown field in front.
+        let mut header = Header::new(provenance());
+        header
+            .fields
+            .retain(|field| field.name != "radial_velocity");
+        header
+            .fields
+            .iter_mut()
+            .find(|field| field.name == "parallax")
+            .unwrap()
+            .field_type = FieldType::F32;
+        header.fields.insert(
+            0,
+            Field {
+                name: "ruwe".to_owned(),
+                field_type: FieldType::F32,
+                unit: None,
+                description: String::new(),
+            },
+        );
+
+        let expected = record(7);
+        let mut data = vec![];
+        for field in &header.fields {
+            match field.name.as_str() {
+                "ruwe" => data.write_f32(1.1).await.unwrap(),
+                "source_id" => data.write_u64(expected.source_id).await.unwrap(),
+                "healpix_start" => data.write_u32(3).await.unwrap(),
+                "healpix_end" => data.write_u32(7).await.unwrap(),
+                "parallax" => data.write_f32(expected.parallax as f32).await.unwrap(),
+                "parallax_error" => data.write_f32(expected.parallax_error).await.unwrap(),
+                "longitude" => data.write_f64(expected.longitude).await.unwrap(),
+                "latitude" => data.write_f64(expected.latitude).await.unwrap(),
+                "t_eff" => data.write_f32(expected.t_eff).await.unwrap(),
+                "apparent_magnitude" => data.write_f32(expected.apparent_magnitude).await.unwrap(),
+                "pm_longitude" => data.write_f32(expected.pm_longitude).await.unwrap(),
+                "pm_latitude" => data.write_f32(expected.pm_latitude).await.unwrap(),
+                "bp_rp" => data.write_f32(expected.bp_rp).await.unwrap(),
+                "metallicity" => data.write_f32(expected.metallicity).await.unwrap(),
+                "extinction" => data.write_f32(expected.extinction).await.unwrap(),
+                "non_single_star" => data.write_u8(expected.non_single_star).await.unwrap(),
+                name => panic!("unexpected field {name}"),
+            }
+        }
+        let path = write_raw(&header, 1, &data).await;
+
+        let mut reader = RecordReader::open(&path).await.unwrap();
+        assert!(!reader.native);
+        let read = reader.read_record().await.unwrap().unwrap();
+        assert_same(
+            &read,
+            &Record {
+                radial_velocity: f32::NAN,
+                ..expected
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_files() {
+        let mut data = vec![];
+        record(1).write(&mut data).await.unwrap();
+        let path = write_raw(&Header::new(provenance()), 2, &data).await;
+
+        assert!(RecordReader::open(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_required_fields() {
+        let mut header = Header::new(provenance());
+        header.fields.retain(|field| field.name != "parallax");
+        let path = write_raw(&header, 0, &[]).await;
+
+        assert!(RecordReader::open(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_units() {
+        let mut header = Header::new(provenance());
+        header
+            .fields
+            .iter_mut()
+            .find(|field| field.name == "parallax")
+            .unwrap()
+            .unit = Some("arcsec".to_owned());
+        let path = write_raw(&header, 0, &[]).await;
+
+        assert!(RecordReader::open(&path).await.is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//! Renders the sky into a [HiPS][1] (Hierarchical Progressive Survey).
+//!
+//! [1]: https://www.ivoa.net/documents/HiPS/
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use color_eyre::eyre::ensure;
+use image::{
+    imageops::FilterType,
+    Rgb32FImage,
+    RgbImage,
+};
+use nalgebra::Vector3;
+use structopt::StructOpt;
+
+use super::{
+    camera::ObserverSpec,
+    canvas::Canvas,
+    flux,
+    psf::Psf,
+    sky::Frame,
+    Record,
+    SelectOptions,
+    ToneMap,
+    View,
+};
+use crate::{
+    utils::healpix::{
+        self,
+        FacePosition,
+    },
+    Error,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct HipsOptions {
+    /// Deepest HEALPix order of the tiles. The order plus the binary
+    /// logarithm of the tile width must not exceed 29.
+    #[structopt(long, default_value = "3")]
+    pub order: u8,
+
+    /// Width of a tile in pixels. Must be a power of 2.
+    #[structopt(long, default_value = "512")]
+    pub tile_width: u32,
+
+    /// Coordinate frame of the HEALPix grid: galactic, equatorial or ecliptic.
+    #[structopt(long, default_value = "galactic")]
+    pub frame: Frame,
+
+    /// Position of the observer. Either heliocentric galactic coordinates
+    /// `x,y,z` in parsec, or `star:<source_id>`.
+    #[structopt(long, default_value = "0,0,0", allow_hyphen_values = true)]
+    pub observer: ObserverSpec,
+
+    /// Title of the survey.
+    #[structopt(long, default_value = "Via Lactea")]
+    pub title: String,
+
+    /// Approximate memory in MiB used for the tiles of the deepest order, by
+    /// all threads together. The export is read once for every batch of tiles
+    /// that fits.
+    #[structopt(long, default_value = "4096")]
+    pub memory_limit: u64,
+
+    /// Tone mapping operator: linear, log, asinh, reinhard or aces.
+    #[structopt(short, long, default_value = "asinh")]
+    pub tone_map: ToneMap,
+
+    /// Exposure in stops.
+    #[structopt(short, long, default_value = "0", allow_hyphen_values = true)]
+    pub exposure: f32,
+
+    #[structopt(flatten)]
+    pub psf: Psf,
+
+    #[structopt(flatten)]
+    pub select: SelectOptions,
+}
+
+/// The `Allsky` preview is written for this order, or the deepest order if it
+/// is lower.
+const ALLSKY_ORDER: u8 = 3;
+
+/// Width of a tile in the `Allsky` preview.
+const ALLSKY_TILE_WIDTH: u32 = 64;
+
+struct HipsWriter<'a> {
+    output: &'a Path,
+    /// Linear tiles are kept here until the next lower order is built from
+    /// them.
+    scratch: tempfile::TempDir,
+    options: &'a HipsOptions,
+    allsky: RgbImage,
+    allsky_columns: u32,
+}
+
+impl<'a> HipsWriter<'a> {
+    fn new(output: &'a Path, options: &'a HipsOptions) -> Result<Self, Error> {
+        let allsky_order = ALLSKY_ORDER.min(options.order);
+        let num_tiles = healpix::num_pixels(allsky_order) as u32;
+        let allsky_columns = (num_tiles as f64).sqrt() as u32;
+        let allsky_rows = num_tiles.div_ceil(allsky_columns);
+
+        Ok(Self {
+            output,
+            scratch: tempfile::tempdir_in(output)?,
+            options,
+            allsky: RgbImage::new(
+                allsky_columns * ALLSKY_TILE_WIDTH,
+                allsky_rows * ALLSKY_TILE_WIDTH,
+            ),
+            allsky_columns,
+        })
+    }
+
+    fn tile_path(&self, order: u8, index: u64) -> PathBuf {
+        self.output
+            .join(format!("Norder{order}"))
+            .join(format!("Dir{}", index / 10000 * 10000))
+            .join(format!("Npix{index}.png"))
+    }
+
+    fn scratch_path(&self, order: u8, index: u64) -> PathBuf {
+        self.scratch.path().join(format!("{order}-{index}.f32"))
+    }
+
+    /// Writes a finished tile. Empty tiles are skipped, as HiPS viewers treat
+    /// missing tiles as empty.
+    async fn write_tile(&mut self, order: u8, index: u64, image: Rgb32FImage) -> Result<(), Error> {
+        if image.iter().all(|channel| *channel == 0.0) {
+            return Ok(());
+        }
+
+        if order > 0 {
+            let data = image
+                .iter()
+                .flat_map(|channel| channel.to_le_bytes())
+                .collect::<Vec<u8>>();
+            tokio::fs::write(self.scratch_path(order, index), data).await?;
+        }
+
+        let canvas = Canvas { image };
+        let tile = canvas.tone_map(self.options.tone_map, self.options.exposure);
+
+        if order == ALLSKY_ORDER.min(self.options.order) {
+            let thumbnail = image::imageops::resize(
+                &tile,
+                ALLSKY_TILE_WIDTH,
+                ALLSKY_TILE_WIDTH,
+                FilterType::Triangle,
+            );
+            let index = index as u32;
+            image::imageops::replace(
+                &mut self.allsky,
+                &thumbnail,
+                ((index % self.allsky_columns) * ALLSKY_TILE_WIDTH) as i64,
+                ((index / self.allsky_columns) * ALLSKY_TILE_WIDTH) as i64,
+            );
+        }
+
+        let path = self.tile_path(order, index);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tile.save(path)?;
+
+        Ok(())
+    }
+
+    /// Reads a linear tile written by [`HipsWriter::write_tile`].
+    async fn read_tile(&self, order: u8, index: u64) -> Result<Option<Rgb32FImage>, Error> {
+        let path = self.scratch_path(order, index);
+        if !tokio::fs::try_exists(&path).await? {
+            return Ok(None);
+        }
+
+        let data = tokio::fs::read(&path).await?;
+        tokio::fs::remove_file(&path).await?;
+
+        let data = data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let width = self.options.tile_width;
+        Ok(Rgb32FImage::from_raw(width, width, data))
+    }
+
+    async fn write_metadata(&self) -> Result<(), Error> {
+        let allsky_order = ALLSKY_ORDER.min(self.options.order);
+        let allsky_dir = self.output.join(format!("Norder{allsky_order}"));
+        tokio::fs::create_dir_all(&allsky_dir).await?;
+        self.allsky.save(allsky_dir.join("Allsky.png"))?;
+
+        let properties = [
+            ("creator_did", "ivo://via-lactea/P/stars".to_owned()),
+            ("obs_title", self.options.title.clone()),
+            ("dataproduct_type", "image".to_owned()),
+            ("hips_version", "1.4".to_owned()),
+            ("hips_builder", "via-tool".to_owned()),
+            (
+                "hips_release_date",
+                chrono::Utc::now().format("%Y-%m-%dT%H:%MZ").to_string(),
+            ),
+            ("hips_status", "private master clonableOnce".to_owned()),
+            ("hips_tile_format", "png".to_owned()),
+            ("hips_order", self.options.order.to_string()),
+            ("hips_order_min", "0".to_owned()),
+            ("hips_tile_width", self.options.tile_width.to_string()),
+            ("hips_frame", self.options.frame.to_string()),
+        ];
+
+        let mut text = String::new();
+        for (key, value) in properties {
+            text.push_str(&format!("{key:<20} = {value}\n"));
+        }
+        tokio::fs::write(self.output.join("properties"), text).await?;
+
+        Ok(())
+    }
+}
+
+/// Builds a tile from its four children by averaging 2x2 pixels.
+///
+/// Within a tile, image columns follow the HEALPix `y` coordinate and rows the
+/// `x` coordinate, as required by the HiPS standard.
+fn downsample(children: [Option<Rgb32FImage>; 4], width: u32) -> Rgb32FImage {
+    let mut parent = Rgb32FImage::new(width, width);
+    let half = width / 2;
+
+    for (quadrant, child) in children.into_iter().enumerate() {
+        let Some(child) = child
+        else {
+            continue;
+        };
+        let (qx, qy) = healpix::deinterleave(quadrant as u64);
+
+        for (column, row, pixel) in child.enumerate_pixels() {
+            let target = parent.get_pixel_mut(qy * half + column / 2, qx * half + row / 2);
+            for (target, source) in target.0.iter_mut().zip(pixel.0) {
+                *target += 0.25 * source;
+            }
+        }
+    }
+
+    parent
+}
+
+/// Angle covered by the side of a base pixel, on average.
+const FACE_ANGLE: f64 = 1.0233267079464885;
+
+/// Tiles of the given order onto which a star drawn with `radius` pixels
+/// falls, with the position of the star in the pixels of each tile.
+///
+/// Tiles around the star are found by sampling directions on a circle around
+/// it. The star's position in tiles of another base pixel is extrapolated
+/// from the nearest sample, as the face coordinates aren't continuous across
+/// base pixels.
+fn tile_positions(
+    direction: &Vector3<f64>,
+    order: u8,
+    width: u32,
+    radius: f64,
+) -> Vec<(u64, [f64; 2])> {
+    const NUM_SAMPLES: usize = 16;
+
+    let nside = (1u64 << order) as f64;
+    let width = width as f64;
+    let tile_position = |index: u64, x: f64, y: f64| {
+        let (ix, iy) = healpix::deinterleave(index % (1 << (2 * order as u64)));
+        // image columns follow y, rows follow x
+        (
+            index,
+            [
+                (y * nside - iy as f64) * width,
+                (x * nside - ix as f64) * width,
+            ],
+        )
+    };
+
+    let center = FacePosition::from_direction(direction);
+    let (index, _) = center.pixel(order);
+    let mut tiles = vec![tile_position(index, center.x, center.y)];
+    if radius <= 0.0 {
+        return tiles;
+    }
+
+    // pixels are up to about twice as large as on average
+    let angle = 2.0 * (radius + 1.0) * FACE_ANGLE / (nside * width);
+    let axis = if direction.z.abs() < 0.9 {
+        Vector3::z()
+    }
+    else {
+        Vector3::x()
+    };
+    let east = direction.cross(&axis).normalize();
+    let north = direction.cross(&east);
+
+    for i in 0..NUM_SAMPLES {
+        let azimuth = std::f64::consts::TAU * i as f64 / NUM_SAMPLES as f64;
+        let sample =
+            direction * angle.cos() + (east * azimuth.cos() + north * azimuth.sin()) * angle.sin();
+        let position = FacePosition::from_direction(&sample);
+        let (index, _) = position.pixel(order);
+        if tiles.iter().any(|(tile, _)| *tile == index) {
+            continue;
+        }
+
+        if position.face == center.face {
+            tiles.push(tile_position(index, center.x, center.y));
+            continue;
+        }
+
+        // solve `direction - sample = dx * d/dx + dy * d/dy` in the face of the
+        // sample
+        let step = 1e-6;
+        let at = |x: f64, y: f64| {
+            FacePosition {
+                face: position.face,
+                x,
+                y,
+            }
+            .direction()
+        };
+        let origin = at(position.x, position.y);
+        let dx = (at(position.x + step, position.y) - origin) / step;
+        let dy = (at(position.x, position.y + step) - origin) / step;
+        let offset = direction - origin;
+        let [xx, xy, yy] = [dx.dot(&dx), dx.dot(&dy), dy.dot(&dy)];
+        let [ox, oy] = [dx.dot(&offset), dy.dot(&offset)];
+        let determinant = xx * yy - xy * xy;
+        if determinant.abs() < f64::EPSILON {
+            continue;
+        }
+        let x = position.x + (yy * ox - xy * oy) / determinant;
+        let y = position.y + (xx * oy - xy * ox) / determinant;
+        tiles.push(tile_position(index, x, y));
+    }
+
+    tiles
+}
+
+pub async fn hips(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: HipsOptions,
+) -> Result<(), Error> {
+    ensure!(
+        options.tile_width.is_power_of_two() && options.tile_width >= 2,
+        "tile width must be a power of 2"
+    );
+    ensure!(
+        options.order as u32 + options.tile_width.ilog2() <= healpix::MAX_ORDER as u32,
+        "order {} is too deep for tiles {} pixels wide",
+        options.order,
+        options.tile_width
+    );
+
+    let path = path.as_ref();
+    let output = output.as_ref();
+    tokio::fs::create_dir_all(output).await?;
+
+    let observer = options.observer.resolve(path).await?;
+    let from_galactic = options.frame.rotation_from_galactic();
+
+    let mut writer = HipsWriter::new(output, &options)?;
+
+    // render the deepest order in batches of tiles that fit into memory.
+    let order = options.order;
+    let width = options.tile_width;
+    let num_tiles = healpix::num_pixels(order);
+    let tile_bytes = 3 * 4 * (width as u64).pow(2);
+    let jobs = options.select.num_jobs() as u64;
+    let batch_size = (options.memory_limit * 1024 * 1024 / (tile_bytes * jobs)).max(1);
+
+    for batch_start in (0..num_tiles).step_by(batch_size as usize) {
+        let batch_end = (batch_start + batch_size).min(num_tiles);
+        tracing::info!(order, batch_start, batch_end, "rendering tiles");
+
+        let mut threads = options
+            .select
+            .draw_parallel(
+                path,
+                || {
+                    (batch_start..batch_end)
+                        .map(|_| Canvas::new(width, width))
+                        .collect::<Vec<_>>()
+                },
+                |canvases, record: &Record| {
+                    let Some(observation) = observer.observe(record)
+                    else {
+                        return;
+                    };
+
+                    let direction = from_galactic * observation.direction;
+                    let flux = flux(
+                        observation.apparent_magnitude,
+                        View::SKY_REFERENCE_MAGNITUDE,
+                    );
+
+                    // stars near the edge of a tile spill into its neighbours
+                    let radius = options.psf.radius(flux).min(width as f64);
+                    for (index, position) in tile_positions(&direction, order, width, radius) {
+                        if index < batch_start || index >= batch_end {
+                            continue;
+                        }
+                        let canvas = &mut canvases[(index - batch_start) as usize];
+                        options.psf.draw(canvas, position, record.color(), flux);
+                    }
+                },
+            )
+            .await?
+            .into_iter();
+
+        let mut canvases = threads.next().unwrap();
+        for other in threads {
+            for (canvas, other) in canvases.iter_mut().zip(&other) {
+                canvas.merge(other);
+            }
+        }
+
+        for (index, canvas) in (batch_start..batch_end).zip(canvases) {
+            writer.write_tile(order, index, canvas.image).await?;
+        }
+    }
+
+    // build the lower orders from their children.
+    for order in (0..order).rev() {
+        tracing::info!(order, "building tiles");
+
+        for index in 0..healpix::num_pixels(order) {
+            let mut children = [None, None, None, None];
+            for (quadrant, child) in children.iter_mut().enumerate() {
+                *child = writer
+                    .read_tile(order + 1, 4 * index + quadrant as u64)
+                    .await?;
+            }
+            if children.iter().all(Option::is_none) {
+                continue;
+            }
+
+            writer
+                .write_tile(order, index, downsample(children, width))
+                .await?;
+        }
+    }
+
+    writer.write_metadata().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spills_into_neighbouring_tiles() {
+        let order = 1;
+        let width = 512;
+        let nside = 2.0;
+
+        // near the edges of a tile within a face, and of the base pixel
+        let stars = [
+            (4, 0.4999, 0.2),
+            (4, 0.9999, 0.3),
+            (0, 0.5, 0.0001),
+            (9, 0.0001, 0.6),
+        ];
+        for (face, x, y) in stars {
+            let direction = FacePosition { face, x, y }.direction();
+            let tiles = tile_positions(&direction, order, width, 4.0);
+            assert!(tiles.len() >= 2, "{face} {x} {y}: {tiles:?}");
+
+            for (index, [column, row]) in tiles {
+                let (ix, iy) = healpix::deinterleave(index % 4);
+                let position = FacePosition {
+                    face: (index / 4) as u8,
+                    x: (row / width as f64 + ix as f64) / nside,
+                    y: (column / width as f64 + iy as f64) / nside,
+                };
+                // within a tenth of a pixel
+                assert!(
+                    (position.direction() - direction).norm() < 0.1 / (nside * width as f64),
+                    "{face} {x} {y}: {index} {column} {row}"
+                );
+            }
+        }
+
+        // the four base pixels around the south pole
+        let south_pole = FacePosition {
+            face: 9,
+            x: 0.0001,
+            y: 0.0001,
+        };
+        let mut tiles = tile_positions(&south_pole.direction(), order, width, 4.0)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        tiles.sort();
+        assert_eq!(tiles, [32, 36, 40, 44]);
+    }
+}
@@ -0,0 +1,306 @@
+//! Index of the source ids of an export, to find single records without
+//! scanning the export.
+//!
+//! The index is stored next to the export, with `.idx` appended to its file
+//! name. It starts with:
+//!
+//! | bytes | content                                            |
+//! |-------|----------------------------------------------------|
+//! | 8     | magic `VIAIDX\0\0`                                 |
+//! | 2     | format version                                     |
+//! | 8     | number of records of the export                    |
+//! | 8     | number of entries                                  |
+//!
+//! followed by the entries, ordered by source id, each with the source id
+//! and the index of its record as `u64`. All numbers are big-endian. A
+//! lookup is a binary search on the memory-mapped entries.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use color_eyre::eyre::ensure;
+use memmap2::Mmap;
+use tokio::{
+    fs::File,
+    io::{
+        AsyncWriteExt,
+        BufWriter,
+    },
+};
+
+use super::{
+    progress_bar,
+    Record,
+    RecordReader,
+};
+use crate::Error;
+
+const MAGIC: [u8; 8] = *b"VIAIDX\0\0";
+
+const VERSION: u16 = 1;
+
+const PRELUDE_SIZE: usize = 26;
+
+const ENTRY_SIZE: usize = 16;
+
+/// Path of the index of an export.
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(path.as_os_str());
+    file_name.push(".idx");
+    file_name.into()
+}
+
+/// Writes the source id index of the export at `path`. The entries are sorted
+/// in memory, which takes 16 bytes per record, unless the records are in
+/// source id order already.
+pub async fn write_source_index(path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut records = RecordReader::open(path).await?;
+
+    tracing::info!("indexing source ids");
+    let progress = progress_bar(records.num_records());
+    let mut entries = Vec::with_capacity(records.num_records() as usize);
+    let mut sorted = true;
+    while let Some(record) = records.read_record().await? {
+        if let Some(&(previous, _)) = entries.last() {
+            sorted &= previous <= record.source_id;
+        }
+        entries.push((record.source_id, entries.len() as u64));
+        progress.set_position(records.num_read());
+    }
+    progress.finish();
+
+    if !sorted {
+        tracing::info!("sorting source ids");
+        entries.sort_unstable();
+    }
+
+    let mut writer = BufWriter::new(File::create(index_path(path)).await?);
+    writer.write_all(&MAGIC).await?;
+    writer.write_u16(VERSION).await?;
+    writer.write_u64(records.num_records()).await?;
+    writer.write_u64(entries.len() as u64).await?;
+    for (source_id, index) in entries {
+        writer.write_u64(source_id).await?;
+        writer.write_u64(index).await?;
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Removes the source id index of the export at `path`, if there is one, so
+/// that an index of a previous export isn't used for a new one.
+pub async fn remove_source_index(path: impl AsRef<Path>) -> Result<(), Error> {
+    match tokio::fs::remove_file(index_path(path.as_ref())).await {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// A memory-mapped source id index.
+pub struct SourceIndex {
+    mmap: Mmap,
+    /// Number of records of the indexed export.
+    num_records: u64,
+    num_entries: usize,
+}
+
+impl SourceIndex {
+    /// Opens the index of the export at `path`.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let index_path = index_path(path);
+        let file = fs::File::open(&index_path).map_err(|error| {
+            Error::new(error).wrap_err(format!(
+                "can't open the index {}, create it with the `index` command",
+                index_path.display()
+            ))
+        })?;
+
+        // SAFETY: like exports, indices aren't modified once they are written.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        ensure!(
+            mmap.len() >= PRELUDE_SIZE && mmap[..8] == MAGIC,
+            "{} isn't an index",
+            index_path.display()
+        );
+        let version = u16::from_be_bytes(mmap[8..10].try_into()?);
+        ensure!(
+            version <= VERSION,
+            "{} has version {version}, but only up to {VERSION} is supported",
+            index_path.display()
+        );
+        let num_records = u64::from_be_bytes(mmap[10..18].try_into()?);
+        let num_entries = u64::from_be_bytes(mmap[18..26].try_into()?).try_into()?;
+        ensure!(
+            (mmap.len() - PRELUDE_SIZE) / ENTRY_SIZE >= num_entries,
+            "{} is truncated",
+            index_path.display()
+        );
+
+        Ok(Self {
+            mmap,
+            num_records,
+            num_entries,
+        })
+    }
+
+    fn entry(&self, i: usize) -> (u64, u64) {
+        let offset = PRELUDE_SIZE + i * ENTRY_SIZE;
+        let entry = &self.mmap[offset..offset + ENTRY_SIZE];
+        let (source_id, index) = entry.split_at(8);
+        (
+            u64::from_be_bytes(source_id.try_into().unwrap()),
+            u64::from_be_bytes(index.try_into().unwrap()),
+        )
+    }
+
+    /// Index of the record with the source id, if there is one.
+    pub fn find(&self, source_id: u64) -> Option<u64> {
+        let mut low = 0;
+        let mut high = self.num_entries;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.entry(middle).0 < source_id {
+                low = middle + 1;
+            }
+            else {
+                high = middle;
+            }
+        }
+
+        (low < self.num_entries)
+            .then(|| self.entry(low))
+            .filter(|(found, _)| *found == source_id)
+            .map(|(_, index)| index)
+    }
+}
+
+/// Looks up records of the export at `path` by their source ids, using its
+/// index. Source ids that aren't in the export are `None`.
+pub async fn lookup(
+    path: impl AsRef<Path>,
+    source_ids: &[u64],
+) -> Result<Vec<Option<Record>>, Error> {
+    let path = path.as_ref();
+    let index = SourceIndex::open(path)?;
+    let mut records = RecordReader::open(path).await?;
+    ensure!(
+        index.num_records == records.num_records(),
+        "the index of {} is outdated, recreate it with the `index` command",
+        path.display()
+    );
+
+    let mut found = Vec::with_capacity(source_ids.len());
+    for source_id in source_ids {
+        let record = match index.find(*source_id) {
+            Some(i) => {
+                let record = records.read_record_at(i).await?;
+                // the record count matches for an index of another export with
+                // as many records.
+                ensure!(
+                    record.source_id == *source_id,
+                    "the index of {} doesn't match the export, recreate it with the `index` \
+                     command",
+                    path.display()
+                );
+                Some(record)
+            }
+            None => None,
+        };
+        found.push(record);
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gaia::HealPixRange,
+        render::format::{
+            Header,
+            Provenance,
+            RecordWriter,
+        },
+    };
+
+    async fn write_export(path: &Path, source_ids: &[u64]) {
+        let mut writer = RecordWriter::create(path, &Header::new(Provenance::default()))
+            .await
+            .unwrap();
+        for source_id in source_ids {
+            let record = Record {
+                source_id: *source_id,
+                healpix_range: HealPixRange { start: 0, end: 1 },
+                parallax: *source_id as f64,
+                parallax_error: 0.1,
+                longitude: 0.0,
+                latitude: 0.0,
+                t_eff: 5000.0,
+                apparent_magnitude: 10.0,
+                pm_longitude: 0.0,
+                pm_latitude: 0.0,
+                bp_rp: 0.0,
+                metallicity: 0.0,
+                extinction: 0.0,
+                radial_velocity: 0.0,
+                non_single_star: 0,
+            };
+            writer.write_record(&record).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finds_records_by_source_id() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut source_ids = (0..100)
+            .map(|i| (i * 7919) % 1000 + 1)
+            .collect::<Vec<u64>>();
+
+        // once out of order, and once in order without sorting the index
+        for _ in 0..2 {
+            write_export(&path, &source_ids).await;
+            write_source_index(&path).await.unwrap();
+
+            let found = lookup(&path, &[source_ids[42], source_ids[0], 0, 1001])
+                .await
+                .unwrap();
+            assert_eq!(found[0].as_ref().unwrap().source_id, source_ids[42]);
+            assert_eq!(found[0].as_ref().unwrap().parallax, source_ids[42] as f64);
+            assert_eq!(found[1].as_ref().unwrap().source_id, source_ids[0]);
+            assert!(found[2].is_none());
+            assert!(found[3].is_none());
+
+            source_ids.sort_unstable();
+        }
+
+        remove_source_index(&path).await.unwrap();
+        assert!(!index_path(&path).exists());
+        remove_source_index(&path).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_indices_of_other_exports() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        write_export(&path, &[1, 2, 3]).await;
+        write_source_index(&path).await.unwrap();
+
+        // as many records, but other source ids
+        write_export(&path, &[3, 4, 5]).await;
+        assert!(lookup(&path, &[1]).await.is_err());
+        assert!(lookup(&path, &[3]).await.is_err());
+
+        remove_source_index(&path).await.unwrap();
+    }
+}
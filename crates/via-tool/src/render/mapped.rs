@@ -0,0 +1,274 @@
+//! Memory-mapped exports.
+//!
+//! Records with the current fields have a fixed size and layout, so once an
+//! export is mapped into memory, its records can be accessed as a slice of
+//! [`RawRecord`]s, without reading or parsing the file.
+
+use std::{
+    fs::File,
+    ops::Range,
+    path::Path,
+};
+
+use color_eyre::eyre::{
+    bail,
+    ensure,
+};
+use memmap2::Mmap;
+
+use super::{
+    format::{
+        parse_header,
+        records_end,
+        Header,
+    },
+    spatial::{
+        Node,
+        Region,
+    },
+    Record,
+};
+use crate::{
+    gaia::HealPixRange,
+    Error,
+};
+
+/// A record as stored in an export. The fields are big-endian byte arrays,
+/// so the struct has an alignment of 1 and no padding.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct RawRecord {
+    source_id: [u8; 8],
+    healpix_start: [u8; 4],
+    healpix_end: [u8; 4],
+    parallax: [u8; 8],
+    parallax_error: [u8; 4],
+    longitude: [u8; 8],
+    latitude: [u8; 8],
+    t_eff: [u8; 4],
+    apparent_magnitude: [u8; 4],
+    pm_longitude: [u8; 4],
+    pm_latitude: [u8; 4],
+    bp_rp: [u8; 4],
+    metallicity: [u8; 4],
+    extinction: [u8; 4],
+    radial_velocity: [u8; 4],
+    non_single_star: u8,
+}
+
+const _: () = assert!(std::mem::align_of::<RawRecord>() == 1);
+
+impl RawRecord {
+    pub fn source_id(&self) -> u64 {
+        u64::from_be_bytes(self.source_id)
+    }
+
+    /// in mas
+    pub fn parallax(&self) -> f64 {
+        f64::from_be_bytes(self.parallax)
+    }
+
+    /// Galactic longitude in degrees.
+    pub fn longitude(&self) -> f64 {
+        f64::from_be_bytes(self.longitude)
+    }
+
+    /// Galactic latitude in degrees.
+    pub fn latitude(&self) -> f64 {
+        f64::from_be_bytes(self.latitude)
+    }
+
+    /// in K
+    pub fn t_eff(&self) -> f32 {
+        f32::from_be_bytes(self.t_eff)
+    }
+
+    pub fn apparent_magnitude(&self) -> f32 {
+        f32::from_be_bytes(self.apparent_magnitude)
+    }
+
+    pub fn to_record(&self) -> Record {
+        Record {
+            source_id: self.source_id(),
+            healpix_range: HealPixRange {
+                start: u32::from_be_bytes(self.healpix_start),
+                end: u32::from_be_bytes(self.healpix_end),
+            },
+            parallax: self.parallax(),
+            parallax_error: f32::from_be_bytes(self.parallax_error),
+            longitude: self.longitude(),
+            latitude: self.latitude(),
+            t_eff: self.t_eff(),
+            apparent_magnitude: self.apparent_magnitude(),
+            pm_longitude: f32::from_be_bytes(self.pm_longitude),
+            pm_latitude: f32::from_be_bytes(self.pm_latitude),
+            bp_rp: f32::from_be_bytes(self.bp_rp),
+            metallicity: f32::from_be_bytes(self.metallicity),
+            extinction: f32::from_be_bytes(self.extinction),
+            radial_velocity: f32::from_be_bytes(self.radial_velocity),
+            non_single_star: self.non_single_star,
+        }
+    }
+}
+
+/// An export mapped into memory.
+pub struct MappedExport {
+    mmap: Mmap,
+    header: Header,
+    data_offset: usize,
+    num_records: usize,
+}
+
+impl MappedExport {
+    /// Maps the export. This fails for exports with other fields than the
+    /// current records, which have to be read with
+    /// [`RecordReader`](super::RecordReader).
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+
+        // SAFETY: the mapping is only valid as long as the file isn't
+        // modified, which exports aren't once they are written.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let (header, num_records, data_offset) = parse_header(&mmap)
+            .map_err(|error| error.wrap_err(format!("can't read {}", path.display())))?;
+        ensure!(
+            header.is_native(),
+            "{} has other fields than the current records and can't be memory-mapped",
+            path.display()
+        );
+        ensure!(
+            header.record_size() == std::mem::size_of::<RawRecord>(),
+            "the record size doesn't match the memory layout"
+        );
+        let Some(end) = records_end(data_offset, num_records, &header)
+        else {
+            bail!(
+                "{} is corrupt: it claims to have {num_records} records",
+                path.display()
+            );
+        };
+        ensure!(mmap.len() as u64 >= end, "{} is truncated", path.display());
+
+        Ok(Self {
+            mmap,
+            header,
+            data_offset: data_offset.try_into()?,
+            num_records: num_records.try_into()?,
+        })
+    }
+
+    pub fn records(&self) -> &[RawRecord] {
+        let size = self.num_records * std::mem::size_of::<RawRecord>();
+        let bytes = &self.mmap[self.data_offset..self.data_offset + size];
+
+        // SAFETY: `RawRecord` only consists of byte arrays, so it has an
+        // alignment of 1, no padding, and any bytes are a valid value.
+        unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast(), self.num_records) }
+    }
+
+    /// Octree leaves of a spatially sorted export.
+    fn nodes(&self) -> Result<Option<Vec<Node>>, Error> {
+        if self.header.spatial_index.is_none() {
+            return Ok(None);
+        }
+
+        let index_offset = self.data_offset + self.num_records * self.header.record_size();
+        let index = &self.mmap[index_offset..];
+        ensure!(index.len() >= 8, "the index is missing");
+        let (num_nodes, index) = index.split_at(8);
+        let num_nodes = u64::from_be_bytes(num_nodes.try_into()?) as usize;
+        ensure!(
+            index.len() / Node::SIZE >= num_nodes,
+            "the index is truncated"
+        );
+
+        let nodes = index
+            .chunks_exact(Node::SIZE)
+            .take(num_nodes)
+            .map(|bytes| Node::from_bytes(bytes.try_into()?))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(nodes))
+    }
+
+    /// Ranges of records near the region. These are all records if there is
+    /// no region, or the export isn't spatially sorted.
+    pub fn ranges(&self, region: Option<&Region>) -> Result<Vec<Range<usize>>, Error> {
+        let all_records = 0..self.num_records;
+        let Some(region) = region
+        else {
+            return Ok(vec![all_records]);
+        };
+        let (Some(index), Some(nodes)) = (self.header.spatial_index, self.nodes()?)
+        else {
+            tracing::warn!("the file has no spatial index, so all records are read");
+            return Ok(vec![all_records]);
+        };
+
+        let ranges = index
+            .ranges(&nodes, self.num_records as u64, region)
+            .into_iter()
+            .map(|range| range.start as usize..range.end as usize)
+            .collect();
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::format::{
+        Provenance,
+        RecordWriter,
+    };
+
+    #[tokio::test]
+    async fn raw_records_match_the_written_records() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let records = (0..10)
+            .map(|i| {
+                Record {
+                    source_id: 1000 + i,
+                    healpix_range: HealPixRange { start: 1, end: 2 },
+                    parallax: 0.5 * i as f64,
+                    parallax_error: 0.25,
+                    longitude: 10.0 * i as f64,
+                    latitude: -5.0,
+                    t_eff: 3000.0 + i as f32,
+                    apparent_magnitude: 8.5,
+                    pm_longitude: 1.5,
+                    pm_latitude: -2.5,
+                    bp_rp: 0.75,
+                    metallicity: f32::NAN,
+                    extinction: 0.125,
+                    radial_velocity: -30.0,
+                    non_single_star: i as u8,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut writer = RecordWriter::create(&path, &Header::new(Provenance::default()))
+            .await
+            .unwrap();
+        for record in &records {
+            writer.write_record(record).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let export = MappedExport::open(&path).unwrap();
+        assert_eq!(export.records().len(), records.len());
+        for (raw, record) in export.records().iter().zip(&records) {
+            let mapped = raw.to_record();
+            assert_eq!(mapped.source_id, record.source_id);
+            assert_eq!(mapped.healpix_range.end, record.healpix_range.end);
+            assert_eq!(mapped.parallax, record.parallax);
+            assert_eq!(mapped.longitude, record.longitude);
+            assert_eq!(mapped.t_eff, record.t_eff);
+            assert_eq!(mapped.pm_latitude, record.pm_latitude);
+            assert!(mapped.metallicity.is_nan());
+            assert_eq!(mapped.radial_velocity, record.radial_velocity);
+            assert_eq!(mapped.non_single_star, record.non_single_star);
+        }
+    }
+}
@@ -0,0 +1,1033 @@
+mod animate;
+mod camera;
+mod canvas;
+mod colormap;
+mod diagram;
+mod dump;
+mod fits;
+mod font;
+mod format;
+mod hips;
+mod lookup;
+mod mapped;
+mod orthographic;
+mod pack;
+mod psf;
+mod sample;
+mod sky;
+mod skybox;
+mod spatial;
+mod statistic;
+mod tabular;
+mod tiles;
+mod tone_map;
+mod votable;
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        mpsc::SyncSender,
+        Arc,
+        Mutex,
+    },
+    time::Instant,
+};
+
+use color_eyre::eyre::ensure;
+use futures::{
+    pin_mut,
+    Future,
+    FutureExt,
+};
+use image::{
+    ImageBuffer,
+    Pixel,
+};
+use indicatif::{
+    ProgressBar,
+    ProgressStyle,
+};
+use nalgebra::{
+    Point3,
+    Rotation3,
+    Vector3,
+};
+use palette::LinSrgb;
+use serde::Serialize;
+use structopt::StructOpt;
+
+pub use self::{
+    animate::{
+        animate,
+        AnimateOptions,
+    },
+    diagram::{
+        diagram,
+        DiagramOptions,
+    },
+    dump::{
+        dump,
+        DumpOptions,
+    },
+    hips::{
+        hips,
+        HipsOptions,
+    },
+    lookup::{
+        lookup,
+        remove_source_index,
+        write_source_index,
+    },
+    pack::{
+        pack,
+        PackOptions,
+    },
+    skybox::{
+        skybox,
+        SkyboxOptions,
+    },
+    spatial::{
+        sort,
+        SortOptions,
+    },
+    tabular::TableOptions,
+    tiles::{
+        tiles,
+        TilesOptions,
+    },
+    tone_map::ToneMap,
+};
+use self::{
+    camera::{
+        Camera,
+        CameraOptions,
+        Observer,
+    },
+    canvas::{
+        save_hdr,
+        Canvas,
+        OutputFormat,
+        OverlayPixel,
+    },
+    format::{
+        Header,
+        Provenance,
+        RecordReader,
+        RecordWriter,
+    },
+    mapped::MappedExport,
+    orthographic::{
+        Orthographic,
+        OrthographicOptions,
+    },
+    psf::Psf,
+    sample::{
+        SampleKey,
+        SampleOptions,
+        Sampler,
+    },
+    sky::{
+        east_north,
+        galactic_direction,
+        Frame,
+        SkyOptions,
+        SkyProjector,
+    },
+    spatial::Region,
+    statistic::{
+        render_statistic,
+        StatisticOptions,
+    },
+};
+use crate::{
+    filter::Filter,
+    gaia::{
+        self,
+        Column,
+        Data,
+        GaiaSource,
+        HealPixRange,
+        Row,
+    },
+    utils::teff_color::TEFF_COLORS,
+    Error,
+};
+
+#[derive(Clone, Serialize)]
+pub struct Record {
+    source_id: u64,
+    healpix_range: HealPixRange,
+    parallax: f64,
+    parallax_error: f32,
+    longitude: f64,
+    latitude: f64,
+    t_eff: f32,
+    apparent_magnitude: f32,
+    /// Proper motion in galactic longitude, times `cos(latitude)`, in mas/yr,
+    /// or NaN if unknown.
+    pm_longitude: f32,
+    /// Proper motion in galactic latitude in mas/yr, or NaN if unknown.
+    pm_latitude: f32,
+    /// BP - RP colour in mag, or NaN if unknown.
+    bp_rp: f32,
+    /// `[M/H]` in dex, or NaN if unknown.
+    metallicity: f32,
+    /// Extinction in the G band in mag, or NaN if unknown.
+    extinction: f32,
+    /// in km/s, or NaN if unknown.
+    radial_velocity: f32,
+    /// Non-single star flag, 0 for single stars.
+    non_single_star: u8,
+}
+
+impl Record {
+    pub fn from_gaia(record: &gaia::Record) -> Option<Self> {
+        let longitude = record.gaia_source.l?;
+        let latitude = record.gaia_source.b?;
+        let [pm_longitude, pm_latitude] =
+            galactic_proper_motion(&record.gaia_source, longitude, latitude)
+                .unwrap_or([f32::NAN; 2]);
+
+        Some(Self {
+            source_id: record.gaia_source.source_id,
+            healpix_range: record.healpix_range,
+            parallax: record.gaia_source.parallax?,
+            parallax_error: record.gaia_source.parallax_error.unwrap_or(f32::NAN),
+            longitude,
+            latitude,
+            t_eff: record.gaia_source.teff_gspphot?,
+            apparent_magnitude: record.gaia_source.phot_g_mean_mag?,
+            pm_longitude,
+            pm_latitude,
+            bp_rp: record.gaia_source.bp_rp.unwrap_or(f32::NAN),
+            metallicity: record.gaia_source.mh_gspphot.unwrap_or(f32::NAN),
+            extinction: record.gaia_source.ag_gspphot.unwrap_or(f32::NAN),
+            radial_velocity: record.gaia_source.radial_velocity.unwrap_or(f32::NAN),
+            non_single_star: record.gaia_source.non_single_star.unwrap_or(0) as u8,
+        })
+    }
+
+    pub fn color(&self) -> LinSrgb {
+        TEFF_COLORS
+            .get(self.t_eff)
+            .unwrap_or_else(|| LinSrgb::new(1.0, 1.0, 1.0))
+    }
+
+    /// in kilo parsec
+    pub fn distance(&self) -> f64 {
+        1.0 / self.parallax
+    }
+
+    pub fn absolute_magnitude(&self) -> f32 {
+        self.apparent_magnitude - 5.0 * (self.distance().log10() as f32 + 2.0)
+    }
+
+    pub fn position(&self) -> Point3<f64> {
+        let longitude = self.longitude.to_radians();
+        let latitude = self.latitude.to_radians();
+
+        let rotation = Rotation3::from_axis_angle(&Vector3::z_axis(), longitude)
+            * Rotation3::from_axis_angle(&Vector3::x_axis(), latitude);
+
+        Point3::from(rotation * (self.distance() * *Vector3::y_axis()))
+    }
+
+    /// Unit vector towards the star in galactic coordinates.
+    pub fn direction(&self) -> Vector3<f64> {
+        galactic_direction(self.longitude, self.latitude)
+    }
+
+    /// Heliocentric galactic coordinates in parsec, with the x axis pointing
+    /// to the galactic center and the z axis to the north galactic pole.
+    pub fn heliocentric_position(&self) -> Point3<f64> {
+        Point3::from(1000.0 * self.distance() * self.direction())
+    }
+
+    /// Heliocentric velocity in km/s in galactic coordinates, or `None` if the
+    /// proper motion or distance isn't known. An unknown radial velocity is
+    /// taken to be 0.
+    pub fn velocity(&self) -> Option<Vector3<f64>> {
+        /// km/s for a proper motion of 1 mas/yr at 1 kpc.
+        const TRANSVERSE_VELOCITY: f64 = 4.740470446;
+
+        if self.parallax <= 0.0 || self.pm_longitude.is_nan() || self.pm_latitude.is_nan() {
+            return None;
+        }
+
+        let [east, north] = east_north(self.longitude, self.latitude);
+        let radial_velocity = if self.radial_velocity.is_finite() {
+            self.radial_velocity as f64
+        }
+        else {
+            0.0
+        };
+
+        let proper_motion = self.pm_longitude as f64 * east + self.pm_latitude as f64 * north;
+        let transverse = TRANSVERSE_VELOCITY * self.distance() * proper_motion;
+        Some(transverse + radial_velocity * self.direction())
+    }
+
+    /// The star after moving with its velocity for `years`. Stars whose
+    /// velocity isn't known stay in place.
+    pub fn at_epoch(&self, years: f64) -> Record {
+        /// pc per year for a velocity of 1 km/s.
+        const PARSEC_PER_YEAR: f64 = 1.0227121650537077e-6;
+
+        let mut record = self.clone();
+        let Some(velocity) = self.velocity()
+        else {
+            return record;
+        };
+
+        let position = self.heliocentric_position() + velocity * years * PARSEC_PER_YEAR;
+        let distance = position.coords.norm();
+        if distance <= 0.0 {
+            return record;
+        }
+
+        record.parallax = 1000.0 / distance;
+        record.longitude = position.y.atan2(position.x).to_degrees();
+        record.latitude = (position.z / distance).asin().to_degrees();
+        record.apparent_magnitude += 5.0 * (distance / (1000.0 * self.distance())).log10() as f32;
+        record
+    }
+}
+
+/// Converts the equatorial proper motion of a source into galactic longitude
+/// and latitude.
+fn galactic_proper_motion(source: &GaiaSource, longitude: f64, latitude: f64) -> Option<[f32; 2]> {
+    let [east, north] = east_north(source.ra?, source.dec?);
+    let motion = source.pmra? * east + source.pmdec? * north;
+    let motion = Frame::Equatorial.rotation_from_galactic().inverse() * motion;
+
+    let [east, north] = east_north(longitude, latitude);
+    Some([motion.dot(&east) as f32, motion.dot(&north) as f32])
+}
+
+/// Flux relative to a star of the `reference` magnitude.
+fn flux(magnitude: f32, reference: f32) -> f32 {
+    /// Flux ratio of one magnitude, 10^(-0.4).
+    const FLUX_FACTOR: f32 = 0.398_107_17;
+    FLUX_FACTOR.powf(magnitude - reference)
+}
+
+/// The format of an export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ExportFormat {
+    /// Binary records, as read by the render commands.
+    Native,
+    /// Arrow IPC file with the selected Gaia columns.
+    Arrow,
+    /// Parquet file with the selected Gaia columns.
+    Parquet,
+    /// FITS binary table with the selected Gaia columns.
+    Fits,
+    /// VOTable with the selected Gaia columns.
+    #[strum(serialize = "votable")]
+    VoTable,
+}
+
+impl ExportFormat {
+    /// `.arrow`, `.feather` and `.ipc` files are written as Arrow IPC,
+    /// `.parquet` files as Parquet, `.fits` and `.fit` files as FITS, `.vot`
+    /// and `.xml` files as VOTable, and anything else as binary records.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("arrow" | "feather" | "ipc") => Self::Arrow,
+            Some("parquet") => Self::Parquet,
+            Some("fits" | "fit") => Self::Fits,
+            Some("vot" | "votable" | "xml") => Self::VoTable,
+            _ => Self::Native,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExportOptions {
+    /// Output format: native, arrow, parquet, fits or votable. Defaults to
+    /// the format matching the extension of the output.
+    #[structopt(short, long)]
+    pub format: Option<ExportFormat>,
+
+    /// Only export records matching a filter expression, like
+    /// `parallax_over_error > 5 && ruwe < 1.4`, over the columns of
+    /// `gaia_source` and `astrophysical_parameters`.
+    #[structopt(long)]
+    pub filter: Option<Filter>,
+
+    /// Also write the source id index of a native export, for the `lookup`
+    /// command.
+    #[structopt(long)]
+    pub index: bool,
+
+    #[structopt(flatten)]
+    pub sample: SampleOptions,
+
+    #[structopt(flatten)]
+    pub table: TableOptions,
+}
+
+pub async fn export(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: ExportOptions,
+) -> Result<(), Error> {
+    let output = output.as_ref();
+    let path = path.as_ref();
+    let format = options
+        .format
+        .unwrap_or_else(|| ExportFormat::from_path(output));
+
+    let filter = options
+        .filter
+        .as_ref()
+        .map(|filter| filter.bind(Column::find))
+        .transpose()?;
+
+    let mut provenance = Provenance {
+        dataset: "Gaia DR3 gaia_source and astrophysical_parameters".to_owned(),
+        path: std::fs::canonicalize(path)?.display().to_string(),
+        filters: vec![options.sample.to_string()],
+        created: chrono::Utc::now().to_rfc3339(),
+        tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+    };
+    if let Some(filter) = &filter {
+        provenance.filters.push(filter.to_string());
+    }
+
+    if format != ExportFormat::Native {
+        return tabular::export(
+            output,
+            path,
+            format,
+            Sampler::new(&options.sample)?,
+            filter.as_ref(),
+            &options.table,
+            &provenance,
+        )
+        .await;
+    }
+
+    let mut sampler = Sampler::new(&options.sample)?;
+    let data = Data::open(path).await?;
+    let mut records = data.records();
+
+    let (_, num_partitions) = records.progress();
+    let progress_bar = progress_bar(num_partitions as _);
+
+    provenance.filters.insert(
+        0,
+        "l, b, parallax, teff_gspphot and phot_g_mean_mag are known".to_owned(),
+    );
+    let mut writer = RecordWriter::create(output, &Header::new(provenance)).await?;
+    let mut sampled = vec![];
+
+    while let Some(record) = records.read_record().await? {
+        let selected = match &filter {
+            Some(filter) => {
+                let row = Row::new(&record, filter.fields())?;
+                filter.matches(|column| row.get(column).into())
+            }
+            None => true,
+        };
+
+        if let Some(exported) = Record::from_gaia(&record).filter(|_| selected) {
+            if sampler.push(SampleKey::from(&record), exported, &mut sampled) {
+                records.skip_file();
+            }
+            for record in sampled.drain(..) {
+                writer.write_record(&record).await?;
+            }
+        }
+
+        let (progress, _) = records.progress();
+        progress_bar.set_position(progress as _);
+    }
+
+    sampler.finish(&mut sampled);
+    for record in &sampled {
+        writer.write_record(record).await?;
+    }
+    writer.finish().await?;
+    if options.index {
+        write_source_index(output).await?;
+    }
+    else {
+        remove_source_index(output).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum View {
+    TopDown,
+    Sky,
+    Perspective,
+    Orthographic,
+}
+
+impl View {
+    const TOP_DOWN_RADIUS: f64 = 60.0; // in kilo parsec
+
+    /// Absolute magnitude of a star contributing a flux of 1 in the top-down
+    /// and orthographic views.
+    const TOP_DOWN_REFERENCE_MAGNITUDE: f32 = 5.0;
+
+    /// Apparent magnitude of a star contributing a flux of 1 in the sky and
+    /// perspective views.
+    const SKY_REFERENCE_MAGNITUDE: f32 = 14.0;
+}
+
+/// Selects the records drawn by the render commands, and the number of
+/// threads they are drawn on.
+#[derive(Debug, StructOpt)]
+pub struct SelectOptions {
+    /// Number of render threads. Every thread draws into its own canvases,
+    /// so the memory used grows with the number of threads. Defaults to the
+    /// number of CPUs.
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Only draw stars within a region, given as `box:x,y,z,x,y,z` with the
+    /// minimum and maximum corner, or as `sphere:x,y,z,radius`, in
+    /// heliocentric galactic coordinates in parsec. Of spatially sorted
+    /// exports only the part near the region is read.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub region: Option<Region>,
+
+    /// Only draw stars matching a filter expression, like
+    /// `parallax_over_error > 5 && apparent_magnitude < 18`, over the fields
+    /// of the export and the computed `parallax_over_error` and `distance` in
+    /// parsec. Exports don't keep other Gaia columns, so cuts on those, like
+    /// `ruwe < 1.4`, must be applied with `export --filter`.
+    #[structopt(long)]
+    pub filter: Option<Filter>,
+}
+
+impl SelectOptions {
+    fn num_jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(num_cpus::get).max(1)
+    }
+
+    /// Draws the records within the region and matching the filter, or all
+    /// records, on `--jobs` threads. Exports with the current fields are
+    /// memory-mapped, others are read with a [`RecordReader`].
+    async fn draw_parallel<S: Send>(
+        &self,
+        path: &Path,
+        init: impl Fn() -> S + Sync,
+        draw: impl Fn(&mut S, &Record) + Sync,
+    ) -> Result<Vec<S>, Error> {
+        let filter = self
+            .filter
+            .as_ref()
+            .map(|filter| filter.bind(Record::field_name))
+            .transpose()?;
+        let draw = |state: &mut S, record: &Record| {
+            if filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(|name| record.field(name)))
+            {
+                draw(state, record);
+            }
+        };
+
+        let records = RecordReader::open(path).await?;
+
+        if records.header().is_native() {
+            let export = MappedExport::open(path)?;
+            draw_mapped_parallel(&export, self.region, self.num_jobs(), init, draw).await
+        }
+        else {
+            let records = match self.region {
+                Some(region) => RecordReader::open_region(path, region).await?,
+                None => records,
+            };
+            draw_records_parallel(records, self.num_jobs(), init, draw).await
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RenderOptions {
+    #[structopt(short, long, default_value = "top-down")]
+    pub view: View,
+
+    #[structopt(short, long, default_value = "1024")]
+    pub width: u32,
+
+    /// Tone mapping operator: linear, log, asinh, reinhard or aces.
+    #[structopt(short, long, default_value = "asinh")]
+    pub tone_map: ToneMap,
+
+    /// Exposure in stops.
+    #[structopt(short, long, default_value = "0", allow_hyphen_values = true)]
+    pub exposure: f32,
+
+    /// Bits per channel of tone mapped images: 8, or 16 for PNG. OpenEXR
+    /// (`.exr`) and Radiance HDR (`.hdr`) output isn't tone mapped, but
+    /// contains the linear flux multiplied by the exposure.
+    #[structopt(long, default_value = "8")]
+    pub bit_depth: u8,
+
+    /// Field of view in degrees for the perspective view and the
+    /// stereographic and gnomonic sky projections.
+    #[structopt(long, default_value = "60")]
+    pub fov: f64,
+
+    #[structopt(flatten)]
+    pub psf: Psf,
+
+    #[structopt(flatten)]
+    pub sky: SkyOptions,
+
+    #[structopt(flatten)]
+    pub camera: CameraOptions,
+
+    #[structopt(flatten)]
+    pub orthographic: OrthographicOptions,
+
+    #[structopt(flatten)]
+    pub stats: StatisticOptions,
+
+    #[structopt(flatten)]
+    pub select: SelectOptions,
+}
+
+/// A [`View`] set up for a specific image size.
+enum Viewport {
+    TopDown {
+        width: u32,
+    },
+    Sky {
+        projector: SkyProjector,
+        observer: Observer,
+    },
+    Perspective {
+        camera: Camera,
+        observer: Observer,
+    },
+    Orthographic {
+        projector: Orthographic,
+    },
+}
+
+impl Viewport {
+    fn new(options: &RenderOptions, observer: Observer) -> Result<Self, Error> {
+        Self::with_camera(
+            options,
+            observer,
+            options.camera.direction,
+            options.camera.up,
+            options.fov,
+        )
+    }
+
+    /// Like [`Viewport::new`], but with the viewing direction, up vector and
+    /// field of view given explicitly instead of taken from the options.
+    fn with_camera(
+        options: &RenderOptions,
+        observer: Observer,
+        direction: Vector3<f64>,
+        up: Vector3<f64>,
+        fov: f64,
+    ) -> Result<Self, Error> {
+        let viewport = match options.view {
+            View::TopDown => {
+                Self::TopDown {
+                    width: options.width,
+                }
+            }
+            View::Sky => {
+                Self::Sky {
+                    projector: SkyProjector::new(&options.sky, fov, options.width),
+                    observer,
+                }
+            }
+            View::Perspective => {
+                let width = options.width;
+                let height = options.camera.height.unwrap_or(width);
+                Self::Perspective {
+                    camera: Camera::new(direction, up, fov, [width, height])?,
+                    observer,
+                }
+            }
+            View::Orthographic => {
+                let width = options.width;
+                let height = options.camera.height.unwrap_or(width);
+                Self::Orthographic {
+                    projector: Orthographic::new(
+                        &options.orthographic,
+                        direction,
+                        up,
+                        [width, height],
+                    )?,
+                }
+            }
+        };
+
+        Ok(viewport)
+    }
+
+    fn image_size(&self) -> [u32; 2] {
+        match self {
+            Self::TopDown { width } => [*width, *width],
+            Self::Sky { projector, .. } => projector.image_size(),
+            Self::Perspective { camera, .. } => camera.image_size(),
+            Self::Orthographic { projector } => projector.image_size(),
+        }
+    }
+
+    /// Position of the star in pixels and its flux, or `None` if it isn't
+    /// visible.
+    fn project(&self, record: &Record) -> Option<([f64; 2], f32)> {
+        match self {
+            Self::TopDown { width } => {
+                if record.parallax < 0.0 {
+                    return None;
+                }
+
+                let image_size = *width as f64;
+                let scale = 0.5 * image_size / View::TOP_DOWN_RADIUS;
+
+                let position = record.position();
+                let x = position.x * scale + 0.5 * image_size;
+                let y = position.y * scale + 0.5 * image_size;
+
+                let flux = flux(
+                    record.absolute_magnitude(),
+                    View::TOP_DOWN_REFERENCE_MAGNITUDE,
+                );
+                Some(([x, y], flux))
+            }
+            Self::Sky {
+                projector,
+                observer,
+            } => {
+                let observation = observer.observe(record)?;
+                let position = projector.project(&observation.direction)?;
+
+                let flux = flux(
+                    observation.apparent_magnitude,
+                    View::SKY_REFERENCE_MAGNITUDE,
+                );
+                Some((position, flux))
+            }
+            Self::Perspective { camera, observer } => {
+                let observation = observer.observe(record)?;
+                let position = camera.project(&observation.direction)?;
+
+                let flux = flux(
+                    observation.apparent_magnitude,
+                    View::SKY_REFERENCE_MAGNITUDE,
+                );
+                Some((position, flux))
+            }
+            Self::Orthographic { projector } => {
+                if record.parallax <= 0.0 {
+                    return None;
+                }
+
+                let position = Point3::from(record.distance() * record.direction());
+                let position = projector.project(&position)?;
+
+                let flux = flux(
+                    record.absolute_magnitude(),
+                    View::TOP_DOWN_REFERENCE_MAGNITUDE,
+                );
+                Some((position, flux))
+            }
+        }
+    }
+
+    fn draw_particle(&self, canvas: &mut Canvas, psf: &Psf, record: &Record) {
+        if let Some((position, flux)) = self.project(record) {
+            psf.draw(canvas, position, record.color(), flux);
+        }
+    }
+
+    fn draw_overlay<P>(&self, image: &mut ImageBuffer<P, Vec<P::Subpixel>>)
+    where
+        P: Pixel + OverlayPixel,
+    {
+        match self {
+            Self::TopDown { .. } | Self::Perspective { .. } | Self::Orthographic { .. } => {}
+            Self::Sky { projector, .. } => projector.draw_overlay(image),
+        }
+    }
+}
+
+/// Number of records handed to a render thread at once.
+const CHUNK_SIZE: usize = 16384;
+
+fn progress_bar(num_records: u64) -> ProgressBar {
+    let progress_bar = ProgressBar::new(num_records);
+    progress_bar.set_style(
+        ProgressStyle::with_template(
+            "[{pos}/{len}] {spinner:.green} {wide_bar:.cyan/blue} ({eta})",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+    progress_bar
+}
+
+/// Reads the next record, or returns `None` if the `abort_signal` fires first.
+async fn next_record(
+    records: &mut RecordReader,
+    abort_signal: impl Future<Output = ()> + Unpin,
+) -> Result<Option<Record>, Error> {
+    tokio::select! {
+        _ = abort_signal => Ok(None),
+        result = records.read_record() => result
+    }
+}
+
+/// Reads all records from `records` and draws them on `num_threads` threads.
+///
+/// Shows a progress bar and stops early when Ctrl-C is pressed, so that the
+/// partial result can still be saved.
+///
+/// Records are read in chunks of [`CHUNK_SIZE`] and handed to whichever
+/// thread is free. Every thread draws into its own state created by `init`.
+/// The states are returned, so that the caller can merge them.
+async fn draw_records_parallel<S: Send>(
+    mut records: RecordReader,
+    num_threads: usize,
+    init: impl Fn() -> S + Sync,
+    draw: impl Fn(&mut S, &Record) + Sync,
+) -> Result<Vec<S>, Error> {
+    async fn read_chunks(
+        records: &mut RecordReader,
+        sender: SyncSender<Vec<Record>>,
+    ) -> Result<(), Error> {
+        let ctrl_c = tokio::signal::ctrl_c().map(|_| ());
+        pin_mut!(ctrl_c);
+
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            let mut done = false;
+            while chunk.len() < CHUNK_SIZE {
+                let Some(record) = next_record(records, &mut ctrl_c).await?
+                else {
+                    done = true;
+                    break;
+                };
+                chunk.push(record);
+            }
+
+            // the send only fails if all threads are gone.
+            if (!chunk.is_empty() && sender.send(chunk).is_err()) || done {
+                return Ok(());
+            }
+        }
+    }
+
+    let num_threads = num_threads.max(1);
+    let progress_bar = progress_bar(records.num_records());
+    let runtime = tokio::runtime::Handle::current();
+
+    let t_start = Instant::now();
+
+    // the reader blocks whenever all threads are busy, so this must not run on
+    // the async runtime.
+    let (states, result) = tokio::task::block_in_place(|| {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(2 * num_threads);
+        let receiver = Mutex::new(receiver);
+
+        std::thread::scope(|scope| {
+            let threads = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut state = init();
+                        loop {
+                            let chunk: Result<Vec<Record>, _> = receiver.lock().unwrap().recv();
+                            let Ok(chunk) = chunk
+                            else {
+                                break;
+                            };
+                            for record in &chunk {
+                                draw(&mut state, record);
+                            }
+                            progress_bar.inc(chunk.len() as u64);
+                        }
+                        state
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let result = runtime.block_on(read_chunks(&mut records, sender));
+
+            let states = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>();
+            (states, result)
+        })
+    });
+    result?;
+
+    let time = t_start.elapsed();
+    tracing::info!("rendering took {} s", time.as_secs());
+
+    Ok(states)
+}
+
+/// Like [`draw_records_parallel`], but for a memory-mapped export. The
+/// threads take chunks of records directly from the mapping, so no reader is
+/// needed.
+async fn draw_mapped_parallel<S: Send>(
+    export: &MappedExport,
+    region: Option<Region>,
+    num_threads: usize,
+    init: impl Fn() -> S + Sync,
+    draw: impl Fn(&mut S, &Record) + Sync,
+) -> Result<Vec<S>, Error> {
+    let chunks = export
+        .ranges(region.as_ref())?
+        .into_iter()
+        .flat_map(|range| {
+            let end = range.end;
+            range
+                .step_by(CHUNK_SIZE)
+                .map(move |start| start..(start + CHUNK_SIZE).min(end))
+        })
+        .collect::<Vec<_>>();
+
+    let num_records = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+    let progress_bar = progress_bar(num_records);
+    let records = export.records();
+    let next_chunk = AtomicUsize::new(0);
+
+    let aborted = Arc::new(AtomicBool::new(false));
+    let ctrl_c = tokio::spawn({
+        let aborted = aborted.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                aborted.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let t_start = Instant::now();
+
+    // the threads don't await anything, but block the runtime until they are
+    // done.
+    let states = tokio::task::block_in_place(|| {
+        std::thread::scope(|scope| {
+            let threads = (0..num_threads.max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut state = init();
+                        while !aborted.load(Ordering::Relaxed) {
+                            let Some(chunk) =
+                                chunks.get(next_chunk.fetch_add(1, Ordering::Relaxed))
+                            else {
+                                break;
+                            };
+                            for raw in &records[chunk.clone()] {
+                                let record = raw.to_record();
+                                if region
+                                    .as_ref()
+                                    .is_none_or(|region| region.contains_record(&record))
+                                {
+                                    draw(&mut state, &record);
+                                }
+                            }
+                            progress_bar.inc(chunk.len() as u64);
+                        }
+                        state
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+    });
+    ctrl_c.abort();
+
+    let time = t_start.elapsed();
+    tracing::info!("rendering took {} s", time.as_secs());
+
+    Ok(states)
+}
+
+pub async fn render(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: RenderOptions,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let observer = options.camera.observer.resolve(path).await?;
+
+    let viewport = Viewport::new(&options, observer)?;
+    let output = output.as_ref();
+    let format = OutputFormat::from_path(output, options.bit_depth)?;
+
+    if let Some(statistic) = options.stats.statistic {
+        ensure!(
+            format == OutputFormat::Rgb8,
+            "statistic maps are only written with 8 bits per channel"
+        );
+        return render_statistic(output, path, &options, &viewport, statistic).await;
+    }
+
+    let [width, height] = viewport.image_size();
+
+    let mut canvases = options
+        .select
+        .draw_parallel(
+            path,
+            || Canvas::new(width, height),
+            |canvas, record| viewport.draw_particle(canvas, &options.psf, record),
+        )
+        .await?
+        .into_iter();
+
+    let mut canvas = canvases.next().unwrap();
+    for other in canvases {
+        canvas.merge(&other);
+    }
+
+    tracing::info!("writing image: {}", output.display());
+
+    match format {
+        OutputFormat::Rgb8 => {
+            let mut image = canvas.tone_map(options.tone_map, options.exposure);
+            viewport.draw_overlay(&mut image);
+            image.save(output)?;
+        }
+        OutputFormat::Rgb16 => {
+            let mut image = canvas.tone_map_16(options.tone_map, options.exposure);
+            viewport.draw_overlay(&mut image);
+            image.save(output)?;
+        }
+        OutputFormat::Exr | OutputFormat::Hdr => {
+            let mut image = canvas.linear(options.exposure);
+            viewport.draw_overlay(&mut image);
+            if format == OutputFormat::Exr {
+                image.save(output)?;
+            }
+            else {
+                save_hdr(&image, output)?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use color_eyre::eyre::eyre;
+use nalgebra::{
+    Point3,
+    Vector3,
+};
+use structopt::StructOpt;
+
+use crate::{
+    utils::parse_range,
+    Error,
+};
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct OrthographicOptions {
+    /// Half the width of the orthographic view in kilo parsec.
+    #[structopt(long, default_value = "60")]
+    pub radius: f64,
+
+    /// Only draw stars whose depth along the viewing direction, measured from
+    /// the origin, is within `min,max` kilo parsec.
+    #[structopt(long, allow_hyphen_values = true, parse(try_from_str = parse_range))]
+    pub slab: Option<[f64; 2]>,
+
+    /// Center of the orthographic view: sun or galactic-center.
+    #[structopt(long, default_value = "sun")]
+    pub origin: Origin,
+}
+
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Origin {
+    Sun,
+    GalacticCenter,
+}
+
+impl Origin {
+    /// Distance of the sun from the galactic center in kilo parsec (GRAVITY
+    /// Collaboration 2018).
+    const GALACTIC_CENTER_DISTANCE: f64 = 8.122;
+
+    /// Height of the sun above the galactic plane in kilo parsec (Bennett &
+    /// Bovy 2019).
+    const SUN_HEIGHT: f64 = 0.0208;
+
+    /// Heliocentric galactic coordinates of the origin in kilo parsec.
+    fn position(&self) -> Vector3<f64> {
+        match self {
+            Self::Sun => Vector3::zeros(),
+            Self::GalacticCenter => {
+                Vector3::new(Self::GALACTIC_CENTER_DISTANCE, 0.0, -Self::SUN_HEIGHT)
+            }
+        }
+    }
+}
+
+/// Parallel projection along a viewing direction.
+///
+/// With the direction `0,0,-1` and up vector `1,0,0` this looks down from the
+/// north galactic pole, with `1,0,0` and `0,0,1` it looks at the disk
+/// edge-on towards the galactic center.
+pub struct Orthographic {
+    right: Vector3<f64>,
+    up: Vector3<f64>,
+    forward: Vector3<f64>,
+    origin: Vector3<f64>,
+    slab: Option<[f64; 2]>,
+    /// Pixels per kilo parsec.
+    scale: f64,
+    width: u32,
+    height: u32,
+}
+
+impl Orthographic {
+    /// `direction` and `up` are given in galactic coordinates. If the
+    /// direction is parallel to the up vector, like when looking at a galactic
+    /// pole with the default up vector, up points towards the galactic center
+    /// instead.
+    pub fn new(
+        options: &OrthographicOptions,
+        direction: Vector3<f64>,
+        up: Vector3<f64>,
+        [width, height]: [u32; 2],
+    ) -> Result<Self, Error> {
+        let forward = direction
+            .try_normalize(f64::EPSILON)
+            .ok_or_else(|| eyre!("viewing direction must not be zero"))?;
+        let right = [up, Vector3::x(), Vector3::y()]
+            .iter()
+            .find_map(|up| forward.cross(up).try_normalize(1e-9))
+            .unwrap();
+        let up = right.cross(&forward);
+
+        Ok(Self {
+            right,
+            up,
+            forward,
+            origin: options.origin.position(),
+            slab: options.slab,
+            scale: 0.5 * width as f64 / options.radius,
+            width,
+            height,
+        })
+    }
+
+    pub fn image_size(&self) -> [u32; 2] {
+        [self.width, self.height]
+    }
+
+    /// Projects a heliocentric position in kilo parsec to pixel coordinates,
+    /// or returns `None` if it lies outside of the slab.
+    pub fn project(&self, position: &Point3<f64>) -> Option<[f64; 2]> {
+        let offset = position.coords - self.origin;
+
+        if let Some([min, max]) = self.slab {
+            let depth = offset.dot(&self.forward);
+            if depth < min || depth > max {
+                return None;
+            }
+        }
+
+        Some([
+            0.5 * self.width as f64 + offset.dot(&self.right) * self.scale,
+            0.5 * self.height as f64 - offset.dot(&self.up) * self.scale,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_at_the_pole_with_the_default_up_vector() {
+        let options = OrthographicOptions {
+            radius: 1.0,
+            slab: None,
+            origin: Origin::Sun,
+        };
+        let projector =
+            Orthographic::new(&options, -Vector3::z(), Vector3::z(), [100, 100]).unwrap();
+
+        let [x, y] = projector.project(&Point3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!((x - 50.0).abs() < 1e-9 && (y - 0.0).abs() < 1e-9);
+
+        assert!(Orthographic::new(&options, Vector3::zeros(), Vector3::z(), [100, 100]).is_err());
+    }
+}
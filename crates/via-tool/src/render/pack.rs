@@ -0,0 +1,434 @@
+//! Compact star packs for distribution with the client.
+//!
+//! Stars are grouped into cubic chunks, and within a chunk only what the
+//! client draws is kept: the quantized position, absolute magnitude and
+//! effective temperature. The format is described in [`via_protocol::pack`],
+//! which also reads packs.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+};
+
+use color_eyre::eyre::ensure;
+use nalgebra::{
+    Point3,
+    Vector3,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use structopt::StructOpt;
+use tokio::{
+    fs::File,
+    io::{
+        AsyncWriteExt,
+        BufWriter,
+    },
+};
+use via_protocol::pack::{
+    self as protocol,
+    ChunkEntry,
+    QuantizedRange,
+    MAGIC,
+    PRELUDE_SIZE,
+    VERSION,
+};
+
+use super::{
+    format::Provenance,
+    progress_bar,
+    spatial::spread_bits,
+    RecordReader,
+};
+use crate::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct PackOptions {
+    /// Edge length of a chunk in parsec.
+    #[structopt(long, default_value = "256")]
+    pub chunk_size: f64,
+
+    /// Bits per axis of the position within a chunk, at most 21.
+    #[structopt(long, default_value = "16")]
+    pub position_bits: u32,
+
+    /// Stars further away from the sun than this, in kilo parsec, are left
+    /// out.
+    #[structopt(long, default_value = "100")]
+    pub max_distance: f64,
+
+    /// zstd compression level of the chunks.
+    #[structopt(long, default_value = "19")]
+    pub compression_level: i32,
+}
+
+/// Absolute magnitudes in steps of 1/8 mag.
+const MAGNITUDE: QuantizedRange = QuantizedRange {
+    min: -10.0,
+    max: 21.875,
+};
+
+/// Natural logarithm of the temperature, from 1000 K to 50000 K.
+const LOG_TEMPERATURE: QuantizedRange = QuantizedRange {
+    min: 6.907755278982137,
+    max: 10.819778284410283,
+};
+
+/// The header of a pack, with the provenance of the export it was packed
+/// from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackHeader {
+    #[serde(flatten)]
+    pub layout: protocol::PackHeader,
+    pub provenance: Provenance,
+}
+
+/// Chunk and Morton code of a heliocentric position in parsec.
+fn quantize(layout: &protocol::PackHeader, position: &Point3<f64>) -> ([i32; 3], u64) {
+    let cells = 1u64 << layout.position_bits;
+    let mut chunk = [0; 3];
+    let mut morton = 0;
+    for (axis, coordinate) in position.iter().enumerate() {
+        let scaled = coordinate / layout.chunk_size;
+        chunk[axis] = scaled.floor() as i32;
+        let cell = ((scaled - scaled.floor()) * cells as f64) as u64;
+        morton |= spread_bits(cell.min(cells - 1)) << (2 - axis);
+    }
+    (chunk, morton)
+}
+
+/// A star as stored in a chunk.
+#[derive(Clone, Copy, Debug)]
+struct PackedStar {
+    morton: u64,
+    magnitude: u8,
+    temperature: u8,
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+/// Uncompressed data of a chunk.
+fn encode_chunk(stars: &mut [PackedStar]) -> Vec<u8> {
+    stars.sort_unstable_by_key(|star| star.morton);
+
+    let mut data = Vec::with_capacity(4 * stars.len());
+    let mut previous = 0;
+    for star in stars.iter() {
+        write_varint(&mut data, star.morton - previous);
+        previous = star.morton;
+    }
+    data.extend(stars.iter().map(|star| star.magnitude));
+    data.extend(stars.iter().map(|star| star.temperature));
+    data
+}
+
+/// Packs the stars of an export for the client.
+pub async fn pack(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: PackOptions,
+) -> Result<(), Error> {
+    ensure!(options.chunk_size > 0.0, "the chunk size must be positive");
+    ensure!(
+        (1..=21).contains(&options.position_bits),
+        "the position bits must be between 1 and 21"
+    );
+
+    let mut records = RecordReader::open(path).await?;
+    let mut header = PackHeader {
+        layout: protocol::PackHeader {
+            chunk_size: options.chunk_size,
+            position_bits: options.position_bits,
+            max_position_error: 0.0,
+            magnitude: MAGNITUDE,
+            log_temperature: LOG_TEMPERATURE,
+            num_stars: 0,
+            num_chunks: 0,
+        },
+        provenance: records.header().provenance.clone(),
+    };
+    header.layout.max_position_error = 3f64.sqrt() / 2.0 * header.layout.cell_size();
+    header
+        .provenance
+        .filters
+        .push(format!("packed within {} kpc", options.max_distance));
+
+    tracing::info!("quantizing stars");
+    let progress = progress_bar(records.num_records());
+    let mut chunks: HashMap<[i32; 3], Vec<PackedStar>> = HashMap::new();
+    let mut max_error: f64 = 0.0;
+    let mut num_skipped = 0;
+
+    while let Some(record) = records.read_record().await? {
+        progress.set_position(records.num_read());
+
+        let position = record.heliocentric_position();
+        if !(record.parallax > 0.0 && position.coords.norm() <= 1000.0 * options.max_distance) {
+            num_skipped += 1;
+            continue;
+        }
+
+        let layout = &header.layout;
+        let (chunk, morton) = quantize(layout, &position);
+        let decoded = Point3::from(layout.position(chunk, morton));
+        max_error = max_error.max((decoded - position).norm());
+
+        chunks.entry(chunk).or_default().push(PackedStar {
+            morton,
+            magnitude: layout
+                .magnitude
+                .quantize(record.absolute_magnitude() as f64),
+            temperature: layout.log_temperature.quantize((record.t_eff as f64).ln()),
+        });
+    }
+    progress.finish();
+
+    if num_skipped > 0 {
+        tracing::warn!(
+            num_skipped,
+            "skipped stars without a positive parallax or too far away"
+        );
+    }
+
+    // nearest chunks first
+    let mut chunks = chunks.into_iter().collect::<Vec<_>>();
+    let distance =
+        |chunk: &[i32; 3]| Vector3::from_fn(|axis, _| chunk[axis] as f64 + 0.5).norm_squared();
+    chunks.sort_by(|(a, _), (b, _)| distance(a).total_cmp(&distance(b)));
+
+    tracing::info!(num_chunks = chunks.len(), "compressing chunks");
+    let progress = progress_bar(chunks.len() as u64);
+    let compressed = tokio::task::block_in_place(|| {
+        chunks
+            .iter_mut()
+            .map(|(_, stars)| {
+                let data = zstd::bulk::compress(&encode_chunk(stars), options.compression_level);
+                progress.inc(1);
+                data
+            })
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    progress.finish();
+
+    header.layout.num_stars = chunks.iter().map(|(_, stars)| stars.len() as u64).sum();
+    header.layout.num_chunks = chunks.len() as u64;
+    let header_json = serde_json::to_vec(&header)?;
+
+    let mut offset = (PRELUDE_SIZE + header_json.len() + ChunkEntry::SIZE * chunks.len()) as u64;
+    let mut writer = BufWriter::new(File::create(output).await?);
+    writer.write_all(&MAGIC).await?;
+    writer.write_u16(VERSION).await?;
+    writer.write_u32(header_json.len().try_into()?).await?;
+    writer.write_all(&header_json).await?;
+
+    for ((chunk, stars), data) in chunks.iter().zip(&compressed) {
+        let entry = ChunkEntry {
+            chunk: *chunk,
+            num_stars: stars.len().try_into()?,
+            offset,
+            length: data.len().try_into()?,
+        };
+        writer.write_all(&entry.to_bytes()).await?;
+        offset += data.len() as u64;
+    }
+    for data in &compressed {
+        writer.write_all(data).await?;
+    }
+    writer.flush().await?;
+
+    let size = offset as f64;
+    tracing::info!(
+        num_stars = header.layout.num_stars,
+        bytes_per_star = size / header.layout.num_stars.max(1) as f64,
+        max_error,
+        max_position_error = header.layout.max_position_error,
+        "packed"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use via_protocol::pack::Pack;
+
+    use super::*;
+    use crate::{
+        gaia::HealPixRange,
+        render::{
+            format::{
+                Header,
+                RecordWriter,
+            },
+            Record,
+        },
+    };
+
+    fn read_varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let (&byte, rest) = data.split_first().unwrap();
+            *data = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn layout() -> protocol::PackHeader {
+        let mut layout = protocol::PackHeader {
+            chunk_size: 256.0,
+            position_bits: 12,
+            max_position_error: 0.0,
+            magnitude: MAGNITUDE,
+            log_temperature: LOG_TEMPERATURE,
+            num_stars: 0,
+            num_chunks: 0,
+        };
+        layout.max_position_error = 3f64.sqrt() / 2.0 * layout.cell_size();
+        layout
+    }
+
+    #[test]
+    fn positions_are_within_the_error_bound() {
+        let layout = layout();
+        for i in 0..1000 {
+            let i = i as f64;
+            let position = Point3::new(
+                1000.0 * (0.37 * i).sin(),
+                -300.0 + 1.7 * i,
+                (i * 12.345) % 700.0 - 350.0,
+            );
+            let (chunk, morton) = quantize(&layout, &position);
+            let error = (Point3::from(layout.position(chunk, morton)) - position).norm();
+            assert!(error <= layout.max_position_error, "{position}: {error}");
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let layout = layout();
+        let mut stars = (0..100)
+            .map(|i| {
+                PackedStar {
+                    morton: (i * 7919) % (1 << 36),
+                    magnitude: layout.magnitude.quantize(i as f64 * 0.2 - 5.0),
+                    temperature: layout
+                        .log_temperature
+                        .quantize((3000.0 + 50.0 * i as f64).ln()),
+                }
+            })
+            .collect::<Vec<_>>();
+        let data = encode_chunk(&mut stars);
+
+        let mut rest = &data[..];
+        let mut morton = 0;
+        for star in &stars {
+            morton += read_varint(&mut rest);
+            assert_eq!(morton, star.morton);
+        }
+        let (magnitudes, temperatures) = rest.split_at(stars.len());
+        assert_eq!(temperatures.len(), stars.len());
+
+        for (i, star) in stars.iter().enumerate() {
+            assert_eq!(star.magnitude, magnitudes[i]);
+            assert_eq!(star.temperature, temperatures[i]);
+        }
+
+        let magnitude = layout.magnitude.dequantize(layout.magnitude.quantize(4.3));
+        assert!((magnitude - 4.3).abs() <= 1.0 / 16.0);
+        let temperature = layout
+            .log_temperature
+            .dequantize(layout.log_temperature.quantize(5772f64.ln()))
+            .exp();
+        assert!((temperature / 5772.0 - 1.0).abs() < 0.01);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decodes_packed_exports() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let output = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let records = (0..500)
+            .map(|i| {
+                let i = i as f64;
+                Record {
+                    source_id: i as u64,
+                    healpix_range: HealPixRange { start: 0, end: 1 },
+                    parallax: 0.2 + (0.7 * i).sin().abs() * 20.0,
+                    parallax_error: 0.1,
+                    longitude: (i * 37.0) % 360.0,
+                    latitude: (i * 13.0) % 180.0 - 90.0,
+                    t_eff: (3000.0 + 20.0 * i) as f32,
+                    apparent_magnitude: (5.0 + (i % 10.0)) as f32,
+                    pm_longitude: 0.0,
+                    pm_latitude: 0.0,
+                    bp_rp: 0.0,
+                    metallicity: 0.0,
+                    extinction: 0.0,
+                    radial_velocity: 0.0,
+                    non_single_star: 0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut writer = RecordWriter::create(&path, &Header::new(Provenance::default()))
+            .await
+            .unwrap();
+        for record in &records {
+            writer.write_record(record).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let options = PackOptions {
+            chunk_size: 256.0,
+            position_bits: 8,
+            max_distance: 100.0,
+            compression_level: 3,
+        };
+        pack(&output, &path, options).await.unwrap();
+
+        let data = std::fs::read(&output).unwrap();
+        let pack = Pack::parse(&data).unwrap();
+        let layout = pack.header();
+        assert_eq!(layout.num_stars, records.len() as u64);
+
+        let mut stars = vec![];
+        for entry in pack.chunks() {
+            stars.extend(pack.decode_chunk(entry).unwrap());
+        }
+        assert_eq!(stars.len(), records.len());
+
+        // every star decodes close to a record with its magnitude and
+        // temperature, and every record to a star
+        let distance = |record: &Record, star: &protocol::Star| {
+            (record.heliocentric_position() - Point3::from(star.position)).norm()
+        };
+        for star in &stars {
+            assert!(
+                records.iter().any(|record| {
+                    distance(record, star) <= layout.max_position_error
+                        && (star.t_eff / record.t_eff - 1.0).abs() < 0.01
+                        && (star.absolute_magnitude - record.absolute_magnitude()).abs()
+                            <= 1.0 / 16.0
+                }),
+                "{star:?}"
+            );
+        }
+        for record in &records {
+            assert!(stars
+                .iter()
+                .any(|star| distance(record, star) <= layout.max_position_error));
+        }
+    }
+}
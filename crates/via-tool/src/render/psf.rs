@@ -0,0 +1,293 @@
+use palette::LinSrgb;
+use structopt::StructOpt;
+
+use super::canvas::Canvas;
+
+/// Point spread function used to draw stars.
+///
+/// Fluxes are relative to a star of the view's reference magnitude, before
+/// the exposure is applied.
+#[derive(Clone, Debug, StructOpt)]
+pub struct Psf {
+    /// Shape of the point spread function: point, gaussian or airy.
+    #[structopt(long, default_value = "gaussian")]
+    pub psf: PsfShape,
+
+    /// Standard deviation of the PSF in pixels for stars with a flux of 1 or
+    /// less. For the Airy disk this is the standard deviation of the Gaussian
+    /// with the same FWHM.
+    #[structopt(long, default_value = "0.7")]
+    pub psf_sigma: f32,
+
+    /// The PSF width grows with `flux^psf_growth` for stars brighter than a
+    /// flux of 1.
+    #[structopt(long, default_value = "0.25")]
+    pub psf_growth: f32,
+
+    /// Maximum radius of the PSF profile in pixels. The profile of brighter
+    /// stars doesn't grow any further.
+    #[structopt(long, default_value = "64")]
+    pub psf_max_radius: f32,
+
+    /// Stars with at least this flux get diffraction spikes.
+    #[structopt(long)]
+    pub spikes: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum PsfShape {
+    /// A single pixel per star.
+    Point,
+    Gaussian,
+    Airy,
+}
+
+impl PsfShape {
+    /// Radius in units of sigma at which the profile is cut off.
+    fn extent(&self) -> f32 {
+        match self {
+            Self::Point => 0.0,
+            Self::Gaussian => 3.0,
+            // covers the first three rings
+            Self::Airy => 7.5,
+        }
+    }
+
+    /// Unnormalized intensity at distance `r` from the center.
+    fn profile(&self, r: f32, sigma: f32) -> f32 {
+        match self {
+            Self::Point => 1.0,
+            Self::Gaussian => (-0.5 * (r / sigma).powi(2)).exp(),
+            Self::Airy => {
+                // the Airy disk's FWHM is 1.029 lambda/D, the Gaussian's 2.355 sigma.
+                const LAMBDA_OVER_D: f32 = 2.355 / 1.029;
+                let x = std::f64::consts::PI * r as f64 / (LAMBDA_OVER_D * sigma) as f64;
+                if x < 1e-6 {
+                    1.0
+                }
+                else {
+                    (2.0 * bessel_j1(x) / x).powi(2) as f32
+                }
+            }
+        }
+    }
+}
+
+impl Psf {
+    /// Fraction of the flux at distance 1 pixel along a diffraction spike. The
+    /// intensity falls off with the square of the distance.
+    const SPIKE_STRENGTH: f32 = 0.01;
+
+    /// Spikes are drawn until their intensity falls below this flux.
+    const SPIKE_CUTOFF: f32 = 1e-3;
+
+    fn sigma(&self, flux: f32) -> f32 {
+        let sigma = self.psf_sigma * flux.max(1.0).powf(self.psf_growth);
+        let extent = self.psf.extent();
+        if extent > 0.0 {
+            sigma.min(self.psf_max_radius.max(0.0) / extent)
+        }
+        else {
+            sigma
+        }
+    }
+
+    fn spike_length(&self, flux: f32) -> f32 {
+        if self.spikes.is_some_and(|threshold| flux >= threshold) {
+            (flux * Self::SPIKE_STRENGTH / Self::SPIKE_CUTOFF).sqrt()
+        }
+        else {
+            0.0
+        }
+    }
+
+    /// Distance in pixels from its center up to which a star is drawn.
+    pub fn radius(&self, flux: f32) -> f64 {
+        let profile = self.psf.extent() * self.sigma(flux);
+        profile.max(self.spike_length(flux)).ceil() as f64
+    }
+
+    /// Draws a star at sub-pixel position `[x, y]`.
+    pub fn draw(&self, canvas: &mut Canvas, [x, y]: [f64; 2], color: LinSrgb, flux: f32) {
+        // stars further off the canvas than their radius don't touch it, and
+        // their position might not even fit into pixel coordinates.
+        let radius = self.radius(flux);
+        let on_canvas =
+            |position: f64, size: u32| position >= -radius && position <= size as f64 + radius;
+        if !on_canvas(x, canvas.width()) || !on_canvas(y, canvas.height()) {
+            return;
+        }
+
+        if self.psf == PsfShape::Point {
+            canvas.add(x.floor() as i64, y.floor() as i64, color, flux);
+        }
+        else {
+            self.draw_profile(canvas, [x, y], color, flux);
+        }
+
+        self.draw_spikes(canvas, [x, y], color, flux);
+    }
+
+    fn draw_profile(&self, canvas: &mut Canvas, [x, y]: [f64; 2], color: LinSrgb, flux: f32) {
+        let sigma = self.sigma(flux);
+        let radius = (self.psf.extent() * sigma).ceil() as i64;
+        let center_x = x.floor() as i64;
+        let center_y = y.floor() as i64;
+
+        // the part of the profile that lies on the canvas. The bounds saturate,
+        // as stars with an infinite flux have endless spikes and are drawn
+        // wherever they are.
+        let min_x = center_x.saturating_sub(radius).max(0);
+        let max_x = center_x
+            .saturating_add(radius)
+            .min(canvas.width() as i64 - 1);
+        let min_y = center_y.saturating_sub(radius).max(0);
+        let max_y = center_y
+            .saturating_add(radius)
+            .min(canvas.height() as i64 - 1);
+        if min_x > max_x || min_y > max_y {
+            return;
+        }
+
+        // distance of the pixel's center from the star
+        let distance = |pixel_x: i64, pixel_y: i64| {
+            let dx = pixel_x as f64 + 0.5 - x;
+            let dy = pixel_y as f64 + 0.5 - y;
+            (dx * dx + dy * dy).sqrt() as f32
+        };
+
+        // normalize over the sampled pixels, so that the total flux is preserved
+        // regardless of the sub-pixel position. This includes the pixels off the
+        // canvas, or stars at the edge would get brighter, but is bounded by
+        // `psf_max_radius`.
+        let mut total = 0.0;
+        for pixel_y in center_y - radius..=center_y + radius {
+            for pixel_x in center_x - radius..=center_x + radius {
+                total += self.psf.profile(distance(pixel_x, pixel_y), sigma);
+            }
+        }
+        if total <= 0.0 {
+            return;
+        }
+
+        for pixel_y in min_y..=max_y {
+            for pixel_x in min_x..=max_x {
+                let weight = self.psf.profile(distance(pixel_x, pixel_y), sigma) / total;
+                canvas.add(pixel_x, pixel_y, color, flux * weight);
+            }
+        }
+    }
+
+    fn draw_spikes(&self, canvas: &mut Canvas, [x, y]: [f64; 2], color: LinSrgb, flux: f32) {
+        let center_x = x.floor() as i64;
+        let center_y = y.floor() as i64;
+
+        let max_length = std::cmp::max(canvas.width(), canvas.height()) as f32;
+        let length = self.spike_length(flux).min(max_length) as i64;
+
+        for d in 1..=length {
+            let intensity = flux * Self::SPIKE_STRENGTH / (d * d) as f32;
+            canvas.add(center_x.saturating_add(d), center_y, color, intensity);
+            canvas.add(center_x.saturating_sub(d), center_y, color, intensity);
+            canvas.add(center_x, center_y.saturating_add(d), color, intensity);
+            canvas.add(center_x, center_y.saturating_sub(d), color, intensity);
+        }
+    }
+}
+
+/// Bessel function of the first kind of order one.
+///
+/// Rational approximation from Numerical Recipes (`bessj1`).
+fn bessel_j1(x: f64) -> f64 {
+    let ax = x.abs();
+
+    if ax < 8.0 {
+        let y = x * x;
+        let numerator = x
+            * (72362614232.0
+                + y * (-7895059235.0
+                    + y * (242396853.1
+                        + y * (-2972611.439 + y * (15704.48260 + y * (-30.16036606))))));
+        let denominator = 144725228442.0
+            + y * (2300535178.0
+                + y * (18583304.74 + y * (99447.43394 + y * (376.9991397 + y * 1.0))));
+        numerator / denominator
+    }
+    else {
+        let z = 8.0 / ax;
+        let y = z * z;
+        let xx = ax - 2.356194491;
+        let p = 1.0
+            + y * (0.183105e-2
+                + y * (-0.3516396496e-4 + y * (0.2457520174e-5 + y * (-0.240337019e-6))));
+        let q = 0.04687499995
+            + y * (-0.2002690873e-3
+                + y * (0.8449199096e-5 + y * (-0.88228987e-6 + y * 0.105787412e-6)));
+        let j1 = (std::f64::consts::FRAC_2_PI / ax).sqrt() * (xx.cos() * p - z * xx.sin() * q);
+        if x < 0.0 {
+            -j1
+        }
+        else {
+            j1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn psf() -> Psf {
+        Psf {
+            psf: PsfShape::Gaussian,
+            psf_sigma: 0.7,
+            psf_growth: 0.25,
+            psf_max_radius: 16.0,
+            spikes: None,
+        }
+    }
+
+    #[test]
+    fn caps_radius() {
+        assert_eq!(psf().radius(1.0), 3.0);
+        assert_eq!(psf().radius(1e30), 16.0);
+    }
+
+    #[test]
+    fn preserves_flux_on_the_canvas() {
+        let mut canvas = Canvas::new(64, 64);
+        psf().draw(&mut canvas, [32.3, 32.6], LinSrgb::new(1.0, 1.0, 1.0), 1e6);
+        // stars off the canvas are skipped
+        psf().draw(&mut canvas, [-1e9, 1e9], LinSrgb::new(1.0, 1.0, 1.0), 1e30);
+
+        let total = canvas.image.pixels().map(|pixel| pixel.0[0]).sum::<f32>();
+        assert!((total / 1e6 - 1.0).abs() < 1e-4, "{total}");
+    }
+
+    #[test]
+    fn skips_stars_far_off_the_canvas() {
+        let mut canvas = Canvas::new(16, 16);
+        let positions = [
+            [f64::INFINITY, 0.0],
+            [1e300, 0.0],
+            [0.0, -1e300],
+            [f64::NEG_INFINITY, f64::INFINITY],
+        ];
+
+        for shape in [PsfShape::Point, PsfShape::Gaussian, PsfShape::Airy] {
+            let psf = Psf {
+                psf: shape,
+                spikes: Some(1.0),
+                ..psf()
+            };
+            for position in positions {
+                for flux in [1.0, 1e30, f32::INFINITY] {
+                    psf.draw(&mut canvas, position, LinSrgb::new(1.0, 1.0, 1.0), flux);
+                }
+            }
+        }
+
+        assert!(canvas.image.pixels().all(|pixel| pixel.0 == [0.0; 3]));
+    }
+}
@@ -0,0 +1,379 @@
+//! Sampling of Gaia records for smaller exports.
+//!
+//! The records arrive ordered by source id, which starts with the HEALPix
+//! pixel of order 12. So every pixel of a lower order, or cell, is a
+//! contiguous run of records, and the sample of a cell is complete as soon as
+//! the first record of the next cell arrives.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    fmt::{
+        self,
+        Display,
+    },
+    str::FromStr,
+};
+
+use color_eyre::eyre::{
+    bail,
+    ensure,
+    eyre,
+};
+use structopt::StructOpt;
+
+use crate::{
+    gaia,
+    utils::healpix,
+    Error,
+};
+
+/// Number of sources in Gaia DR3. `random_index` is a random permutation of
+/// `0..NUM_SOURCES`.
+const NUM_SOURCES: u64 = 1_811_709_771;
+
+#[derive(Debug, StructOpt)]
+pub struct SampleOptions {
+    /// How records are sampled: `first:N` keeps the first N records of every
+    /// partition file, `reservoir:N` a uniform sample of N records per cell,
+    /// `brightest:N` the N brightest records in G per cell, `fraction:F` the
+    /// fraction F of all sources by their `random_index`, and `all` every
+    /// record.
+    #[structopt(long, default_value = "first:1024")]
+    pub sample: Sampling,
+
+    /// Deprecated alias for `--sample first:N`.
+    #[structopt(short, long, conflicts_with = "sample")]
+    pub limit_per_file: Option<usize>,
+
+    /// HEALPix order of the cells for `reservoir` and `brightest` sampling.
+    #[structopt(long, default_value = "6")]
+    pub cell_order: u8,
+
+    /// Seed for `reservoir` sampling, so that samples can be reproduced.
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    All,
+    First(usize),
+    Reservoir(usize),
+    Brightest(usize),
+    Fraction(f64),
+}
+
+impl SampleOptions {
+    /// The sampling strategy, with `--limit-per-file` taking the place of
+    /// `--sample`.
+    pub fn sampling(&self) -> Sampling {
+        self.limit_per_file.map_or(self.sample, Sampling::First)
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Self::All);
+        }
+
+        let (kind, value) = s.split_once(':').ok_or_else(|| {
+            eyre!("expected first:N, reservoir:N, brightest:N or fraction:F: {s}")
+        })?;
+
+        let sampling = match kind {
+            "first" => Self::First(value.parse()?),
+            "reservoir" => Self::Reservoir(value.parse()?),
+            "brightest" => Self::Brightest(value.parse()?),
+            "fraction" => {
+                let fraction = value.parse()?;
+                ensure!(
+                    fraction > 0.0 && fraction <= 1.0,
+                    "the fraction must be in (0, 1]: {s}"
+                );
+                Self::Fraction(fraction)
+            }
+            _ => bail!("unknown sampling: {kind}"),
+        };
+        ensure!(
+            !matches!(
+                sampling,
+                Self::First(0) | Self::Reservoir(0) | Self::Brightest(0)
+            ),
+            "the sample size must be positive: {s}"
+        );
+
+        Ok(sampling)
+    }
+}
+
+/// What the sampling strategies need to know about a record.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleKey {
+    /// Start of the partition file.
+    pub partition: u32,
+    pub source_id: u64,
+    /// G magnitude
+    pub magnitude: Option<f32>,
+    pub random_index: Option<i64>,
+}
+
+impl From<&gaia::Record> for SampleKey {
+    fn from(record: &gaia::Record) -> Self {
+        Self {
+            partition: record.healpix_range.start,
+            source_id: record.gaia_source.source_id,
+            magnitude: record.gaia_source.phot_g_mean_mag,
+            random_index: record.gaia_source.random_index,
+        }
+    }
+}
+
+/// A candidate for the brightest records. The faintest is on top of the
+/// heap, so it's the one that's replaced.
+struct Candidate<T> {
+    magnitude: f32,
+    item: T,
+}
+
+impl<T> PartialEq for Candidate<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Candidate<T> {}
+
+impl<T> PartialOrd for Candidate<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Candidate<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.magnitude.total_cmp(&other.magnitude)
+    }
+}
+
+/// Samples a stream of records ordered by source id.
+pub struct Sampler<T> {
+    sampling: Sampling,
+    cell_order: u8,
+    rng: fastrand::Rng,
+    /// The current cell, or partition file for `first` sampling.
+    cell: Option<u64>,
+    /// Records offered in the current cell.
+    num_offered: u64,
+    reservoir: Vec<T>,
+    brightest: BinaryHeap<Candidate<T>>,
+}
+
+impl<T> Sampler<T> {
+    pub fn new(options: &SampleOptions) -> Result<Self, Error> {
+        ensure!(options.cell_order <= 12, "the cell order can be at most 12");
+        ensure!(
+            options.limit_per_file != Some(0),
+            "the limit per file must be positive"
+        );
+
+        Ok(Self {
+            sampling: options.sampling(),
+            cell_order: options.cell_order,
+            rng: fastrand::Rng::with_seed(options.seed),
+            cell: None,
+            num_offered: 0,
+            reservoir: vec![],
+            brightest: BinaryHeap::new(),
+        })
+    }
+
+    /// Offers a record to the sample. Sampled records are added to `output`
+    /// once the sample of their cell is complete.
+    ///
+    /// Returns whether the rest of the partition file can be skipped, because
+    /// none of its records would be sampled.
+    pub fn push(&mut self, key: SampleKey, item: T, output: &mut Vec<T>) -> bool {
+        let cell = match self.sampling {
+            Sampling::First(_) => key.partition as u64,
+            _ => healpix::gaia_source_pixel(key.source_id, self.cell_order),
+        };
+        if self.cell != Some(cell) {
+            self.finish(output);
+            self.cell = Some(cell);
+        }
+        self.num_offered += 1;
+
+        match self.sampling {
+            Sampling::All => output.push(item),
+            Sampling::First(size) => {
+                output.push(item);
+                return self.num_offered >= size as u64;
+            }
+            Sampling::Reservoir(size) => {
+                // Algorithm R: the n-th record replaces a random one with a
+                // probability of size / n.
+                if self.reservoir.len() < size {
+                    self.reservoir.push(item);
+                }
+                else {
+                    let index = self.rng.u64(0..self.num_offered) as usize;
+                    if index < size {
+                        self.reservoir[index] = item;
+                    }
+                }
+            }
+            Sampling::Brightest(size) => {
+                let Some(magnitude) = key.magnitude
+                else {
+                    return false;
+                };
+                let candidate = Candidate { magnitude, item };
+                if self.brightest.len() < size {
+                    self.brightest.push(candidate);
+                }
+                else if self
+                    .brightest
+                    .peek()
+                    .is_some_and(|faintest| candidate < *faintest)
+                {
+                    self.brightest.pop();
+                    self.brightest.push(candidate);
+                }
+            }
+            Sampling::Fraction(fraction) => {
+                let threshold = fraction * NUM_SOURCES as f64;
+                if key
+                    .random_index
+                    .is_some_and(|random_index| (random_index as f64) < threshold)
+                {
+                    output.push(item);
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Completes the sample of the current cell.
+    pub fn finish(&mut self, output: &mut Vec<T>) {
+        output.append(&mut self.reservoir);
+        output.extend(
+            std::mem::take(&mut self.brightest)
+                .into_sorted_vec()
+                .into_iter()
+                .map(|candidate| candidate.item),
+        );
+        self.num_offered = 0;
+    }
+}
+
+impl Display for SampleOptions {
+    /// Describes the sampling for the provenance of an export.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let order = self.cell_order;
+        match self.sampling() {
+            Sampling::All => write!(f, "all stars"),
+            Sampling::First(size) => write!(f, "at most {size} stars per file"),
+            Sampling::Reservoir(size) => {
+                write!(
+                    f,
+                    "uniform sample of {size} stars per HEALPix cell of order {order}, seed {}",
+                    self.seed
+                )
+            }
+            Sampling::Brightest(size) => {
+                write!(
+                    f,
+                    "{size} brightest stars per HEALPix cell of order {order}"
+                )
+            }
+            Sampling::Fraction(fraction) => {
+                write!(f, "random_index below {fraction} of all sources")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(sample: Sampling, keys: &[SampleKey]) -> Vec<usize> {
+        let mut sampler = Sampler::new(&SampleOptions {
+            sample,
+            limit_per_file: None,
+            cell_order: 0,
+            seed: 1,
+        })
+        .unwrap();
+
+        let mut output = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            sampler.push(*key, i, &mut output);
+        }
+        sampler.finish(&mut output);
+        output
+    }
+
+    /// Records in the first two cells of order 0, with 100 records each.
+    fn keys() -> Vec<SampleKey> {
+        (0..200)
+            .map(|i| {
+                let cell = i / 100;
+                SampleKey {
+                    partition: 0,
+                    source_id: (cell << 59) + i,
+                    magnitude: (i % 7 != 0).then_some(((i * 37) % 101) as f32),
+                    random_index: Some(i as i64 * 10_000_000),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples_per_cell() {
+        let keys = keys();
+
+        let reservoir = sample(Sampling::Reservoir(10), &keys);
+        assert_eq!(reservoir.len(), 20);
+        assert_eq!(reservoir.iter().filter(|i| **i < 100).count(), 10);
+
+        let brightest = sample(Sampling::Brightest(5), &keys);
+        for cell in [0..100, 100..200] {
+            let mut expected = keys[cell.clone()]
+                .iter()
+                .zip(cell)
+                .filter_map(|(key, i)| Some((key.magnitude?, i)))
+                .collect::<Vec<_>>();
+            expected.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let expected = expected.into_iter().take(5).map(|(_, i)| i);
+            assert!(expected.into_iter().all(|i| brightest.contains(&i)));
+        }
+        assert_eq!(brightest.len(), 10);
+
+        let fraction = sample(Sampling::Fraction(0.5), &keys);
+        assert_eq!(fraction, (0..91).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn limit_per_file_is_an_alias_for_first() {
+        let parse = |args: &[&str]| {
+            SampleOptions::from_iter_safe(
+                std::iter::once("export").chain(args.iter().copied()),
+            )
+        };
+
+        let options = parse(&["-l", "10"]).unwrap();
+        assert_eq!(options.sampling(), Sampling::First(10));
+        assert_eq!(options.to_string(), "at most 10 stars per file");
+
+        let options = parse(&["--sample", "brightest:5"]).unwrap();
+        assert_eq!(options.sampling(), Sampling::Brightest(5));
+
+        assert!(parse(&["--limit-per-file", "10", "--sample", "all"]).is_err());
+    }
+}
@@ -0,0 +1,442 @@
+use std::f64::consts::{
+    FRAC_PI_2,
+    PI,
+    SQRT_2,
+};
+
+use image::{
+    ImageBuffer,
+    Pixel,
+    Rgb,
+};
+use nalgebra::{
+    Matrix3,
+    Rotation3,
+    Vector3,
+};
+use structopt::StructOpt;
+
+use super::canvas::OverlayPixel;
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct SkyOptions {
+    /// Map projection: equirectangular, mollweide, hammer-aitoff,
+    /// orthographic, stereographic or gnomonic.
+    #[structopt(long, default_value = "mollweide")]
+    pub projection: Projection,
+
+    /// Coordinate frame: galactic, equatorial or ecliptic.
+    #[structopt(long, default_value = "galactic")]
+    pub frame: Frame,
+
+    /// Longitude of the center of the map in degrees.
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub center_lon: f64,
+
+    /// Latitude of the center of the map in degrees.
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub center_lat: f64,
+
+    /// Draw a coordinate grid on top of the map.
+    #[structopt(long)]
+    pub graticule: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Projection {
+    Equirectangular,
+    Mollweide,
+    HammerAitoff,
+    /// Two orthographic hemispheres side by side. The left one is centered on
+    /// the map center, the right one on its antipode.
+    Orthographic,
+    Stereographic,
+    Gnomonic,
+}
+
+impl Projection {
+    /// Ratio of width to height of the map.
+    pub fn aspect_ratio(&self) -> u32 {
+        match self {
+            Self::Stereographic | Self::Gnomonic => 1,
+            _ => 2,
+        }
+    }
+
+    /// Projects a direction onto the map plane.
+    ///
+    /// The direction is given in a frame where the map center is on the x
+    /// axis and the z axis points up. The map plane is normalized to
+    /// `[-1, 1]` in both coordinates, with longitude increasing to the left.
+    fn project(&self, d: &Vector3<f64>, fov: f64) -> Option<[f64; 2]> {
+        let lon = d.y.atan2(d.x);
+        let lat = d.z.clamp(-1.0, 1.0).asin();
+
+        let uv = match self {
+            Self::Equirectangular => [-lon / PI, lat / FRAC_PI_2],
+            Self::Mollweide => {
+                // solve 2 theta + sin(2 theta) = pi sin(lat) with Newton's method
+                let target = PI * lat.sin();
+                let mut theta = lat;
+                for _ in 0..16 {
+                    let f = 2.0 * theta + (2.0 * theta).sin() - target;
+                    let df = 2.0 + 2.0 * (2.0 * theta).cos();
+                    if df.abs() < 1e-12 {
+                        break;
+                    }
+                    let step = f / df;
+                    theta -= step;
+                    if step.abs() < 1e-12 {
+                        break;
+                    }
+                }
+                [-lon / PI * theta.cos(), theta.sin()]
+            }
+            Self::HammerAitoff => {
+                let z = (1.0 + lat.cos() * (0.5 * lon).cos()).sqrt();
+                let x = 2.0 * SQRT_2 * lat.cos() * (0.5 * lon).sin() / z;
+                let y = SQRT_2 * lat.sin() / z;
+                [-x / (2.0 * SQRT_2), y / SQRT_2]
+            }
+            Self::Orthographic => {
+                if d.x >= 0.0 {
+                    [-0.5 - 0.5 * d.y, d.z]
+                }
+                else {
+                    [0.5 + 0.5 * d.y, d.z]
+                }
+            }
+            Self::Stereographic => {
+                if d.x <= -1.0 {
+                    return None;
+                }
+                let k = 2.0 / (1.0 + d.x);
+                let scale = 2.0 * (0.25 * fov).tan();
+                [-k * d.y / scale, k * d.z / scale]
+            }
+            Self::Gnomonic => {
+                if d.x <= 0.0 {
+                    return None;
+                }
+                let scale = (0.5 * fov).tan();
+                [-d.y / d.x / scale, d.z / d.x / scale]
+            }
+        };
+
+        Some(uv)
+    }
+
+    /// Inverse of [`Projection::project`]. Returns `None` for points outside
+    /// of the map.
+    fn unproject(&self, [u, v]: [f64; 2], fov: f64) -> Option<Vector3<f64>> {
+        fn from_lon_lat(lon: f64, lat: f64) -> Vector3<f64> {
+            Vector3::new(lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin())
+        }
+
+        match self {
+            Self::Equirectangular => {
+                if u.abs() > 1.0 || v.abs() > 1.0 {
+                    return None;
+                }
+                Some(from_lon_lat(-u * PI, v * FRAC_PI_2))
+            }
+            Self::Mollweide => {
+                if u * u + v * v > 1.0 {
+                    return None;
+                }
+                let theta = v.asin();
+                let lat = ((2.0 * theta + (2.0 * theta).sin()) / PI)
+                    .clamp(-1.0, 1.0)
+                    .asin();
+                let lon = if theta.cos() > 0.0 {
+                    -u * PI / theta.cos()
+                }
+                else {
+                    0.0
+                };
+                Some(from_lon_lat(lon, lat))
+            }
+            Self::HammerAitoff => {
+                if u * u + v * v > 1.0 {
+                    return None;
+                }
+                let x = -u * 2.0 * SQRT_2;
+                let y = v * SQRT_2;
+                let z = (1.0 - (0.25 * x).powi(2) - (0.5 * y).powi(2)).sqrt();
+                let lon = 2.0 * (z * x).atan2(2.0 * (2.0 * z * z - 1.0));
+                let lat = (z * y).clamp(-1.0, 1.0).asin();
+                Some(from_lon_lat(lon, lat))
+            }
+            Self::Orthographic => {
+                let (a, front) = if u < 0.0 {
+                    (2.0 * (u + 0.5), true)
+                }
+                else {
+                    (2.0 * (u - 0.5), false)
+                };
+                let r2 = a * a + v * v;
+                if r2 > 1.0 {
+                    return None;
+                }
+                let x = (1.0 - r2).sqrt();
+                if front {
+                    Some(Vector3::new(x, -a, v))
+                }
+                else {
+                    Some(Vector3::new(-x, a, v))
+                }
+            }
+            Self::Stereographic => {
+                let scale = 2.0 * (0.25 * fov).tan();
+                let x = u * scale;
+                let y = v * scale;
+                let r2 = x * x + y * y;
+                Some(Vector3::new(4.0 - r2, -4.0 * x, 4.0 * y) / (4.0 + r2))
+            }
+            Self::Gnomonic => {
+                let scale = (0.5 * fov).tan();
+                Some(Vector3::new(1.0, -u * scale, v * scale).normalize())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Frame {
+    Galactic,
+    /// ICRS
+    Equatorial,
+    /// Mean ecliptic of J2000
+    Ecliptic,
+}
+
+impl Frame {
+    /// Obliquity of the ecliptic at J2000 in degrees.
+    const OBLIQUITY: f64 = 23.4392911;
+
+    /// Rotation from ICRS to galactic coordinates (Hipparcos, ESA 1997).
+    fn equatorial_to_galactic() -> Rotation3<f64> {
+        Rotation3::from_matrix_unchecked(Matrix3::new(
+            -0.0548755604162154,
+            -0.873437090234885,
+            -0.4838350155487132,
+            0.4941094278755837,
+            -0.4448296299600112,
+            0.746982244497219,
+            -0.8676661490190047,
+            -0.1980763734312015,
+            0.4559837761750669,
+        ))
+    }
+
+    /// Rotation from galactic coordinates into this frame.
+    pub fn rotation_from_galactic(&self) -> Rotation3<f64> {
+        match self {
+            Self::Galactic => Rotation3::identity(),
+            Self::Equatorial => Self::equatorial_to_galactic().inverse(),
+            Self::Ecliptic => {
+                Rotation3::from_axis_angle(&Vector3::x_axis(), -Self::OBLIQUITY.to_radians())
+                    * Self::equatorial_to_galactic().inverse()
+            }
+        }
+    }
+}
+
+/// Unit vector for galactic longitude and latitude given in degrees.
+pub fn galactic_direction(longitude: f64, latitude: f64) -> Vector3<f64> {
+    let l = longitude.to_radians();
+    let b = latitude.to_radians();
+    Vector3::new(b.cos() * l.cos(), b.cos() * l.sin(), b.sin())
+}
+
+/// Unit vectors pointing east (towards increasing longitude) and north at the
+/// longitude and latitude given in degrees.
+pub fn east_north(longitude: f64, latitude: f64) -> [Vector3<f64>; 2] {
+    let l = longitude.to_radians();
+    let b = latitude.to_radians();
+    [
+        Vector3::new(-l.sin(), l.cos(), 0.0),
+        Vector3::new(-b.sin() * l.cos(), -b.sin() * l.sin(), b.cos()),
+    ]
+}
+
+/// Maps galactic directions to pixels of a sky map.
+pub struct SkyProjector {
+    projection: Projection,
+    fov: f64,
+    graticule: bool,
+    /// Rotation from galactic coordinates into the map frame.
+    frame: Rotation3<f64>,
+    /// Rotation from the map frame into a frame with the map center on the x
+    /// axis.
+    center: Rotation3<f64>,
+    width: u32,
+    height: u32,
+}
+
+impl SkyProjector {
+    const GRATICULE_COLOR: Rgb<u8> = Rgb([96, 128, 160]);
+
+    /// `fov` is the field of view in degrees used by the stereographic and
+    /// gnomonic projections.
+    pub fn new(options: &SkyOptions, fov: f64, width: u32) -> Self {
+        let center =
+            Rotation3::from_axis_angle(&Vector3::y_axis(), options.center_lat.to_radians())
+                * Rotation3::from_axis_angle(&Vector3::z_axis(), -options.center_lon.to_radians());
+
+        Self {
+            projection: options.projection,
+            fov: fov.to_radians(),
+            graticule: options.graticule,
+            frame: options.frame.rotation_from_galactic(),
+            center,
+            width,
+            height: width / options.projection.aspect_ratio(),
+        }
+    }
+
+    pub fn image_size(&self) -> [u32; 2] {
+        [self.width, self.height]
+    }
+
+    /// Projects a direction in galactic coordinates to pixel coordinates.
+    pub fn project(&self, direction: &Vector3<f64>) -> Option<[f64; 2]> {
+        let d = self.center * (self.frame * direction);
+        let [u, v] = self.projection.project(&d, self.fov)?;
+        Some([
+            0.5 * (u + 1.0) * self.width as f64,
+            0.5 * (1.0 - v) * self.height as f64,
+        ])
+    }
+
+    /// Direction in the map frame for the pixel coordinates.
+    fn unproject(&self, x: f64, y: f64) -> Option<Vector3<f64>> {
+        let u = 2.0 * x / self.width as f64 - 1.0;
+        let v = 1.0 - 2.0 * y / self.height as f64;
+        let d = self.projection.unproject([u, v], self.fov)?;
+        Some(self.center.inverse() * d)
+    }
+
+    /// Spacing of the graticule lines in degrees.
+    fn graticule_spacing(&self) -> f64 {
+        match self.projection {
+            Projection::Stereographic | Projection::Gnomonic => {
+                let fov = self.fov.to_degrees();
+                [
+                    30.0, 15.0, 10.0, 5.0, 2.0, 1.0, 0.5, 0.2, 0.1, 0.05, 0.02, 0.01,
+                ]
+                .into_iter()
+                .find(|spacing| *spacing <= 0.25 * fov)
+                .unwrap_or(0.01)
+            }
+            _ => 30.0,
+        }
+    }
+
+    /// Draws the graticule, if enabled.
+    ///
+    /// A pixel is part of a line if the grid cell of its center differs from
+    /// that of its left or upper neighbour.
+    pub fn draw_overlay<P>(&self, image: &mut ImageBuffer<P, Vec<P::Subpixel>>)
+    where
+        P: Pixel + OverlayPixel,
+    {
+        if !self.graticule {
+            return;
+        }
+
+        let spacing = self.graticule_spacing();
+        let cell = |x: u32, y: u32| {
+            let d = self.unproject(x as f64 + 0.5, y as f64 + 0.5)?;
+            let lon = d.y.atan2(d.x).to_degrees();
+            let lat = d.z.clamp(-1.0, 1.0).asin().to_degrees();
+            // don't draw meridians converging at the poles
+            let lon = if lat.abs() < 90.0 - spacing {
+                (lon / spacing).floor() as i64
+            }
+            else {
+                0
+            };
+            Some((lon, (lat / spacing).floor() as i64))
+        };
+
+        let width = image.width();
+        let height = image.height();
+        let mut previous_row: Vec<_> = (0..width).map(|x| cell(x, 0)).collect();
+
+        for y in 0..height {
+            let row: Vec<_> = (0..width).map(|x| cell(x, y)).collect();
+
+            for x in 0..width {
+                let Some(current) = row[x as usize]
+                else {
+                    continue;
+                };
+                let is_line = [
+                    x.checked_sub(1).and_then(|x| row[x as usize]),
+                    previous_row[x as usize],
+                ]
+                .into_iter()
+                .flatten()
+                .any(|neighbour| neighbour != current);
+
+                if is_line {
+                    image.get_pixel_mut(x, y).overlay(Self::GRATICULE_COLOR);
+                }
+            }
+
+            previous_row = row;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directions() -> impl Iterator<Item = Vector3<f64>> {
+        (-8..=8).flat_map(|lat| {
+            (-17..=17).map(move |lon| galactic_direction(lon as f64 * 10.0, lat as f64 * 10.0))
+        })
+    }
+
+    fn assert_round_trip(projection: Projection, fov: f64, d: Vector3<f64>) {
+        let uv = projection.project(&d, fov).unwrap();
+        let unprojected = projection.unproject(uv, fov).unwrap();
+        assert!(
+            (unprojected - d).norm() < 1e-9,
+            "{projection:?}: {d:?} -> {uv:?} -> {unprojected:?}"
+        );
+    }
+
+    #[test]
+    fn unprojects_projected_directions() {
+        for d in directions() {
+            assert_round_trip(Projection::Mollweide, PI, d);
+            assert_round_trip(Projection::HammerAitoff, PI, d);
+            if d.x > -0.9 {
+                assert_round_trip(Projection::Stereographic, PI, d);
+            }
+            if d.x > 0.1 {
+                assert_round_trip(Projection::Gnomonic, 170f64.to_radians(), d);
+            }
+        }
+    }
+
+    #[test]
+    fn rotates_galactic_center_from_icrs() {
+        let ra = 266.405f64.to_radians();
+        let dec = (-28.936f64).to_radians();
+        let icrs = Vector3::new(dec.cos() * ra.cos(), dec.cos() * ra.sin(), dec.sin());
+
+        let galactic = Frame::equatorial_to_galactic() * icrs;
+        let l = galactic.y.atan2(galactic.x).to_degrees();
+        let b = galactic.z.asin().to_degrees();
+        assert!(l.abs() < 1e-3 && b.abs() < 1e-3, "l = {l}, b = {b}");
+
+        let rotated = Frame::Equatorial.rotation_from_galactic() * galactic_direction(0.0, 0.0);
+        assert!((rotated - icrs).norm() < 1e-4);
+    }
+}
@@ -0,0 +1,249 @@
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use structopt::StructOpt;
+
+use super::{
+    camera::{
+        Camera,
+        ObserverSpec,
+    },
+    canvas::{
+        save_hdr,
+        Canvas,
+        OutputFormat,
+    },
+    flux,
+    psf::Psf,
+    sky::{
+        Frame,
+        Projection,
+        SkyOptions,
+        SkyProjector,
+    },
+    Record,
+    SelectOptions,
+    ToneMap,
+    View,
+};
+use crate::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct SkyboxOptions {
+    /// Position of the observer. Either heliocentric galactic coordinates
+    /// `x,y,z` in parsec, or `star:<source_id>`.
+    #[structopt(long, default_value = "0,0,0", allow_hyphen_values = true)]
+    pub observer: ObserverSpec,
+
+    /// Layout: cubemap or equirectangular.
+    #[structopt(short, long, default_value = "cubemap")]
+    pub layout: SkyboxLayout,
+
+    /// Size of a cubemap face, or width of the equirectangular panorama.
+    #[structopt(short, long, default_value = "2048")]
+    pub size: u32,
+
+    /// Stars closer to the observer than this distance in parsec are left
+    /// out, as the engine renders them as objects.
+    #[structopt(long, default_value = "1")]
+    pub min_distance: f64,
+
+    /// Tone mapping operator for PNG output.
+    #[structopt(short, long, default_value = "asinh")]
+    pub tone_map: ToneMap,
+
+    /// Exposure in stops.
+    #[structopt(short, long, default_value = "0", allow_hyphen_values = true)]
+    pub exposure: f32,
+
+    #[structopt(flatten)]
+    pub psf: Psf,
+
+    #[structopt(flatten)]
+    pub select: SelectOptions,
+}
+
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum SkyboxLayout {
+    /// Six faces with a field of view of 90 degrees each, written to
+    /// `<name>_px.<ext>`, `<name>_nx.<ext>`, etc.
+    Cubemap,
+    /// A single panorama in galactic coordinates, with the galactic center
+    /// in the middle.
+    Equirectangular,
+}
+
+/// Cubemap faces with their name, viewing direction and up vector in galactic
+/// coordinates. The faces are ordered by axis, positive direction first.
+const CUBEMAP_FACES: [(&str, [f64; 3], [f64; 3]); 6] = [
+    ("px", [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ("nx", [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ("py", [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ("ny", [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ("pz", [0.0, 0.0, 1.0], [-1.0, 0.0, 0.0]),
+    ("nz", [0.0, 0.0, -1.0], [1.0, 0.0, 0.0]),
+];
+
+/// Renders the sky as seen from the observer into a skybox.
+///
+/// The output format is chosen by the file extension: OpenEXR (`.exr`) and
+/// Radiance HDR (`.hdr`) contain the linear flux, PNG is tone mapped with 16
+/// bits per channel.
+pub async fn skybox(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: SkyboxOptions,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let output = output.as_ref();
+    let observer = options.observer.resolve(path).await?;
+
+    let observe = |record: &Record| {
+        observer
+            .observe(record)
+            .filter(|observation| observation.distance >= options.min_distance)
+    };
+
+    match options.layout {
+        SkyboxLayout::Cubemap => {
+            let cameras = CUBEMAP_FACES
+                .iter()
+                .map(|(_, direction, up)| {
+                    Camera::new(
+                        (*direction).into(),
+                        (*up).into(),
+                        90.0,
+                        [options.size, options.size],
+                    )
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let mut threads = options
+                .select
+                .draw_parallel(
+                    path,
+                    || CUBEMAP_FACES.map(|_| Canvas::new(options.size, options.size)),
+                    |canvases, record| {
+                        let Some(observation) = observe(record)
+                        else {
+                            return;
+                        };
+                        let flux = flux(
+                            observation.apparent_magnitude,
+                            View::SKY_REFERENCE_MAGNITUDE,
+                        );
+
+                        // stars near an edge also spill onto the neighbouring faces
+                        let radius = options.psf.radius(flux);
+                        let size = options.size as f64;
+                        for (camera, canvas) in cameras.iter().zip(canvases.iter_mut()) {
+                            let Some([x, y]) = camera.project(&observation.direction)
+                            else {
+                                continue;
+                            };
+                            if x < -radius
+                                || x > size + radius
+                                || y < -radius
+                                || y > size + radius
+                            {
+                                continue;
+                            }
+                            options.psf.draw(canvas, [x, y], record.color(), flux);
+                        }
+                    },
+                )
+                .await?
+                .into_iter();
+
+            let mut canvases = threads.next().unwrap();
+            for other in threads {
+                for (canvas, other) in canvases.iter_mut().zip(&other) {
+                    canvas.merge(other);
+                }
+            }
+
+            for ((name, _, _), canvas) in CUBEMAP_FACES.iter().zip(&canvases) {
+                save(canvas, &face_path(output, name), &options)?;
+            }
+        }
+        SkyboxLayout::Equirectangular => {
+            let projector = SkyProjector::new(
+                &SkyOptions {
+                    projection: Projection::Equirectangular,
+                    frame: Frame::Galactic,
+                    center_lon: 0.0,
+                    center_lat: 0.0,
+                    graticule: false,
+                },
+                360.0,
+                options.size,
+            );
+            let [width, height] = projector.image_size();
+            let mut canvases = options
+                .select
+                .draw_parallel(
+                    path,
+                    || Canvas::new(width, height),
+                    |canvas, record| {
+                        let Some(observation) = observe(record)
+                        else {
+                            return;
+                        };
+                        let Some([x, y]) = projector.project(&observation.direction)
+                        else {
+                            return;
+                        };
+
+                        let flux = flux(
+                            observation.apparent_magnitude,
+                            View::SKY_REFERENCE_MAGNITUDE,
+                        );
+                        options.psf.draw(canvas, [x, y], record.color(), flux);
+                    },
+                )
+                .await?
+                .into_iter();
+
+            let mut canvas = canvases.next().unwrap();
+            for other in canvases {
+                canvas.merge(&other);
+            }
+
+            save(&canvas, output, &options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `<name>_<face>.<ext>` next to `output`.
+fn face_path(output: &Path, face: &str) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let mut file_name = format!("{stem}_{face}");
+    if let Some(extension) = output.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+    output.with_file_name(file_name)
+}
+
+fn save(canvas: &Canvas, path: &Path, options: &SkyboxOptions) -> Result<(), Error> {
+    tracing::info!("writing image: {}", path.display());
+
+    match OutputFormat::from_path(path, 16)? {
+        OutputFormat::Exr => canvas.linear(options.exposure).save(path)?,
+        OutputFormat::Hdr => save_hdr(&canvas.linear(options.exposure), path)?,
+        OutputFormat::Rgb8 | OutputFormat::Rgb16 => {
+            canvas
+                .tone_map_16(options.tone_map, options.exposure)
+                .save(path)?
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,714 @@
+//! Exports sorted along a Morton curve over the heliocentric positions of the
+//! stars, with an octree index of the sorted records.
+//!
+//! The indexed cube is centered on the sun and divided into `2^BITS` cells
+//! along every axis. The Morton key of a star interleaves the bits of its cell
+//! coordinates, so that every octree node covers a contiguous range of keys,
+//! and thus of records in the sorted file. The index stores the leaves of an
+//! octree that is split until a node has at most `leaf_size` records. Stars
+//! outside of the cube, or without a positive parallax, are sorted to the end
+//! and aren't part of the index. They are read for every region that reaches
+//! out of the cube.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    ops::Range,
+    path::Path,
+    str::FromStr,
+};
+
+use color_eyre::eyre::{
+    bail,
+    ensure,
+    eyre,
+};
+use nalgebra::{
+    Point3,
+    Vector3,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use structopt::StructOpt;
+use tokio::{
+    fs::File,
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncSeekExt,
+        AsyncWrite,
+        AsyncWriteExt,
+        BufReader,
+        BufWriter,
+    },
+};
+
+use super::{
+    format::{
+        Header,
+        RecordReader,
+        RecordWriter,
+    },
+    lookup::{
+        remove_source_index,
+        write_source_index,
+    },
+    progress_bar,
+    Record,
+};
+use crate::Error;
+
+/// Bits of the cell coordinates along every axis.
+const BITS: u32 = 21;
+
+#[derive(Debug, StructOpt)]
+pub struct SortOptions {
+    /// Half the edge length of the indexed cube in kilo parsec.
+    #[structopt(long, default_value = "100")]
+    pub half_size: f64,
+
+    /// Octree nodes with more records than this are split.
+    #[structopt(long, default_value = "4096")]
+    pub leaf_size: u64,
+
+    /// Approximate memory in MiB used for sorting. Larger exports are sorted
+    /// in runs that are merged afterwards.
+    #[structopt(long, default_value = "4096")]
+    pub memory_limit: u64,
+
+    /// Also write the source id index of the sorted export, for the `lookup`
+    /// command.
+    #[structopt(long)]
+    pub index: bool,
+}
+
+/// Parameters of the index, as stored in the header.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpatialIndex {
+    /// Half the edge length of the indexed cube in parsec.
+    pub half_size: f64,
+    pub leaf_size: u64,
+    /// Index of the first record that isn't indexed. Files sorted before
+    /// this was stored have their tail right after the last leaf.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tail_start: Option<u64>,
+}
+
+impl SpatialIndex {
+    /// Morton key of a heliocentric position in parsec, or `None` if it's
+    /// outside of the cube.
+    fn key(&self, position: &Point3<f64>) -> Option<u64> {
+        let cells = (1u64 << BITS) as f64;
+        let mut key = 0;
+        for (axis, coordinate) in position.iter().enumerate() {
+            let cell = (coordinate + self.half_size) / (2.0 * self.half_size) * cells;
+            if !(0.0..cells).contains(&cell) {
+                return None;
+            }
+            key |= spread_bits(cell as u64) << (2 - axis);
+        }
+        Some(key)
+    }
+
+    /// Key of a record, or `u64::MAX` for records that aren't indexed.
+    fn record_key(&self, record: &Record) -> u64 {
+        if record.parallax.is_nan() || record.parallax <= 0.0 {
+            return u64::MAX;
+        }
+        self.key(&record.heliocentric_position())
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Corners of the cube covered by a node.
+    fn bounds(&self, node: &Node) -> [Point3<f64>; 2] {
+        let size = 2.0 * self.half_size / (1u64 << node.depth) as f64;
+        let cell = Vector3::from_fn(|axis, _| compact_bits(node.prefix >> (2 - axis)) as f64);
+        let min = Point3::from(cell * size - Vector3::repeat(self.half_size));
+        [min, min + Vector3::repeat(size)]
+    }
+
+    /// Ranges of records of all leaves intersecting the region, with adjacent
+    /// ranges merged. Unless the region lies within the cube, the records
+    /// that aren't indexed are included too.
+    pub fn ranges(&self, nodes: &[Node], num_records: u64, region: &Region) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = vec![];
+        let mut push = |range: Range<u64>| {
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        };
+
+        for node in nodes {
+            let [min, max] = self.bounds(node);
+            if region.intersects(&min, &max) {
+                push(node.start..node.end);
+            }
+        }
+
+        let min = Point3::from(Vector3::repeat(-self.half_size));
+        let max = Point3::from(Vector3::repeat(self.half_size));
+        if !region.is_within(&min, &max) {
+            let tail_start = self
+                .tail_start
+                .unwrap_or_else(|| nodes.last().map_or(0, |node| node.end));
+            if tail_start < num_records {
+                push(tail_start..num_records);
+            }
+        }
+
+        ranges
+    }
+}
+
+/// Spreads the lower [`BITS`] bits of `x` to every third bit.
+pub(super) fn spread_bits(x: u64) -> u64 {
+    let mut x = x & 0x1f_ffff;
+    x = (x | x << 32) & 0x001f_0000_0000_ffff;
+    x = (x | x << 16) & 0x001f_0000_ff00_00ff;
+    x = (x | x << 8) & 0x100f_00f0_0f00_f00f;
+    x = (x | x << 4) & 0x10c3_0c30_c30c_30c3;
+    x = (x | x << 2) & 0x1249_2492_4924_9249;
+    x
+}
+
+/// Inverse of [`spread_bits`].
+pub(super) fn compact_bits(x: u64) -> u64 {
+    let mut x = x & 0x1249_2492_4924_9249;
+    x = (x | x >> 2) & 0x10c3_0c30_c30c_30c3;
+    x = (x | x >> 4) & 0x100f_00f0_0f00_f00f;
+    x = (x | x >> 8) & 0x001f_0000_ff00_00ff;
+    x = (x | x >> 16) & 0x001f_0000_0000_ffff;
+    x = (x | x >> 32) & 0x1f_ffff;
+    x
+}
+
+/// Prefix of the key at an octree depth.
+fn prefix(key: u64, depth: u32) -> u64 {
+    key >> (3 * (BITS - depth))
+}
+
+/// A leaf of the octree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub depth: u32,
+    /// Key prefix of the node, i.e. the interleaved cell coordinates at its
+    /// depth.
+    pub prefix: u64,
+    /// Index of the first record.
+    pub start: u64,
+    /// Index past the last record.
+    pub end: u64,
+}
+
+impl Node {
+    /// Size in bytes as written by [`Node::write`].
+    pub const SIZE: usize = 25;
+
+    pub async fn write(&self, mut writer: impl AsyncWrite + Unpin) -> Result<(), Error> {
+        writer.write_u8(self.depth as u8).await?;
+        writer.write_u64(self.prefix).await?;
+        writer.write_u64(self.start).await?;
+        writer.write_u64(self.end).await?;
+        Ok(())
+    }
+
+    pub async fn read(mut reader: impl AsyncRead + Unpin) -> Result<Self, Error> {
+        let mut bytes = [0; Self::SIZE];
+        reader.read_exact(&mut bytes).await?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self, Error> {
+        let u64_at =
+            |offset: usize| u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let node = Self {
+            depth: bytes[0].into(),
+            prefix: u64_at(1),
+            start: u64_at(9),
+            end: u64_at(17),
+        };
+        ensure!(
+            node.depth <= BITS && node.start <= node.end,
+            "invalid octree node"
+        );
+        Ok(node)
+    }
+}
+
+/// Node that is still receiving records while building the octree.
+struct OpenNode {
+    prefix: u64,
+    start: u64,
+    /// Number of leaves before the node was opened. All leaves after this
+    /// are its descendants.
+    first_leaf: usize,
+}
+
+/// Builds the octree leaves from sorted keys.
+///
+/// A node is closed when a key with another prefix arrives. If it has few
+/// enough records, the leaves of its descendants are replaced by the node
+/// itself.
+struct OctreeBuilder {
+    leaf_size: u64,
+    /// Open nodes, indexed by depth.
+    open: Vec<OpenNode>,
+    leaves: Vec<Node>,
+    num_records: u64,
+}
+
+impl OctreeBuilder {
+    fn new(leaf_size: u64) -> Self {
+        Self {
+            leaf_size,
+            open: vec![],
+            leaves: vec![],
+            num_records: 0,
+        }
+    }
+
+    fn push(&mut self, key: u64) {
+        let changed = (0..=BITS)
+            .find(|&depth| {
+                self.open
+                    .get(depth as usize)
+                    .is_none_or(|node| node.prefix != prefix(key, depth))
+            })
+            .unwrap_or(BITS + 1);
+        self.close(changed);
+
+        for depth in changed..=BITS {
+            self.open.push(OpenNode {
+                prefix: prefix(key, depth),
+                start: self.num_records,
+                first_leaf: self.leaves.len(),
+            });
+        }
+        self.num_records += 1;
+    }
+
+    /// Closes all nodes at `depth` and below.
+    fn close(&mut self, depth: u32) {
+        while self.open.len() > depth as usize {
+            let node = self.open.pop().unwrap();
+            let depth = self.open.len() as u32;
+            if self.num_records - node.start <= self.leaf_size || depth == BITS {
+                self.leaves.truncate(node.first_leaf);
+                self.leaves.push(Node {
+                    depth,
+                    prefix: node.prefix,
+                    start: node.start,
+                    end: self.num_records,
+                });
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Node> {
+        self.close(0);
+        self.leaves
+    }
+}
+
+/// Region of space the stars are read from.
+#[derive(Clone, Copy, Debug)]
+pub enum Region {
+    /// Axis aligned box, given by its minimum and maximum corner.
+    Box {
+        min: Point3<f64>,
+        max: Point3<f64>,
+    },
+    Sphere {
+        center: Point3<f64>,
+        radius: f64,
+    },
+}
+
+impl Region {
+    pub fn contains(&self, position: &Point3<f64>) -> bool {
+        match self {
+            Self::Box { min, max } => {
+                (0..3).all(|axis| position[axis] >= min[axis] && position[axis] <= max[axis])
+            }
+            Self::Sphere { center, radius } => (position - center).norm() <= *radius,
+        }
+    }
+
+    /// Whether the region intersects the box from `min` to `max`.
+    pub fn intersects(&self, min: &Point3<f64>, max: &Point3<f64>) -> bool {
+        match self {
+            Self::Box {
+                min: region_min,
+                max: region_max,
+            } => (0..3).all(|axis| min[axis] <= region_max[axis] && max[axis] >= region_min[axis]),
+            Self::Sphere { center, radius } => {
+                let closest = Vector3::from_fn(|axis, _| center[axis].clamp(min[axis], max[axis]));
+                (closest - center.coords).norm() <= *radius
+            }
+        }
+    }
+
+    /// Whether the region lies within the box from `min` to `max`, excluding
+    /// the box's maximum faces.
+    pub fn is_within(&self, min: &Point3<f64>, max: &Point3<f64>) -> bool {
+        let [region_min, region_max] = match self {
+            Self::Box {
+                min: region_min,
+                max: region_max,
+            } => [*region_min, *region_max],
+            Self::Sphere { center, radius } => {
+                [
+                    center - Vector3::repeat(*radius),
+                    center + Vector3::repeat(*radius),
+                ]
+            }
+        };
+        (0..3).all(|axis| region_min[axis] >= min[axis] && region_max[axis] < max[axis])
+    }
+
+    /// Whether the region contains the star. Stars without a positive parallax
+    /// are never contained.
+    pub fn contains_record(&self, record: &Record) -> bool {
+        record.parallax > 0.0 && self.contains(&record.heliocentric_position())
+    }
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    /// Parses `box:x,y,z,x,y,z` with the minimum and maximum corner, or
+    /// `sphere:x,y,z,radius`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, values) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("expected box:... or sphere:...: {s}"))?;
+        let values = values
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<Result<Vec<f64>, _>>()?;
+
+        match (kind, &values[..]) {
+            ("box", [x0, y0, z0, x1, y1, z1]) => {
+                let min = Point3::new(x0.min(*x1), y0.min(*y1), z0.min(*z1));
+                let max = Point3::new(x0.max(*x1), y0.max(*y1), z0.max(*z1));
+                Ok(Self::Box { min, max })
+            }
+            ("sphere", [x, y, z, radius]) => {
+                Ok(Self::Sphere {
+                    center: Point3::new(*x, *y, *z),
+                    radius: *radius,
+                })
+            }
+            _ => bail!("expected box:x,y,z,x,y,z or sphere:x,y,z,radius: {s}"),
+        }
+    }
+}
+
+/// A sorted run of records in a temporary file, each preceded by its key.
+struct Run {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+impl Run {
+    async fn write(records: &mut [(u64, Record)]) -> Result<Self, Error> {
+        records.sort_unstable_by_key(|(key, record)| (*key, record.source_id));
+
+        let mut writer = BufWriter::new(File::from_std(tempfile::tempfile()?));
+        for (key, record) in records.iter() {
+            writer.write_u64(*key).await?;
+            record.write(&mut writer).await?;
+        }
+        writer.flush().await?;
+
+        let mut file = writer.into_inner();
+        file.rewind().await?;
+
+        Ok(Self {
+            reader: BufReader::new(file),
+            remaining: records.len() as u64,
+        })
+    }
+
+    async fn next(&mut self) -> Result<Option<(u64, Record)>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let key = self.reader.read_u64().await?;
+        let record = Record::read(&mut self.reader).await?;
+        Ok(Some((key, record)))
+    }
+}
+
+/// Sorts an export along a Morton curve and writes it with an octree index.
+///
+/// The records are sorted in runs that fit into the memory limit, which are
+/// written to temporary files and merged.
+pub async fn sort(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: SortOptions,
+) -> Result<(), Error> {
+    ensure!(options.half_size > 0.0, "the half size must be positive");
+    ensure!(options.leaf_size > 0, "the leaf size must be positive");
+
+    let mut index = SpatialIndex {
+        half_size: 1000.0 * options.half_size,
+        leaf_size: options.leaf_size,
+        tail_start: None,
+    };
+
+    let mut records = RecordReader::open(path).await?;
+    let mut header = Header::new(records.header().provenance.clone());
+    header.provenance.filters.push(format!(
+        "sorted along a Morton curve within {} kpc",
+        options.half_size
+    ));
+
+    let run_size = (options.memory_limit * 1024 * 1024
+        / std::mem::size_of::<(u64, Record)>() as u64)
+        .max(1) as usize;
+
+    tracing::info!("sorting runs");
+    let run_progress = progress_bar(records.num_records());
+    let mut runs = vec![];
+    let mut run = Vec::with_capacity(run_size.min(records.num_records() as usize));
+    let mut num_indexed = 0;
+    while let Some(record) = records.read_record().await? {
+        let key = index.record_key(&record);
+        if key != u64::MAX {
+            num_indexed += 1;
+        }
+        run.push((key, record));
+        if run.len() >= run_size {
+            runs.push(Run::write(&mut run).await?);
+            run.clear();
+        }
+        run_progress.set_position(records.num_read());
+    }
+    if !run.is_empty() {
+        runs.push(Run::write(&mut run).await?);
+    }
+    drop(run);
+    run_progress.finish();
+
+    // the records that aren't indexed are sorted after all others.
+    index.tail_start = Some(num_indexed);
+    header.spatial_index = Some(index);
+
+    tracing::info!(num_runs = runs.len(), "merging runs");
+    let merge_progress = progress_bar(records.num_records());
+    let mut writer = RecordWriter::create(&output, &header).await?;
+    let mut octree = OctreeBuilder::new(options.leaf_size);
+
+    let mut heads = BinaryHeap::new();
+    let mut pending = Vec::with_capacity(runs.len());
+    for (i, run) in runs.iter_mut().enumerate() {
+        let head = run.next().await?;
+        if let Some((key, record)) = &head {
+            heads.push(Reverse((*key, record.source_id, i)));
+        }
+        pending.push(head);
+    }
+
+    while let Some(Reverse((key, _, i))) = heads.pop() {
+        let (_, record) = pending[i].take().unwrap();
+        writer.write_record(&record).await?;
+        if key != u64::MAX {
+            octree.push(key);
+        }
+
+        pending[i] = runs[i].next().await?;
+        if let Some((key, record)) = &pending[i] {
+            heads.push(Reverse((*key, record.source_id, i)));
+        }
+        merge_progress.inc(1);
+    }
+    merge_progress.finish();
+
+    let leaves = octree.finish();
+    tracing::info!(num_leaves = leaves.len(), "writing index");
+    writer.write_index(&leaves).await?;
+    writer.finish().await?;
+    if options.index {
+        write_source_index(output).await?;
+    }
+    else {
+        remove_source_index(output).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::mapped::MappedExport;
+
+    fn index() -> SpatialIndex {
+        SpatialIndex {
+            half_size: 1000.0,
+            leaf_size: 4,
+            tail_start: None,
+        }
+    }
+
+    #[test]
+    fn spread_and_compact_bits() {
+        for x in [0, 1, 2, 0x15_5555, 0x1f_ffff, 12345] {
+            assert_eq!(compact_bits(spread_bits(x)), x);
+        }
+        assert_eq!(spread_bits(0b111), 0b1001001);
+    }
+
+    #[test]
+    fn nodes_contain_their_keys() {
+        let index = index();
+        let position = Point3::new(123.0, -456.0, 789.0);
+        let key = index.key(&position).unwrap();
+
+        for depth in 0..=BITS {
+            let node = Node {
+                depth,
+                prefix: prefix(key, depth),
+                start: 0,
+                end: 1,
+            };
+            let [min, max] = index.bounds(&node);
+            assert!((0..3).all(|axis| position[axis] >= min[axis] && position[axis] < max[axis]));
+        }
+
+        assert!(index.key(&Point3::new(1000.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn octree_leaves_cover_all_records() {
+        let index = index();
+        let mut keys = (0..1000)
+            .map(|i| {
+                let t = i as f64 * 0.1;
+                let position = Point3::new(900.0 * t.sin(), 300.0 * t.cos(), t);
+                index.key(&position).unwrap()
+            })
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        let mut octree = OctreeBuilder::new(index.leaf_size);
+        for &key in &keys {
+            octree.push(key);
+        }
+        let leaves = octree.finish();
+
+        let mut next = 0;
+        for leaf in &leaves {
+            assert_eq!(leaf.start, next);
+            assert!(leaf.end - leaf.start <= index.leaf_size || leaf.depth == BITS);
+            for &key in &keys[leaf.start as usize..leaf.end as usize] {
+                assert_eq!(prefix(key, leaf.depth), leaf.prefix);
+            }
+            next = leaf.end;
+        }
+        assert_eq!(next, keys.len() as u64);
+    }
+
+    #[test]
+    fn region_intersection() {
+        let sphere: Region = "sphere:0,0,0,10".parse().unwrap();
+        let min = Point3::new(5.0, 5.0, 5.0);
+        assert!(sphere.intersects(&min, &Point3::new(20.0, 20.0, 20.0)));
+        assert!(!sphere.intersects(&Point3::new(6.0, 6.0, 6.0), &Point3::new(7.0, 7.0, 7.0)));
+
+        let cube: Region = "box:10,10,10,-10,-10,-10".parse().unwrap();
+        assert!(cube.contains(&Point3::new(10.0, -10.0, 0.0)));
+        assert!(!cube.contains(&Point3::new(10.1, 0.0, 0.0)));
+        assert!(cube.intersects(&Point3::new(9.0, 9.0, 9.0), &Point3::new(20.0, 20.0, 20.0)));
+        assert!(!cube.intersects(&Point3::new(11.0, 0.0, 0.0), &Point3::new(20.0, 1.0, 1.0)));
+
+        assert!("cylinder:0,0,0,1".parse::<Region>().is_err());
+        assert!("sphere:0,0,0".parse::<Region>().is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_regions_past_the_cube() {
+        let unsorted = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let sorted = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        // stars from 100 pc to 10 kpc, most of them outside of a 1 kpc cube.
+        let parallax = |source_id: u64| 10.0 / (1.0 + (source_id % 100) as f64);
+        let mut writer = RecordWriter::create(&unsorted, &Header::new(Default::default()))
+            .await
+            .unwrap();
+        for i in 0..500u64 {
+            let record = Record {
+                source_id: i,
+                healpix_range: crate::gaia::HealPixRange { start: 0, end: 0 },
+                parallax: parallax(i),
+                parallax_error: 0.1,
+                longitude: (i * 37 % 360) as f64,
+                latitude: (i * 11 % 180) as f64 - 90.0,
+                t_eff: 5000.0,
+                apparent_magnitude: 10.0,
+                pm_longitude: f32::NAN,
+                pm_latitude: f32::NAN,
+                bp_rp: f32::NAN,
+                metallicity: f32::NAN,
+                extinction: f32::NAN,
+                radial_velocity: f32::NAN,
+                non_single_star: 0,
+            };
+            writer.write_record(&record).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        sort(
+            &sorted,
+            &unsorted,
+            SortOptions {
+                half_size: 1.0,
+                leaf_size: 4,
+                memory_limit: 1,
+                index: false,
+            },
+        )
+        .await
+        .unwrap();
+
+        async fn read_region(path: &Path, region: Region) -> Vec<u64> {
+            let mut records = RecordReader::open_region(path, region).await.unwrap();
+            let mut source_ids = vec![];
+            while let Some(record) = records.read_record().await.unwrap() {
+                source_ids.push(record.source_id);
+            }
+            source_ids.sort_unstable();
+            source_ids
+        }
+
+        for region in ["sphere:0,0,0,5000", "box:-800,-800,-800,3000,800,800"] {
+            let region = region.parse::<Region>().unwrap();
+            let expected = read_region(&unsorted, region).await;
+            // some of the stars are outside of the cube in any direction.
+            assert!(expected
+                .iter()
+                .any(|&i| 1000.0 / parallax(i) > 1000.0 * 3f64.sqrt()));
+            assert_eq!(read_region(&sorted, region).await, expected);
+
+            let export = MappedExport::open(&sorted).unwrap();
+            let records = export.records();
+            let mut mapped = export
+                .ranges(Some(&region))
+                .unwrap()
+                .into_iter()
+                .flat_map(|range| &records[range])
+                .map(|raw| raw.to_record())
+                .filter(|record| region.contains_record(record))
+                .map(|record| record.source_id)
+                .collect::<Vec<_>>();
+            mapped.sort_unstable();
+            assert_eq!(mapped, expected);
+        }
+    }
+}
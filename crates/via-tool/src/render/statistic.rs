@@ -0,0 +1,379 @@
+//! Maps where every pixel is coloured by a statistic of the stars falling on
+//! it, instead of drawing the stars themselves.
+
+use std::{
+    path::Path,
+    sync::atomic::{
+        AtomicU32,
+        Ordering,
+    },
+};
+
+use color_eyre::eyre::{
+    bail,
+    ensure,
+};
+use image::{
+    Rgb,
+    RgbImage,
+};
+use structopt::StructOpt;
+
+use super::{
+    colormap::{
+        with_colorbar,
+        Colormap,
+    },
+    Record,
+    RenderOptions,
+    Viewport,
+};
+use crate::{
+    utils::parse_range,
+    Error,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct StatisticOptions {
+    /// Colours every pixel by a statistic of the stars falling on it: count,
+    /// mean or median.
+    #[structopt(long)]
+    pub statistic: Option<Statistic>,
+
+    /// Quantity the mean or median is computed of: metallicity, extinction,
+    /// radial-velocity, teff, absolute-magnitude, distance or binary. The
+    /// mean of binary is the fraction of non-single stars.
+    #[structopt(long, default_value = "metallicity")]
+    pub field: Field,
+
+    /// Scaling of the colormap: linear or log. Defaults to log for counts and
+    /// linear otherwise.
+    #[structopt(long)]
+    pub scale: Option<Scale>,
+
+    /// Range `min,max` of values spanned by the colormap. Defaults to the
+    /// typical range of the field, or 1 to the highest count. Values outside
+    /// of it are also clamped when computing the median.
+    #[structopt(long, allow_hyphen_values = true, parse(try_from_str = parse_range))]
+    pub range: Option<[f64; 2]>,
+
+    /// Colormap: viridis, magma, inferno or coolwarm.
+    #[structopt(long, default_value = "viridis")]
+    pub colormap: Colormap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Statistic {
+    Count,
+    Mean,
+    Median,
+}
+
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Field {
+    /// `[M/H]` in dex.
+    Metallicity,
+    /// Extinction in the G band in mag.
+    Extinction,
+    /// in km/s.
+    RadialVelocity,
+    /// Effective temperature in K.
+    Teff,
+    /// Absolute G magnitude.
+    AbsoluteMagnitude,
+    /// in kilo parsec.
+    Distance,
+    /// 1 for non-single stars, 0 otherwise.
+    Binary,
+}
+
+impl Field {
+    /// Value of the field, or `None` if it isn't known for this star.
+    fn value(&self, record: &Record) -> Option<f64> {
+        let value = match self {
+            Self::Metallicity => record.metallicity as f64,
+            Self::Extinction => record.extinction as f64,
+            Self::RadialVelocity => record.radial_velocity as f64,
+            Self::Teff => record.t_eff as f64,
+            Self::AbsoluteMagnitude if record.parallax > 0.0 => record.absolute_magnitude() as f64,
+            Self::Distance if record.parallax > 0.0 => record.distance(),
+            Self::AbsoluteMagnitude | Self::Distance => return None,
+            Self::Binary => (record.non_single_star > 0) as u8 as f64,
+        };
+        value.is_finite().then_some(value)
+    }
+
+    /// Range containing the values of most stars.
+    fn default_range(&self) -> [f64; 2] {
+        match self {
+            Self::Metallicity => [-2.0, 0.5],
+            Self::Extinction => [0.0, 3.0],
+            Self::RadialVelocity => [-100.0, 100.0],
+            Self::Teff => [3000.0, 10000.0],
+            Self::AbsoluteMagnitude => [-5.0, 15.0],
+            Self::Distance => [0.0, 10.0],
+            Self::Binary => [0.0, 1.0],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Scale {
+    Linear,
+    Log,
+}
+
+/// Number of histogram bins per pixel used to compute the median.
+const MEDIAN_BINS: usize = 32;
+
+/// Largest size of the median histograms in bytes.
+const MAX_HISTOGRAM_BYTES: usize = 4 << 30;
+
+/// Per-pixel accumulators for a [`Statistic`].
+///
+/// Every thread counts into its own map, but the median histograms are too
+/// large to have one per thread and are shared by all maps.
+struct StatisticMap<'a> {
+    statistic: Statistic,
+    width: u32,
+    height: u32,
+    /// Number of stars per pixel.
+    counts: Vec<u32>,
+    /// Sum of the values per pixel. Only used for the mean.
+    sums: Vec<f64>,
+    /// Histogram of the values per pixel, with [`MEDIAN_BINS`] bins spanning
+    /// `histogram_range`. Empty unless the statistic is the median.
+    histograms: &'a [AtomicU32],
+    histogram_range: [f64; 2],
+}
+
+impl<'a> StatisticMap<'a> {
+    /// Allocates the shared histograms for a map of the given size.
+    fn histograms(statistic: Statistic, width: u32, height: u32) -> Result<Vec<AtomicU32>, Error> {
+        if statistic != Statistic::Median {
+            return Ok(vec![]);
+        }
+
+        let num_bins = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|num_pixels| num_pixels.checked_mul(MEDIAN_BINS))
+            .filter(|num_bins| {
+                num_bins
+                    .checked_mul(size_of::<AtomicU32>())
+                    .is_some_and(|bytes| bytes <= MAX_HISTOGRAM_BYTES)
+            });
+        let Some(num_bins) = num_bins
+        else {
+            bail!("a median map of {width}x{height} pixels needs too much memory");
+        };
+
+        Ok((0..num_bins).map(|_| AtomicU32::new(0)).collect())
+    }
+
+    fn new(
+        statistic: Statistic,
+        width: u32,
+        height: u32,
+        histograms: &'a [AtomicU32],
+        histogram_range: [f64; 2],
+    ) -> Self {
+        let num_pixels = width as usize * height as usize;
+        let sums = match statistic {
+            Statistic::Mean => vec![0.0; num_pixels],
+            _ => vec![],
+        };
+
+        Self {
+            statistic,
+            width,
+            height,
+            counts: vec![0; num_pixels],
+            sums,
+            histograms,
+            histogram_range,
+        }
+    }
+
+    fn add(&mut self, [x, y]: [f64; 2], value: f64) {
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            return;
+        }
+        let index = y as usize * self.width as usize + x as usize;
+
+        self.counts[index] += 1;
+        match self.statistic {
+            Statistic::Count => {}
+            Statistic::Mean => self.sums[index] += value,
+            Statistic::Median => {
+                let [min, max] = self.histogram_range;
+                let bin = ((value - min) / (max - min) * MEDIAN_BINS as f64)
+                    .clamp(0.0, (MEDIAN_BINS - 1) as f64) as usize;
+                self.histograms[index * MEDIAN_BINS + bin].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Adds the counts and sums of another map. The histograms are shared
+    /// already.
+    fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        for (sum, other) in self.sums.iter_mut().zip(&other.sums) {
+            *sum += other;
+        }
+    }
+
+    /// Value of the statistic for the pixel, or `None` if no star fell on it.
+    fn value(&self, index: usize) -> Option<f64> {
+        let count = self.counts[index];
+        if count == 0 {
+            return None;
+        }
+
+        match self.statistic {
+            Statistic::Count => Some(count as f64),
+            Statistic::Mean => Some(self.sums[index] / count as f64),
+            Statistic::Median => {
+                // interpolate linearly within the bin containing the median.
+                let [min, max] = self.histogram_range;
+                let bin_width = (max - min) / MEDIAN_BINS as f64;
+                let histogram = &self.histograms[index * MEDIAN_BINS..][..MEDIAN_BINS];
+                let half = 0.5 * count as f64;
+
+                let mut below = 0.0;
+                for (bin, n) in histogram.iter().enumerate() {
+                    let n = n.load(Ordering::Relaxed) as f64;
+                    if n > 0.0 && below + n >= half {
+                        let fraction = (half - below) / n;
+                        return Some(min + (bin as f64 + fraction) * bin_width);
+                    }
+                    below += n;
+                }
+                Some(max)
+            }
+        }
+    }
+}
+
+/// Renders a map of `statistic` in the view set up by `viewport` and saves it
+/// with a colorbar below it.
+pub(super) async fn render_statistic(
+    output: &Path,
+    path: &Path,
+    options: &RenderOptions,
+    viewport: &Viewport,
+    statistic: Statistic,
+) -> Result<(), Error> {
+    let stats = &options.stats;
+    let field = stats.field;
+    if let Some([min, max]) = stats.range {
+        ensure!(min < max, "the range must not be empty");
+    }
+
+    let [width, height] = viewport.image_size();
+    let histogram_range = stats.range.unwrap_or_else(|| field.default_range());
+    let histograms = StatisticMap::histograms(statistic, width, height)?;
+
+    let mut maps = options
+        .select
+        .draw_parallel(
+            path,
+            || StatisticMap::new(statistic, width, height, &histograms, histogram_range),
+            |map, record| {
+                let value = match statistic {
+                    Statistic::Count => 0.0,
+                    Statistic::Mean | Statistic::Median => {
+                        let Some(value) = field.value(record)
+                        else {
+                            return;
+                        };
+                        value
+                    }
+                };
+                if let Some((position, _)) = viewport.project(record) {
+                    map.add(position, value);
+                }
+            },
+        )
+        .await?
+        .into_iter();
+
+    let mut map = maps.next().unwrap();
+    for other in maps {
+        map.merge(&other);
+    }
+
+    let values = (0..map.counts.len())
+        .map(|index| map.value(index))
+        .collect::<Vec<_>>();
+
+    let [min, max] = match stats.range {
+        Some(range) => range,
+        None if statistic == Statistic::Count => {
+            let max_count = map.counts.iter().copied().max().unwrap_or_default();
+            [1.0, (max_count as f64).max(2.0)]
+        }
+        None => field.default_range(),
+    };
+    let scale = stats.scale.unwrap_or(match statistic {
+        Statistic::Count => Scale::Log,
+        Statistic::Mean | Statistic::Median => Scale::Linear,
+    });
+    ensure!(
+        scale == Scale::Linear || min > 0.0,
+        "the log scale needs a positive range"
+    );
+
+    let normalize = |value: f64| {
+        match scale {
+            Scale::Linear => (value - min) / (max - min),
+            Scale::Log => (value.max(min).ln() - min.ln()) / (max.ln() - min.ln()),
+        }
+    };
+
+    let mut image = RgbImage::from_fn(width, height, |x, y| {
+        match values[y as usize * width as usize + x as usize] {
+            Some(value) => stats.colormap.sample(normalize(value)),
+            None => Rgb([0, 0, 0]),
+        }
+    });
+    viewport.draw_overlay(&mut image);
+
+    let center = match scale {
+        Scale::Linear => 0.5 * (min + max),
+        Scale::Log => (min * max).sqrt(),
+    };
+    let image = with_colorbar(&image, stats.colormap, [min, center, max]);
+
+    tracing::info!("writing image: {}", output.display());
+    image.save(output)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_median_histograms() {
+        assert!(StatisticMap::histograms(Statistic::Median, 65536, 65536).is_err());
+        assert!(StatisticMap::histograms(Statistic::Mean, 65536, 65536).is_ok());
+
+        let histograms = StatisticMap::histograms(Statistic::Median, 2, 1).unwrap();
+        let mut maps =
+            [0, 1].map(|_| StatisticMap::new(Statistic::Median, 2, 1, &histograms, [0.0, 32.0]));
+        for (i, value) in [1.5, 2.5, 3.5, 20.5].into_iter().enumerate() {
+            maps[i % 2].add([0.5, 0.5], value);
+        }
+
+        let [mut map, other] = maps;
+        map.merge(&other);
+        assert_eq!(map.value(0), Some(3.0));
+        assert_eq!(map.value(1), None);
+    }
+}
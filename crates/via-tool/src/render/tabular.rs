@@ -0,0 +1,548 @@
+//! Tabular exports as Arrow IPC, Parquet, FITS or VOTable.
+//!
+//! Unlike the binary exports, these keep the columns of the Gaia archive, so
+//! the data can be analysed with other tools like pandas, DuckDB or TOPCAT.
+//! Any field of [`GaiaSource`](crate::gaia::GaiaSource) or
+//! [`AstrophysicalParameters`](crate::gaia::AstrophysicalParameters) can be
+//! exported, and missing values stay nulls.
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    sync::Arc,
+};
+
+use arrow::{
+    array::{
+        ArrayRef,
+        BooleanBuilder,
+        Float32Builder,
+        Float64Builder,
+        Int16Builder,
+        Int32Builder,
+        Int64Builder,
+        Int8Builder,
+        StringBuilder,
+        UInt64Builder,
+    },
+    datatypes::{
+        DataType,
+        Field,
+        Schema,
+        SchemaRef,
+    },
+    ipc::{
+        writer::{
+            FileWriter,
+            IpcWriteOptions,
+        },
+        CompressionType,
+    },
+    record_batch::RecordBatch,
+};
+use color_eyre::eyre::{
+    bail,
+    ensure,
+};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{
+        Compression,
+        GzipLevel,
+        ZstdLevel,
+    },
+    file::properties::WriterProperties,
+};
+use serde_json::Value;
+use structopt::StructOpt;
+
+use super::{
+    fits::FitsWriter,
+    format::Provenance,
+    progress_bar,
+    sample::{
+        SampleKey,
+        Sampler,
+    },
+    votable::VoTableWriter,
+    ExportFormat,
+};
+use crate::{
+    filter::Filter,
+    gaia::{
+        Column,
+        ColumnType,
+        Data,
+        Row,
+        Table,
+    },
+    Error,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct TableOptions {
+    /// Columns of tabular exports, separated by commas. Names can
+    /// be qualified with their table, like
+    /// `astrophysical_parameters.teff_gspphot`, and `<table>.*` selects all
+    /// columns of a table that aren't selected yet. Unqualified names are
+    /// looked up in `gaia_source` first. Columns of `astrophysical_parameters`
+    /// that share their name with a selected `gaia_source` column are written
+    /// with their qualified name. Defaults to all columns of `gaia_source`.
+    #[structopt(long, use_delimiter = true)]
+    pub columns: Vec<String>,
+
+    /// Rows per Parquet row group or Arrow record batch.
+    #[structopt(long, default_value = "1048576")]
+    pub row_group_size: usize,
+
+    /// Compression of Arrow and Parquet exports: none, lz4, zstd, or for
+    /// Parquet also snappy and gzip.
+    #[structopt(long, default_value = "zstd")]
+    pub compression: TableCompression,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum TableCompression {
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+    Gzip,
+}
+
+/// Resolves the column names given on the command line.
+///
+/// Columns of `astrophysical_parameters` whose name is also selected from
+/// `gaia_source` are written with their qualified name.
+pub(super) fn select_columns(names: &[String]) -> Result<Vec<Column>, Error> {
+    if names.is_empty() {
+        return Column::all(Table::GaiaSource);
+    }
+
+    let mut selected: Vec<Column> = vec![];
+    let mut wildcards = vec![];
+
+    for name in names {
+        if let Some(table) = name.strip_suffix(".*") {
+            // added last, so that explicitly selected columns take precedence
+            wildcards.push(Table::from_name(table)?);
+            continue;
+        }
+
+        let column = Column::find(name)?;
+        ensure!(
+            selected
+                .iter()
+                .all(|selected| (selected.table, selected.name) != (column.table, column.name)),
+            "column {}.{} is selected twice",
+            column.table.name(),
+            column.name
+        );
+        selected.push(column);
+    }
+
+    for table in wildcards {
+        let names = selected
+            .iter()
+            .map(|column| (column.table, column.name))
+            .collect::<HashSet<_>>();
+        selected.extend(
+            Column::all(table)?
+                .into_iter()
+                .filter(|column| !names.contains(&(column.table, column.name))),
+        );
+    }
+
+    let gaia_source_names = selected
+        .iter()
+        .filter(|column| column.table == Table::GaiaSource)
+        .map(|column| column.name)
+        .collect::<HashSet<_>>();
+    for column in &mut selected {
+        column.qualified =
+            column.table != Table::GaiaSource && gaia_source_names.contains(column.name);
+    }
+
+    Ok(selected)
+}
+
+enum ColumnBuilder {
+    Boolean(BooleanBuilder),
+    Int8(Int8Builder),
+    Int16(Int16Builder),
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    UInt64(UInt64Builder),
+    Float32(Float32Builder),
+    Float64(Float64Builder),
+    Utf8(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(column_type: ColumnType) -> Self {
+        match column_type {
+            ColumnType::Bool => Self::Boolean(BooleanBuilder::new()),
+            ColumnType::I8 => Self::Int8(Int8Builder::new()),
+            ColumnType::I16 => Self::Int16(Int16Builder::new()),
+            ColumnType::I32 => Self::Int32(Int32Builder::new()),
+            ColumnType::I64 => Self::Int64(Int64Builder::new()),
+            ColumnType::U64 => Self::UInt64(UInt64Builder::new()),
+            ColumnType::F32 => Self::Float32(Float32Builder::new()),
+            ColumnType::F64 => Self::Float64(Float64Builder::new()),
+            ColumnType::String => Self::Utf8(StringBuilder::new()),
+        }
+    }
+
+    /// Appends a value as serialized by serde_json. Missing values are `null`.
+    fn append(&mut self, value: &Value) {
+        match self {
+            Self::Boolean(builder) => builder.append_option(value.as_bool()),
+            Self::Int8(builder) => builder.append_option(integer(value)),
+            Self::Int16(builder) => builder.append_option(integer(value)),
+            Self::Int32(builder) => builder.append_option(integer(value)),
+            Self::Int64(builder) => builder.append_option(value.as_i64()),
+            Self::UInt64(builder) => builder.append_option(value.as_u64()),
+            Self::Float32(builder) => builder.append_option(value.as_f64().map(|x| x as f32)),
+            Self::Float64(builder) => builder.append_option(value.as_f64()),
+            Self::Utf8(builder) => builder.append_option(value.as_str()),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Self::Boolean(builder) => Arc::new(builder.finish()),
+            Self::Int8(builder) => Arc::new(builder.finish()),
+            Self::Int16(builder) => Arc::new(builder.finish()),
+            Self::Int32(builder) => Arc::new(builder.finish()),
+            Self::Int64(builder) => Arc::new(builder.finish()),
+            Self::UInt64(builder) => Arc::new(builder.finish()),
+            Self::Float32(builder) => Arc::new(builder.finish()),
+            Self::Float64(builder) => Arc::new(builder.finish()),
+            Self::Utf8(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+fn data_type(column_type: ColumnType) -> DataType {
+    match column_type {
+        ColumnType::Bool => DataType::Boolean,
+        ColumnType::I8 => DataType::Int8,
+        ColumnType::I16 => DataType::Int16,
+        ColumnType::I32 => DataType::Int32,
+        ColumnType::I64 => DataType::Int64,
+        ColumnType::U64 => DataType::UInt64,
+        ColumnType::F32 => DataType::Float32,
+        ColumnType::F64 => DataType::Float64,
+        ColumnType::String => DataType::Utf8,
+    }
+}
+
+pub(super) fn integer<T: TryFrom<i64>>(value: &Value) -> Option<T> {
+    value.as_i64()?.try_into().ok()
+}
+
+/// Collects rows into record batches.
+struct BatchBuilder {
+    schema: SchemaRef,
+    columns: Vec<Column>,
+    builders: Vec<ColumnBuilder>,
+    num_rows: usize,
+}
+
+impl BatchBuilder {
+    fn new(columns: Vec<Column>, provenance: &Provenance) -> Result<Self, Error> {
+        let fields = columns
+            .iter()
+            .map(|column| Field::new(column.output_name(), data_type(column.column_type), true))
+            .collect::<Vec<_>>();
+        let metadata = HashMap::from([(
+            "via.provenance".to_owned(),
+            serde_json::to_string(provenance)?,
+        )]);
+        let builders = columns
+            .iter()
+            .map(|column| ColumnBuilder::new(column.column_type))
+            .collect();
+
+        Ok(Self {
+            schema: Arc::new(Schema::new_with_metadata(fields, metadata)),
+            columns,
+            builders,
+            num_rows: 0,
+        })
+    }
+
+    fn append(&mut self, row: &Row) {
+        for (column, builder) in self.columns.iter().zip(&mut self.builders) {
+            builder.append(row.get(column));
+        }
+        self.num_rows += 1;
+    }
+
+    fn finish(&mut self) -> Result<RecordBatch, Error> {
+        let arrays = self
+            .builders
+            .iter_mut()
+            .map(ColumnBuilder::finish)
+            .collect();
+        self.num_rows = 0;
+        Ok(RecordBatch::try_new(self.schema.clone(), arrays)?)
+    }
+}
+
+enum TableWriter {
+    Arrow(FileWriter<BufWriter<File>>),
+    Parquet(ArrowWriter<BufWriter<File>>),
+}
+
+impl TableWriter {
+    fn create(
+        path: &Path,
+        format: ExportFormat,
+        schema: SchemaRef,
+        options: &TableOptions,
+    ) -> Result<Self, Error> {
+        let compression = options.compression;
+
+        let writer = match format {
+            ExportFormat::Arrow => {
+                let compression = match compression {
+                    TableCompression::None => None,
+                    TableCompression::Lz4 => Some(CompressionType::LZ4_FRAME),
+                    TableCompression::Zstd => Some(CompressionType::ZSTD),
+                    TableCompression::Snappy | TableCompression::Gzip => {
+                        bail!("{compression:?} compression is only supported for Parquet")
+                    }
+                };
+                let options = IpcWriteOptions::default().try_with_compression(compression)?;
+                let file = BufWriter::new(File::create(path)?);
+                Self::Arrow(FileWriter::try_new_with_options(file, &schema, options)?)
+            }
+            ExportFormat::Parquet => {
+                let compression = match compression {
+                    TableCompression::None => Compression::UNCOMPRESSED,
+                    TableCompression::Lz4 => Compression::LZ4_RAW,
+                    TableCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+                    TableCompression::Snappy => Compression::SNAPPY,
+                    TableCompression::Gzip => Compression::GZIP(GzipLevel::default()),
+                };
+                let properties = WriterProperties::builder()
+                    .set_compression(compression)
+                    .set_max_row_group_size(options.row_group_size)
+                    .build();
+                let file = BufWriter::new(File::create(path)?);
+                Self::Parquet(ArrowWriter::try_new(file, schema, Some(properties))?)
+            }
+            ExportFormat::Native | ExportFormat::Fits | ExportFormat::VoTable => {
+                bail!("not an Arrow format: {format:?}")
+            }
+        };
+
+        Ok(writer)
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), Error> {
+        tokio::task::block_in_place(|| {
+            match self {
+                Self::Arrow(writer) => writer.write(batch)?,
+                Self::Parquet(writer) => writer.write(batch)?,
+            }
+            Ok(())
+        })
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        tokio::task::block_in_place(|| {
+            match self {
+                Self::Arrow(mut writer) => writer.finish()?,
+                Self::Parquet(writer) => {
+                    writer.close()?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Where the rows of an export go.
+enum RowSink {
+    Batches {
+        batch: BatchBuilder,
+        writer: TableWriter,
+        row_group_size: usize,
+    },
+    Fits(FitsWriter),
+    VoTable(VoTableWriter),
+}
+
+impl RowSink {
+    async fn create(
+        path: &Path,
+        format: ExportFormat,
+        columns: Vec<Column>,
+        options: &TableOptions,
+        provenance: &Provenance,
+    ) -> Result<Self, Error> {
+        let sink = match format {
+            ExportFormat::Fits => Self::Fits(FitsWriter::create(path, columns, provenance).await?),
+            ExportFormat::VoTable => {
+                Self::VoTable(VoTableWriter::create(path, columns, provenance).await?)
+            }
+            _ => {
+                let batch = BatchBuilder::new(columns, provenance)?;
+                let writer = TableWriter::create(path, format, batch.schema.clone(), options)?;
+                Self::Batches {
+                    batch,
+                    writer,
+                    row_group_size: options.row_group_size,
+                }
+            }
+        };
+
+        Ok(sink)
+    }
+
+    async fn append(&mut self, row: &Row) -> Result<(), Error> {
+        match self {
+            Self::Batches {
+                batch,
+                writer,
+                row_group_size,
+            } => {
+                batch.append(row);
+                if batch.num_rows >= *row_group_size {
+                    writer.write(&batch.finish()?)?;
+                }
+            }
+            Self::Fits(writer) => writer.write_row(row).await?,
+            Self::VoTable(writer) => writer.write_row(row).await?,
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<(), Error> {
+        match self {
+            Self::Batches {
+                mut batch,
+                mut writer,
+                ..
+            } => {
+                if batch.num_rows > 0 {
+                    writer.write(&batch.finish()?)?;
+                }
+                writer.finish()
+            }
+            Self::Fits(writer) => writer.finish().await,
+            Self::VoTable(writer) => writer.finish().await,
+        }
+    }
+}
+
+/// Exports the Gaia records with the selected columns as Arrow IPC, Parquet,
+/// FITS or VOTable. Unlike binary exports, records with missing positions or
+/// photometry are kept.
+pub async fn export(
+    output: &Path,
+    path: &Path,
+    format: ExportFormat,
+    mut sampler: Sampler<Row>,
+    filter: Option<&Filter<Column>>,
+    options: &TableOptions,
+    provenance: &Provenance,
+) -> Result<(), Error> {
+    ensure!(
+        options.row_group_size > 0,
+        "the row group size must be positive"
+    );
+
+    let columns = select_columns(&options.columns)?;
+    let mut sink = RowSink::create(output, format, columns.clone(), options, provenance).await?;
+    let filter_columns = filter.map(Filter::fields).unwrap_or_default();
+
+    let data = Data::open(path).await?;
+    let mut records = data.records();
+    let (_, num_partitions) = records.progress();
+    let progress_bar = progress_bar(num_partitions as _);
+    let mut sampled = vec![];
+
+    while let Some(record) = records.read_record().await? {
+        let row = Row::new(
+            &record,
+            columns.iter().chain(filter_columns.iter().copied()),
+        )?;
+
+        if filter.is_none_or(|filter| filter.matches(|column| row.get(column).into()))
+            && sampler.push(SampleKey::from(&record), row, &mut sampled)
+        {
+            records.skip_file();
+        }
+
+        for row in sampled.drain(..) {
+            sink.append(&row).await?;
+        }
+
+        let (progress, _) = records.progress();
+        progress_bar.set_position(progress as _);
+    }
+
+    sampler.finish(&mut sampled);
+    for row in &sampled {
+        sink.append(row).await?;
+    }
+    sink.finish().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_qualified_names_and_wildcards() {
+        let names = [
+            "ra",
+            "astrophysical_parameters.solution_id",
+            "gaia_source.*",
+        ]
+        .map(str::to_owned);
+        let selected = select_columns(&names).unwrap();
+
+        assert_eq!(selected[0].name, "ra");
+        assert_eq!(selected[1].name, "solution_id");
+        assert_eq!(selected[1].table, Table::AstrophysicalParameters);
+        assert_eq!(
+            selected.iter().filter(|column| column.name == "ra").count(),
+            1
+        );
+        assert!(selected.iter().any(|column| column.name == "source_id"));
+        assert!(select_columns(&["unknown".to_owned()]).is_err());
+        assert!(select_columns(&["ra".to_owned(), "ra".to_owned()]).is_err());
+
+        // columns with the same name in both tables are all kept
+        let output_names = selected
+            .iter()
+            .map(|column| column.output_name())
+            .collect::<Vec<_>>();
+        assert_eq!(output_names[1], "astrophysical_parameters.solution_id");
+        assert!(output_names.iter().any(|name| name == "solution_id"));
+        assert_eq!(
+            output_names.iter().collect::<HashSet<_>>().len(),
+            output_names.len()
+        );
+
+        let selected =
+            select_columns(&["source_id", "astrophysical_parameters.*"].map(str::to_owned))
+                .unwrap();
+        assert_eq!(selected[0].output_name(), "source_id");
+        assert!(selected
+            .iter()
+            .any(|column| column.output_name() == "astrophysical_parameters.source_id"));
+    }
+}
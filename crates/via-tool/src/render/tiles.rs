@@ -0,0 +1,280 @@
+//! Renders the top-down view into an XYZ tile pyramid, as used by slippy
+//! maps.
+//!
+//! Tiles are written to `<output>/<z>/<x>/<y>.png`. At zoom level 0 a single
+//! tile covers the whole map, centered on the sun. Every level is rendered
+//! from the records directly, so stars are drawn with the same PSF at all
+//! levels.
+//!
+//! The export is read once to find the tiles stars reach, and then once for
+//! every batch of these tiles that fits into the memory limit.
+
+use std::{
+    collections::{
+        hash_map::Entry,
+        HashMap,
+        HashSet,
+    },
+    ops::RangeInclusive,
+    path::Path,
+};
+
+use color_eyre::eyre::ensure;
+use structopt::StructOpt;
+
+use super::{
+    canvas::Canvas,
+    flux,
+    psf::Psf,
+    Record,
+    SelectOptions,
+    ToneMap,
+    View,
+};
+use crate::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct TilesOptions {
+    /// Lowest zoom level.
+    #[structopt(long, default_value = "0")]
+    pub min_zoom: u8,
+
+    /// Highest zoom level.
+    #[structopt(long, default_value = "8")]
+    pub max_zoom: u8,
+
+    /// Width and height of a tile in pixels.
+    #[structopt(long, default_value = "256")]
+    pub tile_size: u32,
+
+    /// Half the width of the map in kilo parsec.
+    #[structopt(long, default_value = "60")]
+    pub radius: f64,
+
+    /// Approximate memory in MiB used for tiles, by all threads together. The
+    /// export is read once for every batch of tiles that fits, plus once to
+    /// find the tiles.
+    #[structopt(long, default_value = "4096")]
+    pub memory_limit: u64,
+
+    /// Tone mapping operator: linear, log, asinh, reinhard or aces.
+    #[structopt(short, long, default_value = "asinh")]
+    pub tone_map: ToneMap,
+
+    /// Exposure in stops.
+    #[structopt(short, long, default_value = "0", allow_hyphen_values = true)]
+    pub exposure: f32,
+
+    #[structopt(flatten)]
+    pub psf: Psf,
+
+    #[structopt(flatten)]
+    pub select: SelectOptions,
+}
+
+impl TilesOptions {
+    /// Highest supported zoom level.
+    const MAX_ZOOM: u8 = 24;
+
+    /// Position of a star in pixels at the zoom level, with the origin in the
+    /// top-left corner of the map.
+    fn pixel_position(&self, record: &Record, zoom: u8) -> [f64; 2] {
+        let map_size = (self.tile_size as f64) * (1u64 << zoom) as f64;
+        let scale = 0.5 * map_size / self.radius;
+
+        let position = record.position();
+        [
+            position.x * scale + 0.5 * map_size,
+            position.y * scale + 0.5 * map_size,
+        ]
+    }
+
+    /// Flux of a star and the radius in pixels it's drawn with, or `None` if
+    /// it isn't drawn at all. The radius is the same at every zoom level.
+    fn footprint(&self, record: &Record) -> Option<(f32, f64)> {
+        if record.parallax <= 0.0 {
+            return None;
+        }
+        let flux = flux(
+            record.absolute_magnitude(),
+            View::TOP_DOWN_REFERENCE_MAGNITUDE,
+        );
+        let radius = self.psf.radius(flux).min(self.tile_size as f64);
+        Some((flux, radius))
+    }
+
+    /// Tiles along an axis reached by a star at `position` in pixels. As the
+    /// radius is at most a tile, these are at most 3.
+    fn tile_span(&self, position: f64, radius: f64, zoom: u8) -> RangeInclusive<i64> {
+        let tile_size = self.tile_size as f64;
+        let first = ((position - radius) / tile_size).floor() as i64;
+        let last = ((position + radius) / tile_size).floor() as i64;
+        first.max(0)..=last.min((1i64 << zoom) - 1)
+    }
+}
+
+/// A tile as its zoom level, column and row. Sorted tiles are ordered by zoom
+/// level, then column, then row.
+type Tile = (u8, u32, u32);
+
+/// Finds every tile reached by a star.
+async fn find_tiles(path: &Path, options: &TilesOptions) -> Result<Vec<Tile>, Error> {
+    let mut threads = options
+        .select
+        .draw_parallel(
+            path,
+            HashSet::<Tile>::new,
+            |tiles, record| {
+                let Some((_, radius)) = options.footprint(record)
+                else {
+                    return;
+                };
+                for zoom in options.min_zoom..=options.max_zoom {
+                    let [x, y] = options.pixel_position(record, zoom);
+                    for column in options.tile_span(x, radius, zoom) {
+                        for row in options.tile_span(y, radius, zoom) {
+                            tiles.insert((zoom, column as u32, row as u32));
+                        }
+                    }
+                }
+            },
+        )
+        .await?
+        .into_iter();
+
+    let mut tiles = threads.next().unwrap();
+    for other in threads {
+        tiles.extend(other);
+    }
+    let mut tiles = tiles.into_iter().collect::<Vec<_>>();
+    tiles.sort_unstable();
+    Ok(tiles)
+}
+
+/// Number of tiles in a pass, so that they fit into the memory limit. Every
+/// thread might draw on all tiles of the pass.
+fn pass_size(options: &TilesOptions) -> usize {
+    let tile_bytes = 3 * 4 * (options.tile_size as u64).pow(2);
+    let jobs = options.select.num_jobs() as u64;
+    (options.memory_limit * 1024 * 1024 / (tile_bytes * jobs)).max(1) as usize
+}
+
+pub async fn tiles(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: TilesOptions,
+) -> Result<(), Error> {
+    ensure!(
+        options.min_zoom <= options.max_zoom,
+        "min zoom must not be greater than max zoom"
+    );
+    ensure!(
+        options.max_zoom <= TilesOptions::MAX_ZOOM,
+        "max zoom must not be greater than {}",
+        TilesOptions::MAX_ZOOM
+    );
+
+    let path = path.as_ref();
+    let output = output.as_ref();
+    let tile_size = options.tile_size as f64;
+
+    let tiles = find_tiles(path, &options).await?;
+    let passes = tiles.chunks(pass_size(&options)).collect::<Vec<_>>();
+
+    for (i, pass) in passes.iter().enumerate() {
+        tracing::info!(pass = i + 1, num_passes = passes.len(), "rendering tiles");
+
+        // the tiles are sorted, so the pass covers a range of zoom levels.
+        let zooms = pass[0].0..=pass[pass.len() - 1].0;
+
+        let mut threads = options
+            .select
+            .draw_parallel(
+                path,
+                HashMap::<Tile, Canvas>::new,
+                |tiles, record| {
+                    let Some((flux, radius)) = options.footprint(record)
+                    else {
+                        return;
+                    };
+
+                    for zoom in zooms.clone() {
+                        let [x, y] = options.pixel_position(record, zoom);
+
+                        // the star might extend into neighbouring tiles
+                        for column in options.tile_span(x, radius, zoom) {
+                            for row in options.tile_span(y, radius, zoom) {
+                                let tile = (zoom, column as u32, row as u32);
+                                if pass.binary_search(&tile).is_err() {
+                                    continue;
+                                }
+                                let canvas = tiles.entry(tile).or_insert_with(|| {
+                                    Canvas::new(options.tile_size, options.tile_size)
+                                });
+                                options.psf.draw(
+                                    canvas,
+                                    [x - column as f64 * tile_size, y - row as f64 * tile_size],
+                                    record.color(),
+                                    flux,
+                                );
+                            }
+                        }
+                    }
+                },
+            )
+            .await?
+            .into_iter();
+
+        let mut tiles = threads.next().unwrap();
+        for other in threads {
+            for (key, canvas) in other {
+                match tiles.entry(key) {
+                    Entry::Occupied(mut entry) => entry.get_mut().merge(&canvas),
+                    Entry::Vacant(entry) => {
+                        entry.insert(canvas);
+                    }
+                }
+            }
+        }
+
+        for ((zoom, column, row), canvas) in tiles {
+            let directory = output.join(zoom.to_string()).join(column.to_string());
+            tokio::fs::create_dir_all(&directory).await?;
+            canvas
+                .tone_map(options.tone_map, options.exposure)
+                .save(directory.join(format!("{row}.png")))?;
+        }
+    }
+
+    let metadata = serde_json::json!({
+        "tile_size": options.tile_size,
+        "min_zoom": options.min_zoom,
+        "max_zoom": options.max_zoom,
+        "radius_kpc": options.radius,
+        "center": "sun",
+    });
+    tokio::fs::write(
+        output.join("metadata.json"),
+        serde_json::to_vec_pretty(&metadata)?,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars_reach_neighbouring_tiles() {
+        let options = TilesOptions::from_iter(["tiles", "--tile-size", "256"]);
+
+        assert_eq!(options.tile_span(300.0, 3.0, 2), 1..=1);
+        assert_eq!(options.tile_span(254.0, 3.0, 2), 0..=1);
+        assert_eq!(options.tile_span(300.0, 256.0, 2), 0..=2);
+        // clipped to the map
+        assert_eq!(options.tile_span(1000.0, 256.0, 2), 2..=3);
+        assert!(options.tile_span(-300.0, 3.0, 2).is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+use palette::LinSrgb;
+
+/// Tone mapping operators.
+///
+/// All operators are applied to the luminance of a pixel and the color is
+/// scaled accordingly, so that the hue of bright stars is preserved instead of
+/// every channel clipping to white.
+#[derive(Clone, Copy, Debug, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ToneMap {
+    /// Clips everything above 1.
+    Linear,
+
+    /// Logarithmic, compressing [`ToneMap::LOG_RANGE`] decades into the
+    /// output range.
+    Log,
+
+    /// Inverse hyperbolic sine as used by Lupton et al. (2004). Linear for
+    /// faint values and logarithmic for bright ones.
+    Asinh,
+
+    /// Reinhard et al. (2002): `x / (1 + x)`.
+    Reinhard,
+
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+impl ToneMap {
+    /// Number of decades mapped to the output range by [`ToneMap::Log`].
+    const LOG_RANGE: f32 = 3.0;
+
+    /// Softening parameter of [`ToneMap::Asinh`].
+    const ASINH_SOFTENING: f32 = 0.1;
+
+    /// Maps a luminance value to the range `[0, 1]`.
+    pub fn map(&self, x: f32) -> f32 {
+        let y = match self {
+            Self::Linear => x,
+            Self::Log => {
+                let k = 10.0f32.powf(Self::LOG_RANGE);
+                (1.0 + k * x).ln() / (1.0 + k).ln()
+            }
+            Self::Asinh => {
+                (x / Self::ASINH_SOFTENING).asinh() / (1.0 / Self::ASINH_SOFTENING).asinh()
+            }
+            Self::Reinhard => x / (1.0 + x),
+            Self::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        };
+        y.clamp(0.0, 1.0)
+    }
+
+    /// Tone maps a linear color.
+    pub fn apply(&self, color: LinSrgb) -> LinSrgb {
+        let luminance = 0.2126 * color.red + 0.7152 * color.green + 0.0722 * color.blue;
+        if luminance <= 0.0 {
+            return LinSrgb::new(0.0, 0.0, 0.0);
+        }
+
+        let color = color * (self.map(luminance) / luminance);
+
+        LinSrgb::new(
+            color.red.min(1.0),
+            color.green.min(1.0),
+            color.blue.min(1.0),
+        )
+    }
+}
@@ -0,0 +1,228 @@
+//! VOTables, the XML tables of the Virtual Observatory.
+//!
+//! Every column is a `FIELD` with the unit and description from the doc
+//! comments of the model, and its UCD if it's known. The rows are written as
+//! `TABLEDATA`, with empty cells for missing values, and the provenance as
+//! `INFO` elements of the resource.
+
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    path::Path,
+};
+
+use serde_json::Value;
+use tokio::{
+    fs::File,
+    io::{
+        AsyncWriteExt,
+        BufWriter,
+    },
+};
+
+use super::format::Provenance;
+use crate::{
+    gaia::{
+        Column,
+        ColumnType,
+        Row,
+    },
+    Error,
+};
+
+/// Escapes text for XML content and attribute values.
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn datatype(column_type: ColumnType) -> &'static str {
+    match column_type {
+        ColumnType::Bool => "boolean",
+        // VOTables have no signed bytes
+        ColumnType::I8 | ColumnType::I16 => "short",
+        ColumnType::I32 => "int",
+        // nor unsigned longs, but source ids are below 2^63
+        ColumnType::I64 | ColumnType::U64 => "long",
+        ColumnType::F32 => "float",
+        ColumnType::F64 => "double",
+        ColumnType::String => "char",
+    }
+}
+
+fn header(columns: &[Column], provenance: &Provenance) -> Result<String, Error> {
+    let mut header = String::new();
+    writeln!(header, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        header,
+        r#"<VOTABLE version="1.4" xmlns="http://www.ivoa.net/xml/VOTable/v1.3">"#
+    )?;
+    writeln!(header, r#"  <RESOURCE type="results">"#)?;
+    writeln!(
+        header,
+        "    <DESCRIPTION>{}</DESCRIPTION>",
+        escape(&provenance.dataset)
+    )?;
+
+    let infos = [
+        ("path", &provenance.path),
+        ("created", &provenance.created),
+        ("tool_version", &provenance.tool_version),
+    ]
+    .into_iter()
+    .chain(provenance.filters.iter().map(|filter| ("filter", filter)));
+    for (name, value) in infos {
+        writeln!(
+            header,
+            r#"    <INFO name="{name}" value="{}"/>"#,
+            escape(value)
+        )?;
+    }
+
+    writeln!(header, r#"    <TABLE name="gaia">"#)?;
+    for column in columns {
+        write!(
+            header,
+            r#"      <FIELD name="{}" datatype="{}""#,
+            column.output_name(),
+            datatype(column.column_type)
+        )?;
+        if column.column_type == ColumnType::String {
+            write!(header, r#" arraysize="*""#)?;
+        }
+        let doc = column.doc();
+        if let Some(unit) = doc.and_then(|doc| doc.unit) {
+            write!(header, r#" unit="{}""#, escape(unit))?;
+        }
+        if let Some(ucd) = column.ucd() {
+            write!(header, r#" ucd="{ucd}""#)?;
+        }
+
+        match doc.filter(|doc| !doc.description.is_empty()) {
+            Some(doc) => {
+                writeln!(header, ">")?;
+                writeln!(
+                    header,
+                    "        <DESCRIPTION>{}</DESCRIPTION>",
+                    escape(&doc.description)
+                )?;
+                writeln!(header, "      </FIELD>")?;
+            }
+            None => writeln!(header, "/>")?,
+        }
+    }
+    writeln!(header, "      <DATA>")?;
+    writeln!(header, "        <TABLEDATA>")?;
+
+    Ok(header)
+}
+
+const FOOTER: &str = "        </TABLEDATA>
+      </DATA>
+    </TABLE>
+  </RESOURCE>
+</VOTABLE>
+";
+
+pub struct VoTableWriter {
+    writer: BufWriter<File>,
+    columns: Vec<Column>,
+    row: String,
+}
+
+impl VoTableWriter {
+    pub async fn create(
+        path: &Path,
+        columns: Vec<Column>,
+        provenance: &Provenance,
+    ) -> Result<Self, Error> {
+        let mut writer = BufWriter::new(File::create(path).await?);
+        writer
+            .write_all(header(&columns, provenance)?.as_bytes())
+            .await?;
+
+        Ok(Self {
+            writer,
+            columns,
+            row: String::new(),
+        })
+    }
+
+    pub async fn write_row(&mut self, row: &Row) -> Result<(), Error> {
+        self.row.clear();
+        self.row.push_str("<TR>");
+
+        for column in &self.columns {
+            match (column.column_type, row.get(column)) {
+                (_, Value::Null) => self.row.push_str("<TD/>"),
+                (ColumnType::F32, Value::Number(value)) => {
+                    // shortest representation of the f32, not the f64
+                    let value = value.as_f64().map(|value| value as f32);
+                    write!(self.row, "<TD>{}</TD>", value.unwrap_or(f32::NAN))?;
+                }
+                (_, Value::String(value)) => write!(self.row, "<TD>{}</TD>", escape(value))?,
+                (_, value) => write!(self.row, "<TD>{value}</TD>")?,
+            }
+        }
+
+        self.row.push_str("</TR>\n");
+        self.writer.write_all(self.row.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<(), Error> {
+        self.writer.write_all(FOOTER.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writes_fields_and_rows() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let columns = ["source_id", "designation", "ra_error", "teff_gspphot"]
+            .map(|name| Column::find(name).unwrap())
+            .to_vec();
+
+        let mut writer = VoTableWriter::create(&path, columns, &Provenance::default())
+            .await
+            .unwrap();
+        let row = Row::from_json(
+            json!({ "source_id": 7, "designation": "<Gaia> & co", "ra_error": 0.1f32 }),
+            Value::Null,
+        );
+        writer.write_row(&row).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let votable = std::fs::read_to_string(&path).unwrap();
+        assert!(votable.contains(
+            r#"<FIELD name="ra_error" datatype="float" unit="mas" ucd="stat.error;pos.eq.ra">"#
+        ));
+        assert!(votable.contains(r#"<FIELD name="designation" datatype="char" arraysize="*""#));
+        assert!(
+            votable.contains("<TR><TD>7</TD><TD>&lt;Gaia&gt; &amp; co</TD><TD>0.1</TD><TD/></TR>")
+        );
+        assert!(votable.ends_with("</VOTABLE>\n"));
+    }
+}
@@ -0,0 +1,244 @@
+//! HEALPix in the nested scheme, as described by [Górski et al. (2005)][1].
+//!
+//! [1]: https://doi.org/10.1086/427976
+
+use std::f64::consts::{
+    FRAC_PI_2,
+    FRAC_PI_4,
+    TAU,
+};
+
+use nalgebra::Vector3;
+
+/// Deepest order for which pixel indices and the coordinates within a face fit
+/// into 64 and 32 bits.
+pub const MAX_ORDER: u8 = 29;
+
+/// Number of pixels of the given order.
+pub fn num_pixels(order: u8) -> u64 {
+    debug_assert!(order <= MAX_ORDER);
+    12 << (2 * order as u64)
+}
+
+/// Pixel of the given order containing a Gaia source. Gaia source ids start
+/// with the pixel of order 12, followed by 35 other bits.
+pub fn gaia_source_pixel(source_id: u64, order: u8) -> u64 {
+    source_id >> (35 + 2 * (12 - order as u32))
+}
+
+/// Interleaves the bits of `x` and `y`, with `x` in the even bits.
+pub fn interleave(x: u32, y: u32) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000ffff0000ffff;
+        v = (v | (v << 8)) & 0x00ff00ff00ff00ff;
+        v = (v | (v << 4)) & 0x0f0f0f0f0f0f0f0f;
+        v = (v | (v << 2)) & 0x3333333333333333;
+        v = (v | (v << 1)) & 0x5555555555555555;
+        v
+    }
+
+    spread(x) | (spread(y) << 1)
+}
+
+/// Inverse of [`interleave`].
+pub fn deinterleave(i: u64) -> (u32, u32) {
+    fn compact(mut v: u64) -> u32 {
+        v &= 0x5555555555555555;
+        v = (v | (v >> 1)) & 0x3333333333333333;
+        v = (v | (v >> 2)) & 0x0f0f0f0f0f0f0f0f;
+        v = (v | (v >> 4)) & 0x00ff00ff00ff00ff;
+        v = (v | (v >> 8)) & 0x0000ffff0000ffff;
+        v = (v | (v >> 16)) & 0x00000000ffffffff;
+        v as u32
+    }
+
+    (compact(i), compact(i >> 1))
+}
+
+/// Ring of the southern corner of each base pixel, in units of `nside`.
+const FACE_RINGS: [u8; 12] = [2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4];
+
+/// Longitude of the southern corner of each base pixel, in units of `pi / 4`.
+const FACE_LONGITUDES: [u8; 12] = [1, 3, 5, 7, 0, 2, 4, 6, 1, 3, 5, 7];
+
+/// Continuous position on one of the 12 base pixels.
+///
+/// `x` and `y` are in `[0, 1]`, with `(0, 0)` at the southern corner of the
+/// face, `x` increasing towards the eastern corner and `y` towards the western
+/// corner.
+#[derive(Clone, Copy, Debug)]
+pub struct FacePosition {
+    pub face: u8,
+    pub x: f64,
+    pub y: f64,
+}
+
+impl FacePosition {
+    /// Position of a direction given as a unit vector. The z axis points to
+    /// the pole of the frame, the x axis to longitude 0.
+    pub fn from_direction(direction: &Vector3<f64>) -> Self {
+        let z = direction.z.clamp(-1.0, 1.0);
+        let mut phi = direction.y.atan2(direction.x);
+        if phi < 0.0 {
+            phi += TAU;
+        }
+        let mut tt = phi / FRAC_PI_2;
+        if tt >= 4.0 {
+            tt = 0.0;
+        }
+
+        if z.abs() <= 2.0 / 3.0 {
+            // equatorial region
+            let t1 = 0.5 + tt;
+            let t2 = 0.75 * z;
+            let jp = t1 - t2;
+            let jm = t1 + t2;
+            let ifp = jp.floor() as u8;
+            let ifm = jm.floor() as u8;
+
+            let face = if ifp == ifm {
+                ifp | 4
+            }
+            else if ifp < ifm {
+                ifp
+            }
+            else {
+                ifm + 8
+            };
+
+            Self {
+                face,
+                x: jm - jm.floor(),
+                y: 1.0 - (jp - jp.floor()),
+            }
+        }
+        else {
+            // polar caps
+            let ntt = (tt.floor() as u8).min(3);
+            let tp = tt - ntt as f64;
+            let tmp = (3.0 * (1.0 - z.abs())).sqrt();
+            let jp = (tp * tmp).min(1.0);
+            let jm = ((1.0 - tp) * tmp).min(1.0);
+
+            if z > 0.0 {
+                Self {
+                    face: ntt,
+                    x: 1.0 - jm,
+                    y: 1.0 - jp,
+                }
+            }
+            else {
+                Self {
+                    face: ntt + 8,
+                    x: jp,
+                    y: jm,
+                }
+            }
+        }
+    }
+
+    /// Inverse of [`FacePosition::from_direction`].
+    pub fn direction(&self) -> Vector3<f64> {
+        let face = self.face as usize;
+        // ring from the north pole, in units of nside
+        let ring = FACE_RINGS[face] as f64 - self.x - self.y;
+
+        let (z, num_rings) = if ring < 1.0 {
+            (1.0 - ring * ring / 3.0, ring)
+        }
+        else if ring > 3.0 {
+            let ring = 4.0 - ring;
+            (ring * ring / 3.0 - 1.0, ring)
+        }
+        else {
+            ((2.0 - ring) * 2.0 / 3.0, 1.0)
+        };
+
+        let phi = if num_rings > 0.0 {
+            FRAC_PI_4 * (FACE_LONGITUDES[face] as f64 * num_rings + self.x - self.y) / num_rings
+        }
+        else {
+            0.0
+        };
+
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        Vector3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    /// Position within the pixel of the given order that contains this
+    /// position. Returns the pixel index and the coordinates relative to the
+    /// pixel, in `[0, 1)`.
+    pub fn pixel(&self, order: u8) -> (u64, [f64; 2]) {
+        let nside = 1u32 << order;
+        let x = self.x * nside as f64;
+        let y = self.y * nside as f64;
+        let ix = (x.floor() as u32).min(nside - 1);
+        let iy = (y.floor() as u32).min(nside - 1);
+
+        let index = ((self.face as u64) << (2 * order as u64)) + interleave(ix, iy);
+        (index, [x - ix as f64, y - iy as f64])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direction(longitude: f64, latitude: f64) -> Vector3<f64> {
+        let l = longitude.to_radians();
+        let b = latitude.to_radians();
+        Vector3::new(b.cos() * l.cos(), b.cos() * l.sin(), b.sin())
+    }
+
+    #[test]
+    fn interleaves_bits() {
+        assert_eq!(interleave(0, 0), 0);
+        assert_eq!(interleave(1, 0), 0b01);
+        assert_eq!(interleave(0, 1), 0b10);
+        assert_eq!(interleave(0b101, 0b011), 0b011011);
+        assert_eq!(interleave(u32::MAX, 0), 0x5555555555555555);
+        assert_eq!(
+            deinterleave(interleave(123456789, 987654321)),
+            (123456789, 987654321)
+        );
+    }
+
+    #[test]
+    fn finds_nested_pixels() {
+        // within a base pixel, the nested children are ordered south, east,
+        // west, north.
+        let pixels = [
+            ((0.0, 90.0), 0, 0),
+            ((0.0, 0.0), 0, 4),
+            ((135.0, -30.0), 0, 9),
+            ((0.0, -10.0), 1, 16),
+            ((10.0, 0.0), 1, 17),
+            ((-10.0, 0.0), 1, 18),
+            ((0.0, 10.0), 1, 19),
+            ((90.0, -10.0), 1, 20),
+            ((45.0, 80.0), 1, 3),
+            ((135.0, 80.0), 1, 7),
+            ((45.0, -80.0), 1, 32),
+        ];
+
+        for ((longitude, latitude), order, index) in pixels {
+            let position = FacePosition::from_direction(&direction(longitude, latitude));
+            assert_eq!(position.pixel(order).0, index, "{longitude}, {latitude}");
+        }
+    }
+
+    #[test]
+    fn unprojects_face_positions() {
+        for latitude in (-89..=89).step_by(7) {
+            for longitude in (0..360).step_by(11) {
+                let direction = direction(longitude as f64, latitude as f64);
+                let position = FacePosition::from_direction(&direction);
+                assert!(
+                    (position.direction() - direction).norm() < 1e-9,
+                    "{longitude}, {latitude}: {position:?}"
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,11 @@
+pub mod healpix;
 pub mod teff_color;
 
+use color_eyre::eyre::{
+    eyre,
+    Error,
+};
+use nalgebra::Vector3;
 use serde::{
     Deserialize,
     Deserializer,
@@ -11,3 +17,24 @@ where
 {
     Ok(T::deserialize(deserializer).ok())
 }
+
+/// Parses a vector given as `x,y,z`.
+pub fn parse_vector3(s: &str) -> Result<Vector3<f64>, Error> {
+    let components = s
+        .split(',')
+        .map(|component| component.trim().parse())
+        .collect::<Result<Vec<f64>, _>>()?;
+
+    match components[..] {
+        [x, y, z] => Ok(Vector3::new(x, y, z)),
+        _ => Err(eyre!("expected 3 comma-separated components: {s}")),
+    }
+}
+
+/// Parses a range given as `min,max`.
+pub fn parse_range(s: &str) -> Result<[f64; 2], Error> {
+    let (min, max) = s
+        .split_once(',')
+        .ok_or_else(|| eyre!("expected a range as min,max: {s}"))?;
+    Ok([min.trim().parse()?, max.trim().parse()?])
+}
@@ -1,48 +1,122 @@
-use std::{
-    collections::BTreeMap,
-    ops::Bound,
-};
+//! Star colours from blackbody spectra.
+//!
+//! The Planck spectrum is integrated against the CIE 1931 colour matching
+//! functions and converted to linear sRGB. As this is too slow to do per star,
+//! [`TEFF_COLORS`] caches the colours for the usual range of temperatures.
 
 use lazy_static::lazy_static;
 use palette::LinSrgb;
 
 lazy_static! {
-    pub static ref TEFF_COLORS: TeffColorTable =
-        TeffColorTable::load_table(include_str!("teff-rgb.csv"));
+    pub static ref TEFF_COLORS: TeffColorTable = TeffColorTable::new();
 }
 
+/// Second radiation constant `h c / k` in nm K.
+const C2: f64 = 1.4387769e7;
+
+/// Piecewise Gaussian with different widths left and right of the mean.
+fn piecewise_gaussian(x: f64, mean: f64, sigma_left: f64, sigma_right: f64) -> f64 {
+    let sigma = if x < mean { sigma_left } else { sigma_right };
+    (-0.5 * ((x - mean) / sigma).powi(2)).exp()
+}
+
+/// CIE 1931 2° colour matching functions at a wavelength in nm, using the
+/// multi-lobe fit by [Wyman et al. (2013)][1].
+///
+/// [1]: https://jcgt.org/published/0002/02/01/
+fn color_matching_functions(wavelength: f64) -> [f64; 3] {
+    let x = 1.056 * piecewise_gaussian(wavelength, 599.8, 37.9, 31.0)
+        + 0.362 * piecewise_gaussian(wavelength, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(wavelength, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(wavelength, 568.8, 46.9, 40.5)
+        + 0.286 * piecewise_gaussian(wavelength, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(wavelength, 437.0, 11.8, 36.0)
+        + 0.681 * piecewise_gaussian(wavelength, 459.0, 26.0, 13.8);
+    [x, y, z]
+}
+
+/// CIE XYZ tristimulus values of a blackbody, up to a constant factor.
+fn blackbody_xyz(t_eff: f64) -> [f64; 3] {
+    let mut xyz = [0.0; 3];
+
+    for wavelength in (360..=830).map(f64::from) {
+        let radiance = wavelength.powi(-5) / (C2 / (wavelength * t_eff)).exp_m1();
+        let cmf = color_matching_functions(wavelength);
+        for (sum, cmf) in xyz.iter_mut().zip(cmf) {
+            *sum += radiance * cmf;
+        }
+    }
+
+    xyz
+}
+
+/// Temperatures in K between which the spectrum is computed. Below, the
+/// radiance in the visible underflows, above, the colour hardly changes any
+/// more as the spectrum approaches the Rayleigh-Jeans law.
+const BLACKBODY_T_EFF_RANGE: [f64; 2] = [500.0, 1e6];
+
+/// Colour of a blackbody in linear sRGB, normalized so that the brightest
+/// channel is 1. Colours outside of the sRGB gamut are clipped. Temperatures
+/// outside of [`BLACKBODY_T_EFF_RANGE`], and NaN, get the colour of the
+/// nearest end of the range.
+pub fn blackbody_color(t_eff: f32) -> LinSrgb {
+    let [min, max] = BLACKBODY_T_EFF_RANGE;
+    let [x, y, z] = blackbody_xyz((t_eff as f64).max(min).min(max));
+
+    let rgb = [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+    .map(|channel| channel.max(0.0));
+    let max = rgb.iter().copied().fold(0.0, f64::max);
+    let [red, green, blue] = rgb.map(|channel| (channel / max) as f32);
+
+    LinSrgb::new(red, green, blue)
+}
+
+/// Blackbody colours at logarithmically spaced temperatures. Temperatures
+/// outside of the table are computed directly.
 pub struct TeffColorTable {
-    data: BTreeMap<u32, LinSrgb>,
+    colors: Vec<LinSrgb>,
 }
 
 impl TeffColorTable {
-    fn load_table(table: &str) -> Self {
-        let mut data = BTreeMap::new();
-
-        for line in table.lines() {
-            let mut cells = line.split(',');
-            let t_eff = cells.next().unwrap().parse().unwrap();
-            let color = LinSrgb::new(
-                cells.next().unwrap().parse().unwrap(),
-                cells.next().unwrap().parse().unwrap(),
-                cells.next().unwrap().parse().unwrap(),
-            );
-            data.insert(t_eff, color);
-        }
+    const MIN_T_EFF: f32 = 1000.0;
+    const MAX_T_EFF: f32 = 60000.0;
+    const NUM_ENTRIES: usize = 1024;
 
-        Self { data }
+    fn new() -> Self {
+        let colors = (0..Self::NUM_ENTRIES)
+            .map(|i| blackbody_color(Self::t_eff(i)))
+            .collect();
+        Self { colors }
     }
 
-    pub fn get(&self, t_eff: f32) -> Option<LinSrgb> {
-        let t_eff_int = t_eff as u32;
-
-        let cursor = self.data.lower_bound(Bound::Included(&t_eff_int));
+    /// Temperature of the `i`-th entry.
+    fn t_eff(i: usize) -> f32 {
+        let step = (Self::MAX_T_EFF / Self::MIN_T_EFF).ln() / (Self::NUM_ENTRIES - 1) as f32;
+        Self::MIN_T_EFF * (step * i as f32).exp()
+    }
 
-        let (t_upper, rgb_upper) = cursor.peek_next()?;
-        let (t_lower, rgb_lower) = cursor.peek_prev()?;
+    /// Colour of a star with the effective temperature in K, or `None` if the
+    /// temperature isn't positive.
+    pub fn get(&self, t_eff: f32) -> Option<LinSrgb> {
+        if t_eff.is_nan() || t_eff <= 0.0 {
+            return None;
+        }
+        if !(Self::MIN_T_EFF..=Self::MAX_T_EFF).contains(&t_eff) {
+            return Some(blackbody_color(t_eff));
+        }
 
-        let k = t_eff / ((*t_upper as f32) - (*t_lower as f32));
+        // fractional position between the neighbouring entries
+        let position = (t_eff / Self::MIN_T_EFF).ln() / (Self::MAX_T_EFF / Self::MIN_T_EFF).ln()
+            * (Self::NUM_ENTRIES - 1) as f32;
+        let lower = (position.floor() as usize).min(Self::NUM_ENTRIES - 2);
+        let k = position - lower as f32;
 
+        let rgb_lower = self.colors[lower];
+        let rgb_upper = self.colors[lower + 1];
         let color = LinSrgb::new(
             (1.0 - k) * rgb_lower.red + k * rgb_upper.red,
             (1.0 - k) * rgb_lower.green + k * rgb_upper.green,
@@ -52,3 +126,108 @@ impl TeffColorTable {
         Some(color)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chromaticity(t_eff: f64) -> [f64; 2] {
+        let [x, y, z] = blackbody_xyz(t_eff);
+        [x / (x + y + z), y / (x + y + z)]
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn planckian_locus() {
+        // CIE 1931 chromaticities of the Planckian locus.
+        for (t_eff, [x, y]) in [
+            (2000.0, [0.5267, 0.4133]),
+            (3000.0, [0.4369, 0.4041]),
+            (6500.0, [0.3135, 0.3237]),
+            (10000.0, [0.2807, 0.2884]),
+        ] {
+            let [actual_x, actual_y] = chromaticity(t_eff);
+            assert_close(actual_x as f32, x, 0.005);
+            assert_close(actual_y as f32, y, 0.005);
+        }
+    }
+
+    #[test]
+    fn reference_colors() {
+        // D65 is close to a 6500 K blackbody.
+        let white = blackbody_color(6500.0);
+        assert!(white.red > 0.95 && white.green > 0.9 && white.blue > 0.9);
+
+        // the sun appears slightly yellowish.
+        let sun = blackbody_color(5772.0);
+        assert_close(sun.red, 1.0, 1e-6);
+        assert!(sun.green < sun.red && sun.blue < sun.green && sun.blue > 0.75);
+
+        // M dwarfs are red, with almost no blue.
+        let m_dwarf = blackbody_color(3000.0);
+        assert_close(m_dwarf.red, 1.0, 1e-6);
+        assert!(m_dwarf.green < 0.5 && m_dwarf.blue < 0.2);
+
+        // O stars are blue.
+        let o_star = blackbody_color(40000.0);
+        assert_close(o_star.blue, 1.0, 1e-6);
+        assert!(o_star.red < 0.7 && o_star.green < o_star.blue);
+    }
+
+    #[test]
+    fn extreme_temperatures() {
+        for t_eff in [0.0, 1.0, 19.0, 100.0, 1e7, f32::INFINITY, f32::NAN] {
+            let color = blackbody_color(t_eff);
+            assert!(
+                [color.red, color.green, color.blue]
+                    .iter()
+                    .all(|channel| (0.0..=1.0).contains(channel)),
+                "{t_eff}: {color:?}"
+            );
+        }
+        assert_eq!(blackbody_color(1.0), blackbody_color(500.0));
+        assert_eq!(blackbody_color(f32::INFINITY), blackbody_color(1e6));
+    }
+
+    #[test]
+    fn table_matches_direct_computation() {
+        for t_eff in [1000.0, 2345.0, 4000.0, 5772.0, 9876.5, 25000.0, 60000.0] {
+            let cached = TEFF_COLORS.get(t_eff).unwrap();
+            let direct = blackbody_color(t_eff);
+            assert_close(cached.red, direct.red, 2e-3);
+            assert_close(cached.green, direct.green, 2e-3);
+            assert_close(cached.blue, direct.blue, 2e-3);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_entries() {
+        let lower = TeffColorTable::t_eff(500);
+        let upper = TeffColorTable::t_eff(501);
+        let color_lower = TEFF_COLORS.get(lower).unwrap();
+        let color_upper = TEFF_COLORS.get(upper).unwrap();
+        let color_middle = TEFF_COLORS.get(0.5 * (lower + upper)).unwrap();
+
+        for (lower, middle, upper) in [
+            (color_lower.red, color_middle.red, color_upper.red),
+            (color_lower.green, color_middle.green, color_upper.green),
+            (color_lower.blue, color_middle.blue, color_upper.blue),
+        ] {
+            assert!(middle >= lower.min(upper) && middle <= lower.max(upper));
+        }
+    }
+
+    #[test]
+    fn any_temperature() {
+        assert!(TEFF_COLORS.get(500.0).is_some());
+        assert!(TEFF_COLORS.get(200000.0).is_some());
+        assert!(TEFF_COLORS.get(0.0).is_none());
+        assert!(TEFF_COLORS.get(f32::NAN).is_none());
+    }
+}